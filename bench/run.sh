@@ -0,0 +1,28 @@
+#!/bin/bash
+# Builds each benchmark at -O0, -O1 and -O2 and prints the wall time of a run
+# usage: bench/run.sh [bench/arith.eb ...]
+
+set -e
+
+l_sRoot="$(cd "$(dirname "$0")/.." && pwd)"
+l_sBuildDir=$(mktemp -d)
+trap 'rm -rf "$l_sBuildDir"' EXIT
+l_sEboth="$l_sRoot/target/release/eboth"
+
+cargo build --release --quiet --manifest-path "$l_sRoot/Cargo.toml"
+
+if [ $# -eq 0 ]; then
+    set -- "$l_sRoot"/bench/*.eb
+fi
+
+for l_sSource in "$@"; do
+    l_sName=$(basename "$l_sSource" .eb)
+    for l_sLevel in -O0 -O1 -O2; do
+        l_sExecutable="$l_sBuildDir/$l_sName$l_sLevel"
+        "$l_sEboth" build "$l_sLevel" --backend=builtin -q "$l_sSource" -o "$l_sExecutable"
+        l_iStart=$(date +%s%N)
+        l_sOutput=$("$l_sExecutable")
+        l_iEnd=$(date +%s%N)
+        printf "%-10s %-4s %6d ms   %s\n" "$l_sName" "$l_sLevel" $(( (l_iEnd - l_iStart) / 1000000 )) "$l_sOutput"
+    done
+done
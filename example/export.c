@@ -0,0 +1,16 @@
+/* Calls the procs exported by export.eb through the generated header */
+#include <stdio.h>
+#include <string.h>
+
+#include "export.h"
+
+int main(void) {
+    const char *name = "C";
+
+    printf("add(40, 2) = %ld\n", (long)proc_add(40, 2));
+    printf("square(-7) = %ld\n", (long)proc_square(-7));
+    printf("is_even(10) = %d, is_even(7) = %d\n", proc_is_even(10), proc_is_even(7));
+    fflush(stdout);
+    proc_greet(name, (int64_t)strlen(name));
+    return 0;
+}
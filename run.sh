@@ -8,30 +8,12 @@ l_sBaseName=$(basename "$l_sAsmFile" .asm)
 l_sObjectFile="$l_sBuildDir/$l_sBaseName.o"
 l_sExecutable="$l_sBuildDir/$l_sBaseName"
 
-if ! command -v nasm &> /dev/null; then
-    echo "Installation de NASM..."
-    if command -v apt &> /dev/null; then
-        sudo apt update && sudo apt install -y nasm
-    elif command -v dnf &> /dev/null; then
-        sudo dnf install -y nasm
-    elif command -v pacman &> /dev/null; then
-        sudo pacman -S --noconfirm nasm
-    else
-        echo "Gestionnaire de paquets non supporté"
+for l_sTool in nasm ld; do
+    if ! command -v "$l_sTool" &> /dev/null; then
+        echo "$l_sTool introuvable, installez-le (nasm, binutils)" >&2
         exit 1
     fi
-fi
-
-if ! command -v ld &> /dev/null; then
-    echo "Installation de binutils..."
-    if command -v apt &> /dev/null; then
-        sudo apt install -y binutils
-    elif command -v dnf &> /dev/null; then
-        sudo dnf install -y binutils
-    elif command -v pacman &> /dev/null; then
-        sudo pacman -S --noconfirm binutils
-    fi
-fi
+done
 
 mkdir -p "$l_sBuildDir"
 
@@ -0,0 +1,572 @@
+#![allow(nonstandard_style)]
+use std::collections::HashMap;
+use crate::{EIrInstr, EType};
+use crate::source::CSourceMap;
+use crate::diagnostic::CDiagnostic;
+
+// ============================================================================
+// Static type checker: simulates a typed stack over every proc body. Each
+// instruction is checked against its inputs, calls against the callee's
+// signature, and every path must leave exactly the declared return types.
+// ============================================================================
+
+// Types are tracked per stack cell: every integer width is one `I64` cell,
+// a `str` is a `Ptr` cell followed by an `I64` length cell.
+fn F_lCellTypes(l_lTypes: &[EType]) -> Vec<EType> {
+    let mut l_lCells = Vec::new();
+    for l_eType in l_lTypes {
+        match l_eType {
+            EType::Str => {
+                l_lCells.push(EType::Ptr);
+                l_lCells.push(EType::I64);
+            }
+            EType::Void => {}
+            EType::I8 | EType::U8 | EType::I16 | EType::U16 |
+            EType::I32 | EType::U32 | EType::I64 | EType::U64 => l_lCells.push(EType::I64),
+            l_eOther => l_lCells.push(l_eOther.clone()),
+        }
+    }
+    l_lCells
+}
+
+fn F_sTypeName(l_eType: &EType) -> &'static str {
+    match l_eType {
+        EType::Ptr => "ptr",
+        EType::Bool => "bool",
+        EType::F32 => "f32",
+        EType::F64 => "f64",
+        EType::Str => "str",
+        EType::Void => "void",
+        _ => "int",
+    }
+}
+
+fn F_sStack(l_lStack: &[EType]) -> String {
+    let l_lNames: Vec<&str> = l_lStack.iter().map(F_sTypeName).collect();
+    format!("[{}]", l_lNames.join(", "))
+}
+
+fn F_cTypeError(l_sMessage: String) -> CDiagnostic {
+    CDiagnostic::F_cError("E0103", l_sMessage)
+}
+
+// Pops l_iCount cells, returned bottom-first
+fn F_lPop(l_lStack: &mut Vec<EType>, l_iCount: usize) -> Result<Vec<EType>, CDiagnostic> {
+    if l_lStack.len() < l_iCount {
+        return Err(CDiagnostic::F_cError(
+            "E0102",
+            format!("stack underflow, needs {} value(s), has {}", l_iCount, l_lStack.len()),
+        ));
+    }
+    Ok(l_lStack.split_off(l_lStack.len() - l_iCount))
+}
+
+fn F_vExpect(l_eGot: &EType, l_lAllowed: &[EType], l_sRole: &str) -> Result<(), CDiagnostic> {
+    if l_lAllowed.contains(l_eGot) {
+        return Ok(());
+    }
+    let l_lNames: Vec<&str> = l_lAllowed.iter().map(F_sTypeName).collect();
+    Err(F_cTypeError(format!("{} must be {}, got {}", l_sRole, l_lNames.join(" or "), F_sTypeName(l_eGot))))
+}
+
+// Value cells that can be compared, stored or printed (everything but floats)
+const SCALARS: [EType; 3] = [EType::I64, EType::Ptr, EType::Bool];
+
+struct CChecker<'a> {
+    // (parameter cells, result cells)
+    l_hmSignatures: HashMap<&'static str, (Vec<EType>, Vec<EType>)>,
+    l_hmConsts: HashMap<&'static str, &'a [EIrInstr]>,
+    // Result types of consts are inferred from their body, on first use
+    // (None when the body has errors, which are reported once)
+    l_hmConstTypes: HashMap<&'static str, Option<Vec<EType>>>,
+    l_lInProgress: Vec<&'static str>,
+    l_cSourceMap: &'a CSourceMap,
+    l_lDiagnostics: Vec<CDiagnostic>,
+}
+
+impl<'a> CChecker<'a> {
+    fn F_cNew(l_lIr: &'a [EIrInstr], l_cSourceMap: &'a CSourceMap) -> Self {
+        let mut l_hmSignatures = HashMap::new();
+        let mut l_hmConsts = HashMap::new();
+        for l_cInstr in l_lIr {
+            match l_cInstr {
+                EIrInstr::Proc(l_sName, _, l_lParams, l_lRets) | EIrInstr::Extern(l_sName, l_lParams, l_lRets) => {
+                    l_hmSignatures.insert(*l_sName, (F_lCellTypes(l_lParams), F_lCellTypes(l_lRets)));
+                }
+                EIrInstr::Const(l_sName, l_lBody) => {
+                    l_hmConsts.insert(*l_sName, l_lBody.as_slice());
+                }
+                _ => {}
+            }
+        }
+        CChecker {
+            l_hmSignatures, l_hmConsts, l_hmConstTypes: HashMap::new(), l_lInProgress: Vec::new(),
+            l_cSourceMap, l_lDiagnostics: Vec::new(),
+        }
+    }
+
+    // Records a diagnostic located at instruction l_oPc of l_sDecl (at its name when None)
+    fn F_vReport(&mut self, l_sDecl: &'static str, l_oPc: Option<usize>, l_cDiagnostic: CDiagnostic) {
+        let l_oSpan = self.l_cSourceMap.F_oSpan(l_sDecl, l_oPc);
+        self.l_lDiagnostics.push(l_cDiagnostic.F_cAt(l_oSpan));
+    }
+
+    // Result types of a const (which must not be in progress); Err when its
+    // body has errors, already reported
+    fn F_lConstTypes(&mut self, l_sTarget: &'static str) -> Result<Vec<EType>, ()> {
+        if let Some(l_oTypes) = self.l_hmConstTypes.get(l_sTarget) {
+            return l_oTypes.clone().ok_or(());
+        }
+        let l_lBody = self.l_hmConsts[l_sTarget];
+
+        self.l_lInProgress.push(l_sTarget);
+        let l_rTypes = self.F_oSimulate("const", l_sTarget, l_lBody, Vec::new());
+        self.l_lInProgress.pop();
+
+        let l_oTypes = l_rTypes.ok().map(Option::unwrap_or_default);
+        self.l_hmConstTypes.insert(l_sTarget, l_oTypes.clone());
+        l_oTypes.ok_or(())
+    }
+
+    fn F_vCall(&mut self, l_lStack: &mut Vec<EType>, l_sTarget: &'static str) -> Result<(), CDiagnostic> {
+        let Some((l_lParams, l_lRets)) = self.l_hmSignatures.get(l_sTarget).cloned() else {
+            return Err(CDiagnostic::F_cError("E0101", format!("undefined proc {}", l_sTarget)));
+        };
+        let l_sSignature = format!("{} takes {} and returns {}", l_sTarget, F_sStack(&l_lParams), F_sStack(&l_lRets));
+        let l_lArgs = F_lPop(l_lStack, l_lParams.len()).map_err(|mut e| {
+            e.l_sMessage = format!("call to {}: {}", l_sTarget, e.l_sMessage);
+            e.F_cNote(l_sSignature.clone())
+        })?;
+        for (i, (l_eParam, l_eArg)) in l_lParams.iter().zip(&l_lArgs).enumerate() {
+            if l_eParam != l_eArg {
+                return Err(F_cTypeError(format!(
+                    "call to {}: argument {} must be {}, got {}",
+                    l_sTarget, i + 1, F_sTypeName(l_eParam), F_sTypeName(l_eArg)
+                )).F_cNote(l_sSignature));
+            }
+        }
+        l_lStack.extend(l_lRets);
+        Ok(())
+    }
+
+    // Applies one (non-jump) instruction to the simulated stack
+    fn F_vApply(&mut self, l_lStack: &mut Vec<EType>, l_cInstr: &EIrInstr) -> Result<(), CDiagnostic> {
+        use EType::{Bool, I64, Ptr};
+        match l_cInstr {
+            EIrInstr::PushI64(_) => l_lStack.push(I64),
+            EIrInstr::PushMem(_) => l_lStack.push(Ptr),
+            EIrInstr::PushStr(_) => l_lStack.extend([Ptr, I64]),
+
+            // Pointer arithmetic: ptr+int, int+ptr, ptr-int and ptr-ptr
+            EIrInstr::AddI64 => {
+                let l_eResult = match F_lPop(l_lStack, 2)?.as_slice() {
+                    [I64, I64] => I64,
+                    [Ptr, I64] | [I64, Ptr] => Ptr,
+                    [l_eA, l_eB] => return Err(F_cTypeError(format!("cannot add {} and {}", F_sTypeName(l_eA), F_sTypeName(l_eB)))),
+                    _ => unreachable!(),
+                };
+                l_lStack.push(l_eResult);
+            }
+            EIrInstr::SubI64 => {
+                let l_eResult = match F_lPop(l_lStack, 2)?.as_slice() {
+                    [I64, I64] | [Ptr, Ptr] => I64,
+                    [Ptr, I64] => Ptr,
+                    [l_eA, l_eB] => return Err(F_cTypeError(format!("cannot subtract {} from {}", F_sTypeName(l_eB), F_sTypeName(l_eA)))),
+                    _ => unreachable!(),
+                };
+                l_lStack.push(l_eResult);
+            }
+            EIrInstr::MulI64 | EIrInstr::DivI64 | EIrInstr::ModI64 | EIrInstr::DivU64 | EIrInstr::ModU64 |
+            EIrInstr::ShlI64 | EIrInstr::ShrU64 | EIrInstr::SarI64 => {
+                for l_eOperand in F_lPop(l_lStack, 2)? {
+                    F_vExpect(&l_eOperand, &[I64], "operand")?;
+                }
+                l_lStack.push(I64);
+            }
+            // Bitwise on ints, logical on bools
+            EIrInstr::AndI64 | EIrInstr::OrI64 | EIrInstr::XorI64 => {
+                let l_eResult = match F_lPop(l_lStack, 2)?.as_slice() {
+                    [I64, I64] => I64,
+                    [Bool, Bool] => Bool,
+                    [l_eA, l_eB] => return Err(F_cTypeError(format!("operands must be two ints or two bools, got {} and {}", F_sTypeName(l_eA), F_sTypeName(l_eB)))),
+                    _ => unreachable!(),
+                };
+                l_lStack.push(l_eResult);
+            }
+            EIrInstr::NotI64 => {
+                F_vExpect(&F_lPop(l_lStack, 1)?[0], &[I64], "operand")?;
+                l_lStack.push(I64);
+            }
+
+            // Equality also allows comparing a pointer with an int (null checks)
+            EIrInstr::EqI64 | EIrInstr::NeqI64 => {
+                match F_lPop(l_lStack, 2)?.as_slice() {
+                    [Bool, Bool] => {}
+                    [I64 | Ptr, I64 | Ptr] => {}
+                    [l_eA, l_eB] => return Err(F_cTypeError(format!("cannot compare {} with {}", F_sTypeName(l_eA), F_sTypeName(l_eB)))),
+                    _ => unreachable!(),
+                }
+                l_lStack.push(Bool);
+            }
+            EIrInstr::LtI64 | EIrInstr::GtI64 | EIrInstr::LeI64 | EIrInstr::GeI64 |
+            EIrInstr::LtU64 | EIrInstr::GtU64 | EIrInstr::LeU64 | EIrInstr::GeU64 => {
+                match F_lPop(l_lStack, 2)?.as_slice() {
+                    [I64, I64] | [Ptr, Ptr] => {}
+                    [l_eA, l_eB] => return Err(F_cTypeError(format!("cannot order {} and {}", F_sTypeName(l_eA), F_sTypeName(l_eB)))),
+                    _ => unreachable!(),
+                }
+                l_lStack.push(Bool);
+            }
+
+            EIrInstr::Dup => {
+                let l_lTop = F_lPop(l_lStack, 1)?;
+                l_lStack.extend([l_lTop[0].clone(), l_lTop[0].clone()]);
+            }
+            EIrInstr::Swap => {
+                let l_lTop = F_lPop(l_lStack, 2)?;
+                l_lStack.extend([l_lTop[1].clone(), l_lTop[0].clone()]);
+            }
+            EIrInstr::Drop => {
+                F_lPop(l_lStack, 1)?;
+            }
+            EIrInstr::Over => {
+                let l_lTop = F_lPop(l_lStack, 2)?;
+                l_lStack.extend([l_lTop[0].clone(), l_lTop[1].clone(), l_lTop[0].clone()]);
+            }
+            EIrInstr::Rot => {
+                let l_lTop = F_lPop(l_lStack, 3)?;
+                l_lStack.extend([l_lTop[1].clone(), l_lTop[2].clone(), l_lTop[0].clone()]);
+            }
+
+            EIrInstr::Puts => {
+                let l_lStr = F_lPop(l_lStack, 2)?;
+                F_vExpect(&l_lStr[0], &[Ptr], "string pointer")?;
+                F_vExpect(&l_lStr[1], &[I64], "string length")?;
+            }
+            EIrInstr::Dump => {
+                F_vExpect(&F_lPop(l_lStack, 1)?[0], &SCALARS, "value")?;
+            }
+
+            EIrInstr::Load8 | EIrInstr::Load16 | EIrInstr::Load32 | EIrInstr::Load64 => {
+                F_vExpect(&F_lPop(l_lStack, 1)?[0], &[Ptr], "address")?;
+                l_lStack.push(I64);
+            }
+            EIrInstr::Store8 | EIrInstr::Store16 | EIrInstr::Store32 | EIrInstr::Store64 => {
+                let l_lArgs = F_lPop(l_lStack, 2)?;
+                F_vExpect(&l_lArgs[0], &[Ptr], "address")?;
+                F_vExpect(&l_lArgs[1], &SCALARS, "stored value")?;
+            }
+
+            // Syscall arguments are raw registers, any scalar goes
+            EIrInstr::Syscall0 | EIrInstr::Syscall1 | EIrInstr::Syscall2 | EIrInstr::Syscall3 |
+            EIrInstr::Syscall4 | EIrInstr::Syscall5 | EIrInstr::Syscall6 => {
+                let l_iArgs = match l_cInstr {
+                    EIrInstr::Syscall0 => 0,
+                    EIrInstr::Syscall1 => 1,
+                    EIrInstr::Syscall2 => 2,
+                    EIrInstr::Syscall3 => 3,
+                    EIrInstr::Syscall4 => 4,
+                    EIrInstr::Syscall5 => 5,
+                    _ => 6,
+                };
+                let l_lArgs = F_lPop(l_lStack, l_iArgs + 1)?;
+                F_vExpect(&l_lArgs[0], &[I64], "syscall number")?;
+                for l_eArg in &l_lArgs[1..] {
+                    F_vExpect(l_eArg, &SCALARS, "syscall argument")?;
+                }
+                l_lStack.push(I64);
+            }
+
+            EIrInstr::Argc => l_lStack.push(I64),
+            EIrInstr::Argv | EIrInstr::Envp => {
+                F_vExpect(&F_lPop(l_lStack, 1)?[0], &[I64], "index")?;
+                l_lStack.push(Ptr);
+            }
+            EIrInstr::Exit => {
+                F_vExpect(&F_lPop(l_lStack, 1)?[0], &[I64, Bool], "exit status")?;
+            }
+
+            EIrInstr::Call(l_sTarget) | EIrInstr::CallExtern(l_sTarget) => self.F_vCall(l_lStack, l_sTarget)?,
+            // The condition of if/while: zero is false
+            EIrInstr::JumpIfZero(_) => {
+                F_vExpect(&F_lPop(l_lStack, 1)?[0], &[Bool, I64], "condition")?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Walks every path of l_lBody starting from l_lStart and returns the stack
+    // when it ends (None if it never does, e.g. `while 1 do end`). Paths joining
+    // at a label must agree on the types they bring.
+    // Errors are reported in the body of l_sDecl, a `l_sKind` (proc/const/memory);
+    // the first one stops the simulation.
+    fn F_oSimulate(
+        &mut self,
+        l_sKind: &str,
+        l_sDecl: &'static str,
+        l_lBody: &[EIrInstr],
+        l_lStart: Vec<EType>,
+    ) -> Result<Option<Vec<EType>>, ()> {
+        let mut l_hmLabels: HashMap<usize, usize> = HashMap::new();
+        for (l_iPc, l_cInstr) in l_lBody.iter().enumerate() {
+            if let EIrInstr::Label(l_iLabel) = l_cInstr {
+                l_hmLabels.insert(*l_iLabel, l_iPc);
+            }
+        }
+
+        let mut l_lSeen: Vec<Option<Vec<EType>>> = vec![None; l_lBody.len() + 1];
+        let mut l_lWork = vec![(0usize, l_lStart)];
+
+        while let Some((mut l_iPc, mut l_lStack)) = l_lWork.pop() {
+            loop {
+                match &l_lSeen[l_iPc] {
+                    Some(l_lPrevious) if *l_lPrevious == l_lStack => break,
+                    Some(l_lPrevious) => {
+                        let l_cDiagnostic = CDiagnostic::F_cError("E0105", format!(
+                            "{} {}: branches reach instruction {} with {} and {} on the stack",
+                            l_sKind, l_sDecl, l_iPc, F_sStack(l_lPrevious), F_sStack(&l_lStack)
+                        ));
+                        self.F_vReport(l_sDecl, Some(l_iPc), l_cDiagnostic);
+                        return Err(());
+                    }
+                    None => l_lSeen[l_iPc] = Some(l_lStack.clone()),
+                }
+                // Falling off the end (size expressions have no Ret) ends the path
+                let Some(l_cInstr) = l_lBody.get(l_iPc) else { break };
+
+                let l_rApplied = match l_cInstr {
+                    // Consts are inferred on first use; their own errors point into their body
+                    EIrInstr::Call(l_sTarget)
+                        if self.l_hmConsts.contains_key(l_sTarget) && !self.l_hmSignatures.contains_key(l_sTarget) => {
+                        if self.l_lInProgress.contains(l_sTarget) {
+                            Err(CDiagnostic::F_cError("E0106", format!("const {} is defined in terms of itself", l_sTarget)))
+                        } else {
+                            l_lStack.extend(self.F_lConstTypes(l_sTarget)?);
+                            Ok(())
+                        }
+                    }
+                    _ => self.F_vApply(&mut l_lStack, l_cInstr),
+                };
+                if let Err(mut l_cDiagnostic) = l_rApplied {
+                    l_cDiagnostic.l_sMessage = format!(
+                        "{} {}, instruction {} ({:?}): {}", l_sKind, l_sDecl, l_iPc, l_cInstr, l_cDiagnostic.l_sMessage
+                    );
+                    self.F_vReport(l_sDecl, Some(l_iPc), l_cDiagnostic);
+                    return Err(());
+                }
+
+                match l_cInstr {
+                    EIrInstr::Jump(l_iLabel) => l_iPc = l_hmLabels[l_iLabel],
+                    EIrInstr::JumpIfZero(l_iLabel) => {
+                        l_lWork.push((l_hmLabels[l_iLabel], l_lStack.clone()));
+                        l_iPc += 1;
+                    }
+                    EIrInstr::Ret => l_iPc = l_lBody.len(),
+                    // The program ends here: nothing is left to check on this path
+                    EIrInstr::Exit => break,
+                    _ => l_iPc += 1,
+                }
+            }
+        }
+
+        // Every path ends on the final Ret (or past the end) or on `exit`, so
+        // they all agree here
+        Ok(l_lSeen.pop().flatten())
+    }
+}
+
+// An exported proc is called through a System V shim, and an extern is called
+// like one: every parameter cell goes in one of the six integer argument
+// registers and at most one value comes back in rax
+fn F_vCheckExportable(l_lParams: &[EType], l_lRets: &[EType]) -> Result<(), String> {
+    if l_lParams.iter().chain(l_lRets).any(|l_eType| matches!(l_eType, EType::F32 | EType::F64)) {
+        return Err("floats cannot cross the C ABI yet".to_string());
+    }
+    let l_iRegisters = F_lCellTypes(l_lParams).len();
+    if l_iRegisters > 6 {
+        return Err(format!("takes {} argument registers, at most 6 are supported", l_iRegisters));
+    }
+    match l_lRets {
+        [] => Ok(()),
+        [EType::Str] => Err("a str result needs two registers, only rax is returned".to_string()),
+        [_] => Ok(()),
+        _ => Err(format!("returns {} values, at most one can be returned to C", l_lRets.len())),
+    }
+}
+
+// main takes nothing and returns nothing or a single int (or bool) cell
+fn F_bIsMainSignature(l_lParams: &[EType], l_lRets: &[EType]) -> bool {
+    let l_lRetCells = F_lCellTypes(l_lRets);
+    F_lCellTypes(l_lParams).is_empty() && matches!(l_lRetCells.as_slice(), [] | [EType::I64] | [EType::Bool])
+}
+
+// (parameter cells, result cells) of every proc, const and extern, for the
+// passes that run on a checked program
+pub type CStackEffects = HashMap<&'static str, (Vec<EType>, Vec<EType>)>;
+
+// Checks every declaration, reporting the first error of each
+pub fn F_vCheckProgram(l_lIr: &[EIrInstr], l_cSourceMap: &CSourceMap) -> Result<CStackEffects, Vec<CDiagnostic>> {
+    let mut l_cChecker = CChecker::F_cNew(l_lIr, l_cSourceMap);
+
+    for l_cInstr in l_lIr {
+        match l_cInstr {
+            EIrInstr::Proc(l_sName, l_lBody, l_lParams, l_lRets) => {
+                // main is started on an empty stack, its result is the exit status
+                if *l_sName == "main" && !F_bIsMainSignature(l_lParams, l_lRets) {
+                    let l_cDiagnostic = CDiagnostic::F_cError("E0109", format!(
+                        "proc main: takes {} and returns {}, but must take nothing and return nothing or an int exit status",
+                        F_sStack(&F_lCellTypes(l_lParams)), F_sStack(&F_lCellTypes(l_lRets))
+                    ));
+                    l_cChecker.F_vReport(l_sName, None, l_cDiagnostic);
+                    continue;
+                }
+                let Ok(l_oStack) = l_cChecker.F_oSimulate("proc", l_sName, l_lBody, F_lCellTypes(l_lParams)) else { continue };
+                let l_lExpected = F_lCellTypes(l_lRets);
+                if let Some(l_lStack) = l_oStack && l_lStack != l_lExpected {
+                    // Located at the closing `end`
+                    let l_cDiagnostic = CDiagnostic::F_cError("E0104", format!(
+                        "proc {}: leaves {} on the stack, but declares {}",
+                        l_sName, F_sStack(&l_lStack), F_sStack(&l_lExpected)
+                    ));
+                    l_cChecker.F_vReport(l_sName, Some(l_lBody.len() - 1), l_cDiagnostic);
+                }
+            }
+            EIrInstr::Const(l_sName, _) => {
+                let _ = l_cChecker.F_lConstTypes(l_sName);
+            }
+            EIrInstr::Export(l_sName) => {
+                let l_oSignature = l_lIr.iter().rev().find_map(|l_cDecl| match l_cDecl {
+                    EIrInstr::Proc(l_sProc, _, l_lParams, l_lRets) if l_sProc == l_sName => Some((l_lParams, l_lRets)),
+                    _ => None,
+                });
+                if let Some((l_lParams, l_lRets)) = l_oSignature
+                    && let Err(l_sReason) = F_vCheckExportable(l_lParams, l_lRets) {
+                    let l_cDiagnostic = CDiagnostic::F_cError("E0107", format!("proc {} cannot be exported: {}", l_sName, l_sReason));
+                    l_cChecker.F_vReport(l_sName, None, l_cDiagnostic);
+                }
+            }
+            EIrInstr::Extern(l_sName, l_lParams, l_lRets) => {
+                // The name is used as-is as the symbol to link against
+                let l_bIsCName = !l_sName.starts_with(|c: char| c.is_ascii_digit())
+                    && l_sName.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                let l_rCallable = match l_bIsCName {
+                    true => F_vCheckExportable(l_lParams, l_lRets),
+                    false => Err("its name is not a C identifier".to_string()),
+                };
+                if let Err(l_sReason) = l_rCallable {
+                    let l_cDiagnostic = CDiagnostic::F_cError("E0107", format!("extern {} cannot be called: {}", l_sName, l_sReason));
+                    l_cChecker.F_vReport(l_sName, None, l_cDiagnostic);
+                }
+            }
+            EIrInstr::Memory(l_sName, l_lSize) => {
+                let Ok(l_oStack) = l_cChecker.F_oSimulate("memory", l_sName, l_lSize, Vec::new()) else { continue };
+                if l_oStack != Some(vec![EType::I64]) {
+                    let l_cDiagnostic = CDiagnostic::F_cError(
+                        "E0104",
+                        format!("memory {}: size expression must leave exactly one int", l_sName),
+                    );
+                    l_cChecker.F_vReport(l_sName, None, l_cDiagnostic);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if l_cChecker.l_lDiagnostics.is_empty() {
+        let mut l_hmEffects: CStackEffects = l_cChecker.l_hmSignatures;
+        for (l_sName, l_oTypes) in l_cChecker.l_hmConstTypes {
+            if let Some(l_lTypes) = l_oTypes {
+                l_hmEffects.entry(l_sName).or_insert((Vec::new(), l_lTypes));
+            }
+        }
+        Ok(l_hmEffects)
+    } else {
+        Err(l_cChecker.l_lDiagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::F_lParseProgram;
+
+    // Parses and checks a program, returning the checker's diagnostics with
+    // the line and column each one points at
+    fn F_lCheck(l_sCode: &str) -> Vec<(CDiagnostic, (usize, usize))> {
+        let mut l_cSourceMap = CSourceMap::F_cNew("test.eb", l_sCode);
+        let l_lProgram = F_lParseProgram(l_sCode, &mut l_cSourceMap).expect("test program parses");
+        let l_lDiagnostics = F_vCheckProgram(&l_lProgram, &l_cSourceMap).expect_err("test program fails to check");
+        l_lDiagnostics.into_iter().map(|l_cDiagnostic| {
+            let l_tLineCol = l_cSourceMap.F_tLineCol(l_cDiagnostic.l_oSpan.expect("diagnostic has a span"));
+            (l_cDiagnostic, l_tLineCol)
+        }).collect()
+    }
+
+    fn F_vAssertOne(l_sCode: &str, l_sErrorCode: &str, l_sMessage: &str, l_tLineCol: (usize, usize)) {
+        let l_lDiagnostics = F_lCheck(l_sCode);
+        assert_eq!(l_lDiagnostics.len(), 1, "{:?}", l_lDiagnostics);
+        let (l_cDiagnostic, l_tAt) = &l_lDiagnostics[0];
+        assert_eq!(l_cDiagnostic.l_sCode, l_sErrorCode);
+        assert_eq!(l_cDiagnostic.l_sMessage, l_sMessage);
+        assert_eq!(*l_tAt, l_tLineCol);
+    }
+
+    #[test]
+    fn unbalanced_if_else() {
+        F_vAssertOne(
+            "proc f in\n    1 if 2 else end\nend\nproc main in end\n",
+            "E0105",
+            "proc f: branches reach instruction 5 with [int] and [] on the stack",
+            (2, 17),
+        );
+    }
+
+    #[test]
+    fn while_body_changes_depth() {
+        F_vAssertOne(
+            "proc f in\n    0 while dup 10 < do 1 end drop\nend\nproc main in end\n",
+            "E0105",
+            "proc f: branches reach instruction 1 with [int] and [int, int] on the stack",
+            (2, 7),
+        );
+    }
+
+    #[test]
+    fn return_count_mismatch() {
+        F_vAssertOne(
+            "proc main in end\nproc g ret i64 i64 in\n    1\nend\n",
+            "E0104",
+            "proc g: leaves [int] on the stack, but declares [int, int]",
+            (4, 1),
+        );
+    }
+
+    #[test]
+    fn bool_where_int_expected_at_merge() {
+        F_vAssertOne(
+            "proc f ret i64 in\n    1 if 1 else 1 1 = end\nend\nproc main in end\n",
+            "E0105",
+            "proc f: branches reach instruction 8 with [int] and [bool] on the stack",
+            (2, 23),
+        );
+    }
+
+    #[test]
+    fn instruction_error_names_proc_and_index() {
+        F_vAssertOne(
+            "proc main in end\nproc h in\n    1 drop drop\nend\n",
+            "E0102",
+            "proc h, instruction 2 (Drop): stack underflow, needs 1 value(s), has 0",
+            (3, 12),
+        );
+    }
+
+    #[test]
+    fn well_typed_program_passes() {
+        let l_sCode = "proc f i64 ret i64 in\n    dup 0 > if 1 - else end\nend\nproc main in 3 f drop end\n";
+        let mut l_cSourceMap = CSourceMap::F_cNew("test.eb", l_sCode);
+        let l_lProgram = F_lParseProgram(l_sCode, &mut l_cSourceMap).unwrap();
+        assert!(F_vCheckProgram(&l_lProgram, &l_cSourceMap).is_ok());
+    }
+}
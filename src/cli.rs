@@ -0,0 +1,257 @@
+#![allow(nonstandard_style)]
+use crate::diagnostic::EDiagnosticFormat;
+use crate::engine::{BSS_SIZE_MAX, DATA_STACK_CELLS, PROC_STACK_CELLS};
+
+// ============================================================================
+// Command line: eboth <command> [options] <file.eb>
+// ============================================================================
+
+pub const USAGE: &str = "\
+Usage: eboth <command> [options] <file.eb> [-- <args>...]
+
+Commands:
+  run          Interpret the program
+  build        Compile the program to an executable
+  emit-ir      Print the IR
+  emit-asm     Print the x86-64 assembly (NASM)
+  emit-obj     Compile to a relocatable object (.o) for C programs
+  emit-header  Print the C declarations of the `export` procs
+  check        Parse and type check only
+
+Options:
+  -o <path>                    Output file (build: executable, emit-obj: object, others: instead of stdout)
+  --backend=nasm|builtin       build, emit-obj: run the assembler (and linker), or encode the ELF file directly
+  --keep-asm                   build, emit-obj: also write the assembly next to the output
+  --assembler <cmd>            build, emit-obj: NASM-compatible assembler to run (default: nasm)
+  --linker <cmd>               build: linker to run (default: ld)
+  -l <lib>                     build: also link against lib<lib> (libc is linked when there are externs)
+  -O0, -O1, -O2                emit-asm, build, emit-obj: optimization level (default: -O1, inlining and peephole;
+                               -O2 also keeps the top of the stack in registers)
+  --check-stack                emit-asm, build, emit-obj: stop with exit code 3 when a data or proc stack bound is crossed
+  --data-stack <cells>         emit-asm, build, emit-obj: size of the data stack (default: 4096, at most 67108864)
+  --proc-stack <cells>         emit-asm, build, emit-obj: size of the proc stack, the maximum call depth (default: 8192, at most 67108864)
+  -q, --quiet                  Only print errors
+  -v, --verbose                emit-asm, build, emit-obj: report what was inlined or left out as unused
+  --error-format=human|json    How diagnostics are printed on stderr
+  -h, --help                   Show this help
+  -- <args>...                 run: arguments given to the program after its argv[0], the source file";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ECommand {
+    Run,
+    Build,
+    EmitIr,
+    EmitAsm,
+    EmitObj,
+    EmitHeader,
+    Check,
+}
+
+// How `build` turns the assembly into an executable
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EBackend {
+    Nasm,
+    Builtin,
+}
+
+#[derive(Debug)]
+pub struct COptions {
+    pub l_eCommand: ECommand,
+    pub l_sInput: String,
+    pub l_oOutput: Option<String>,
+    pub l_eBackend: EBackend,
+    pub l_bKeepAsm: bool,
+    pub l_sAssembler: String,
+    pub l_sLinker: String,
+    pub l_lLibs: Vec<String>,
+    pub l_iOptLevel: u8,
+    pub l_bCheckStack: bool,
+    pub l_iDataStack: usize,
+    pub l_iProcStack: usize,
+    pub l_bQuiet: bool,
+    pub l_bVerbose: bool,
+    pub l_eFormat: EDiagnosticFormat,
+    pub l_lProgramArgs: Vec<String>,
+}
+
+// Ok(None) when help was asked for
+pub fn F_oParseArgs(l_lArgs: &[String]) -> Result<Option<COptions>, String> {
+    let mut l_lRest = l_lArgs.iter();
+
+    let l_eCommand = match l_lRest.next().map(String::as_str) {
+        Some("run") => ECommand::Run,
+        Some("build") => ECommand::Build,
+        Some("emit-ir") => ECommand::EmitIr,
+        Some("emit-asm") => ECommand::EmitAsm,
+        Some("emit-obj") => ECommand::EmitObj,
+        Some("emit-header") => ECommand::EmitHeader,
+        Some("check") => ECommand::Check,
+        Some("-h" | "--help") => return Ok(None),
+        Some(l_sOther) => return Err(format!("unknown command '{}'", l_sOther)),
+        None => return Err("missing command".to_string()),
+    };
+
+    let mut l_oInput = None;
+    let mut l_oOutput = None;
+    let mut l_eBackend = EBackend::Nasm;
+    let mut l_bKeepAsm = false;
+    let mut l_sAssembler = "nasm".to_string();
+    let mut l_sLinker = "ld".to_string();
+    let mut l_lLibs = Vec::new();
+    let mut l_iOptLevel = 1;
+    let mut l_bCheckStack = false;
+    let mut l_iDataStack = DATA_STACK_CELLS;
+    let mut l_iProcStack = PROC_STACK_CELLS;
+    let mut l_bQuiet = false;
+    let mut l_bVerbose = false;
+    let mut l_eFormat = EDiagnosticFormat::Human;
+    let mut l_lProgramArgs = Vec::new();
+
+    while let Some(l_sArg) = l_lRest.next() {
+        match l_sArg.as_str() {
+            "-o" => {
+                let l_sPath = l_lRest.next().ok_or("-o expects a path")?;
+                l_oOutput = Some(l_sPath.clone());
+            }
+            "--backend=nasm" => l_eBackend = EBackend::Nasm,
+            "--backend=builtin" => l_eBackend = EBackend::Builtin,
+            "--keep-asm" => l_bKeepAsm = true,
+            "--assembler" => l_sAssembler = l_lRest.next().ok_or("--assembler expects a command")?.clone(),
+            "--linker" => l_sLinker = l_lRest.next().ok_or("--linker expects a command")?.clone(),
+            "-l" => l_lLibs.push(l_lRest.next().ok_or("-l expects a library name")?.clone()),
+            l_sLib if l_sLib.starts_with("-l") => l_lLibs.push(l_sLib[2..].to_string()),
+            "-O0" => l_iOptLevel = 0,
+            "-O1" => l_iOptLevel = 1,
+            "-O2" => l_iOptLevel = 2,
+            "--check-stack" => l_bCheckStack = true,
+            "--data-stack" => l_iDataStack = F_iCells(l_sArg, l_lRest.next())?,
+            "--proc-stack" => l_iProcStack = F_iCells(l_sArg, l_lRest.next())?,
+            "-q" | "--quiet" => l_bQuiet = true,
+            "-v" | "--verbose" => l_bVerbose = true,
+            "--error-format=human" => l_eFormat = EDiagnosticFormat::Human,
+            "--error-format=json" => l_eFormat = EDiagnosticFormat::Json,
+            "-h" | "--help" => return Ok(None),
+            "--" => {
+                if l_eCommand != ECommand::Run {
+                    return Err("arguments after '--' are only passed to the program by run".to_string());
+                }
+                l_lProgramArgs = l_lRest.by_ref().cloned().collect();
+            }
+            l_sOption if l_sOption.starts_with('-') => return Err(format!("unknown option '{}'", l_sOption)),
+            l_sFile => {
+                if l_oInput.is_some() {
+                    return Err(format!("unexpected argument '{}', only one source file is accepted", l_sFile));
+                }
+                l_oInput = Some(l_sFile.to_string());
+            }
+        }
+    }
+
+    let l_sInput = l_oInput.ok_or("missing source file")?;
+    Ok(Some(COptions { l_eCommand, l_sInput, l_oOutput, l_eBackend, l_bKeepAsm, l_sAssembler, l_sLinker, l_lLibs, l_iOptLevel, l_bCheckStack, l_iDataStack, l_iProcStack, l_bQuiet, l_bVerbose, l_eFormat, l_lProgramArgs }))
+}
+
+// Largest stack: the 8-byte cells of both stacks must fit in the `.bss` the
+// compiled code can address
+const STACK_CELLS_MAX: usize = BSS_SIZE_MAX / 16;
+
+// Stack size given to l_sOption, in cells
+fn F_iCells(l_sOption: &str, l_oValue: Option<&String>) -> Result<usize, String> {
+    let l_sValue = l_oValue.ok_or_else(|| format!("{} expects a number of cells", l_sOption))?;
+    match l_sValue.parse::<usize>() {
+        Ok(l_iCells) if (1..=STACK_CELLS_MAX).contains(&l_iCells) => Ok(l_iCells),
+        _ => Err(format!(
+            "{} expects a number of cells between 1 and {}, got '{}'", l_sOption, STACK_CELLS_MAX, l_sValue
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn F_rParse(l_lArgs: &[&str]) -> Result<Option<COptions>, String> {
+        let l_lArgs: Vec<String> = l_lArgs.iter().map(|l_sArg| l_sArg.to_string()).collect();
+        F_oParseArgs(&l_lArgs)
+    }
+
+    fn F_cOptions(l_lArgs: &[&str]) -> COptions {
+        F_rParse(l_lArgs).unwrap().expect("options, not help")
+    }
+
+    #[test]
+    fn defaults() {
+        let l_cOptions = F_cOptions(&["build", "main.eb"]);
+        assert_eq!(l_cOptions.l_eCommand, ECommand::Build);
+        assert_eq!(l_cOptions.l_sInput, "main.eb");
+        assert_eq!(l_cOptions.l_oOutput, None);
+        assert_eq!(l_cOptions.l_eBackend, EBackend::Nasm);
+        assert_eq!(l_cOptions.l_sAssembler, "nasm");
+        assert_eq!(l_cOptions.l_sLinker, "ld");
+        assert_eq!(l_cOptions.l_iOptLevel, 1);
+        assert!(!l_cOptions.l_bCheckStack);
+        assert_eq!(l_cOptions.l_iDataStack, DATA_STACK_CELLS);
+        assert_eq!(l_cOptions.l_iProcStack, PROC_STACK_CELLS);
+        assert_eq!(l_cOptions.l_eFormat, EDiagnosticFormat::Human);
+        assert!(l_cOptions.l_lProgramArgs.is_empty());
+    }
+
+    #[test]
+    fn options() {
+        let l_cOptions = F_cOptions(&[
+            "build", "-O2", "--backend=builtin", "-o", "out", "-lm", "-l", "z", "--check-stack",
+            "--data-stack", "16", "--proc-stack", "32", "--error-format=json", "-q", "main.eb",
+        ]);
+        assert_eq!(l_cOptions.l_iOptLevel, 2);
+        assert_eq!(l_cOptions.l_eBackend, EBackend::Builtin);
+        assert_eq!(l_cOptions.l_oOutput.as_deref(), Some("out"));
+        assert_eq!(l_cOptions.l_lLibs, ["m", "z"]);
+        assert!(l_cOptions.l_bCheckStack);
+        assert_eq!((l_cOptions.l_iDataStack, l_cOptions.l_iProcStack), (16, 32));
+        assert_eq!(l_cOptions.l_eFormat, EDiagnosticFormat::Json);
+        assert!(l_cOptions.l_bQuiet);
+    }
+
+    #[test]
+    fn program_arguments() {
+        let l_cOptions = F_cOptions(&["run", "main.eb", "--", "-O2", "x"]);
+        assert_eq!(l_cOptions.l_lProgramArgs, ["-O2", "x"]);
+        assert_eq!(l_cOptions.l_iOptLevel, 1);
+        assert_eq!(
+            F_rParse(&["build", "main.eb", "--", "x"]).unwrap_err(),
+            "arguments after '--' are only passed to the program by run"
+        );
+    }
+
+    #[test]
+    fn help() {
+        assert!(F_rParse(&["--help"]).unwrap().is_none());
+        assert!(F_rParse(&["build", "-h"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn errors() {
+        let F_sError = |l_lArgs: &[&str]| F_rParse(l_lArgs).unwrap_err();
+        assert_eq!(F_sError(&[]), "missing command");
+        assert_eq!(F_sError(&["compile", "main.eb"]), "unknown command 'compile'");
+        assert_eq!(F_sError(&["build"]), "missing source file");
+        assert_eq!(F_sError(&["build", "-O3", "main.eb"]), "unknown option '-O3'");
+        assert_eq!(F_sError(&["build", "main.eb", "-o"]), "-o expects a path");
+        assert_eq!(
+            F_sError(&["build", "a.eb", "b.eb"]),
+            "unexpected argument 'b.eb', only one source file is accepted"
+        );
+    }
+
+    #[test]
+    fn stack_cells_bounds() {
+        assert_eq!(F_cOptions(&["build", "--data-stack", "67108864", "main.eb"]).l_iDataStack, STACK_CELLS_MAX);
+        for l_sCells in ["0", "67108865", "-1", "many"] {
+            assert_eq!(
+                F_rParse(&["build", "--proc-stack", l_sCells, "main.eb"]).unwrap_err(),
+                format!("--proc-stack expects a number of cells between 1 and 67108864, got '{}'", l_sCells)
+            );
+        }
+        assert_eq!(F_rParse(&["build", "--data-stack"]).unwrap_err(), "--data-stack expects a number of cells");
+    }
+}
@@ -0,0 +1,290 @@
+#![allow(nonstandard_style)]
+use std::collections::{HashMap, HashSet};
+use crate::{CStackToInterpreter, EIrInstr, EType, F_lCallers, F_sTimes};
+use crate::checker::CStackEffects;
+use crate::diagnostic::CDiagnostic;
+use crate::source::CSourceMap;
+
+// ============================================================================
+// Compile-time evaluation of consts: every const body runs once in the
+// interpreter's constant mode (no syscalls, stores, output, C calls or exit)
+// and each call to it is replaced by the literals of the values it left. Runs
+// on a checked program, for the interpreter as well as for the compiler.
+// ============================================================================
+
+// Literals pushing l_lValues, typed by the const's result cells. A pointer
+// must point into a string literal; a pointer + length pair covering a whole
+// literal becomes that literal.
+fn F_lLiterals(
+    l_cInterp: &CStackToInterpreter,
+    l_lValues: &[i64],
+    l_lTypes: &[EType],
+) -> Result<Vec<EIrInstr>, String> {
+    let mut l_lLiterals = Vec::new();
+    let mut i = 0;
+    while i < l_lValues.len() {
+        if l_lTypes.get(i) != Some(&EType::Ptr) {
+            l_lLiterals.push(EIrInstr::PushI64(l_lValues[i]));
+            i += 1;
+            continue;
+        }
+        let l_iAddr = l_lValues[i];
+        let (l_sStr, l_iStart) = l_cInterp.l_hmStrings.values()
+            .find(|(l_sStr, l_iStart)| (*l_iStart..=l_iStart + l_sStr.len() as i64).contains(&l_iAddr))
+            .copied()
+            .ok_or_else(|| format!("value {} is a pointer outside of the string literals", i + 1))?;
+        // Each substitution gets its own copy, like every literal of the source
+        let l_sCopy: &'static str = Box::leak(l_sStr.to_string().into_boxed_str());
+        l_lLiterals.push(EIrInstr::PushStr(l_sCopy));
+        if l_iAddr == l_iStart && l_lValues.get(i + 1) == Some(&(l_sStr.len() as i64)) {
+            i += 2;
+            continue;
+        }
+        l_lLiterals.push(EIrInstr::Drop);
+        if l_iAddr != l_iStart {
+            l_lLiterals.push(EIrInstr::PushI64(l_iAddr - l_iStart));
+            l_lLiterals.push(EIrInstr::AddI64);
+        }
+        i += 1;
+    }
+    Ok(l_lLiterals)
+}
+
+// Values only known once the program runs: memory addresses and its arguments
+fn F_bRunTimeValue(l_cInstr: &EIrInstr) -> bool {
+    matches!(l_cInstr, EIrInstr::PushMem(_) | EIrInstr::Argc | EIrInstr::Argv | EIrInstr::Envp)
+}
+
+// First instruction with a side effect other than reading a run-time value
+// in l_sName or in what it calls, with its error
+fn F_oSideEffect(l_cInterp: &CStackToInterpreter, l_sName: &'static str) -> Option<(&'static str, usize, String)> {
+    let mut l_lPending = vec![l_sName];
+    let mut l_hsSeen = HashSet::from([l_sName]);
+    while let Some(l_sDecl) = l_lPending.pop() {
+        let Some(l_lBody) = l_cInterp.l_hmProcs.get(l_sDecl) else { continue };
+        for (l_iPc, l_cInstr) in l_lBody.iter().enumerate() {
+            match l_cInstr {
+                l_cValue if F_bRunTimeValue(l_cValue) => {}
+                EIrInstr::Call(l_sTarget) => {
+                    if l_hsSeen.insert(l_sTarget) {
+                        l_lPending.push(l_sTarget);
+                    }
+                }
+                _ => if let Err(e) = CStackToInterpreter::F_vCheckConstInstr(l_cInstr) {
+                    return Some((l_sDecl, l_iPc, e));
+                }
+            }
+        }
+    }
+    None
+}
+
+// Replaces the calls to folded consts in every body, keeping l_cSourceMap
+// in step with the new instruction indices
+fn F_vSubstitute(l_lProgram: &mut [EIrInstr], l_hmFolded: &HashMap<&'static str, Vec<EIrInstr>>, l_cSourceMap: &mut CSourceMap) {
+    for l_cDecl in l_lProgram.iter_mut() {
+        let (l_sName, l_lBody) = match l_cDecl {
+            EIrInstr::Proc(l_sName, l_lBody, _, _) | EIrInstr::Const(l_sName, l_lBody) | EIrInstr::Memory(l_sName, l_lBody) => {
+                (*l_sName, l_lBody)
+            }
+            _ => continue,
+        };
+        // From the end, so that the indices still to visit do not move
+        for l_iPc in (0..l_lBody.len()).rev() {
+            let EIrInstr::Call(l_sTarget) = l_lBody[l_iPc] else { continue };
+            let Some(l_lLiterals) = l_hmFolded.get(l_sTarget) else { continue };
+            let l_lCopy: Vec<EIrInstr> = l_lLiterals.iter()
+                .map(|l_cLiteral| match l_cLiteral {
+                    EIrInstr::PushStr(l_sStr) => EIrInstr::PushStr(Box::leak(l_sStr.to_string().into_boxed_str())),
+                    l_cOther => l_cOther.clone(),
+                })
+                .collect();
+            l_cSourceMap.F_vSplice(l_sName, l_iPc, l_lCopy.len());
+            l_lBody.splice(l_iPc..=l_iPc, l_lCopy);
+        }
+    }
+}
+
+// Evaluates every const and substitutes its calls. A const using a memory
+// address or the program's arguments is only known at run time and stays a
+// call, unless it could also write memory or have another side effect; that,
+// or not terminating, is an error.
+pub fn F_lFoldConsts(
+    l_lIr: &[EIrInstr],
+    l_hmEffects: &CStackEffects,
+    l_cSourceMap: &mut CSourceMap,
+) -> Result<Vec<EIrInstr>, Vec<CDiagnostic>> {
+    let mut l_hmFolded: HashMap<&'static str, Vec<EIrInstr>> = HashMap::new();
+    let mut l_lDiagnostics = Vec::new();
+
+    {
+        let mut l_cInterp = CStackToInterpreter::F_cNew(l_lIr, true, &[])
+            .map_err(|e| vec![CDiagnostic::F_cError("E0108", e)])?;
+        let l_cSourceMap: &CSourceMap = l_cSourceMap;
+
+        for l_cInstr in l_lIr {
+            let EIrInstr::Const(l_sName, l_lBody) = l_cInstr else { continue };
+            let mut l_lStack = Vec::new();
+            l_cInterp.l_lErrorTrace.clear();
+            l_cInterp.l_iSteps = 0;
+
+            let l_rFolded = match l_cInterp.F_vExecuteBody(l_sName, l_lBody, &mut l_lStack) {
+                Ok(()) => {
+                    let l_lTypes = l_hmEffects.get(l_sName).map_or(&[][..], |(_, l_lRets)| l_lRets.as_slice());
+                    F_lLiterals(&l_cInterp, &l_lStack, l_lTypes).map_err(|e| (e, None))
+                }
+                Err(e) => {
+                    let (l_sDecl, l_iPc) = l_cInterp.l_lErrorTrace[0];
+                    if !l_cInterp.l_hmProcs.get(l_sDecl).and_then(|l_lBody| l_lBody.get(l_iPc)).is_some_and(F_bRunTimeValue) {
+                        Err((e, Some(l_cInterp.l_lErrorTrace.clone())))
+                    } else if let Some((l_sDecl, l_iPc, e)) = F_oSideEffect(&l_cInterp, l_sName) {
+                        Err((e, Some(vec![(l_sDecl, l_iPc)])))
+                    } else {
+                        continue;
+                    }
+                }
+            };
+            match l_rFolded {
+                Ok(l_lLiterals) => { l_hmFolded.insert(l_sName, l_lLiterals); }
+                Err((l_sMessage, l_oTrace)) => {
+                    let l_cDiagnostic = CDiagnostic::F_cError(
+                        "E0108", format!("const {} cannot be evaluated at compile time: {}", l_sName, l_sMessage)
+                    );
+                    let l_cDiagnostic = match l_oTrace {
+                        // Located at the failing instruction, through the calls leading to it
+                        Some(l_lTrace) => {
+                            let mut l_cDiagnostic = l_cDiagnostic.F_cAt(l_cSourceMap.F_oSpan(l_lTrace[0].0, Some(l_lTrace[0].1)));
+                            for (l_sDecl, l_iPc, l_iTimes) in F_lCallers(&l_lTrace) {
+                                if let Some(l_cSpan) = l_cSourceMap.F_oSpan(l_sDecl, Some(l_iPc)) {
+                                    let (l_iLine, l_iCol) = l_cSourceMap.F_tLineCol(l_cSpan);
+                                    l_cDiagnostic = l_cDiagnostic.F_cNote(format!(
+                                        "called from {} at {}:{}:{}{}", l_sDecl, l_cSourceMap.l_sFile, l_iLine, l_iCol, F_sTimes(l_iTimes)
+                                    ));
+                                }
+                            }
+                            l_cDiagnostic
+                        }
+                        None => l_cDiagnostic.F_cAt(l_cSourceMap.F_oSpan(l_sName, None)),
+                    };
+                    l_lDiagnostics.push(l_cDiagnostic);
+                }
+            }
+        }
+    }
+
+    if !l_lDiagnostics.is_empty() {
+        return Err(l_lDiagnostics);
+    }
+    let mut l_lProgram = l_lIr.to_vec();
+    F_vSubstitute(&mut l_lProgram, &l_hmFolded, l_cSourceMap);
+
+    // Memory sizes are fixed before the program runs, so `check` reports them too
+    for l_cInstr in &l_lProgram {
+        let EIrInstr::Memory(l_sName, l_lBody) = l_cInstr else { continue };
+        if let Err(e) = CStackToInterpreter::F_iMemorySize(&l_lProgram, l_sName, l_lBody) {
+            l_lDiagnostics.push(CDiagnostic::F_cError("E0108", e).F_cAt(l_cSourceMap.F_oSpan(l_sName, None)));
+        }
+    }
+    if !l_lDiagnostics.is_empty() {
+        return Err(l_lDiagnostics);
+    }
+    Ok(l_lProgram)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checker::F_vCheckProgram;
+    use crate::syntax::F_lParseProgram;
+
+    // The checked program before folding, and the result of folding it
+    fn F_tFold(l_sCode: &str) -> (Vec<EIrInstr>, Result<Vec<EIrInstr>, Vec<CDiagnostic>>) {
+        let mut l_cSourceMap = CSourceMap::F_cNew("test.eb", l_sCode);
+        let l_lProgram = F_lParseProgram(l_sCode, &mut l_cSourceMap).expect("test program parses");
+        let l_hmEffects = F_vCheckProgram(&l_lProgram, &l_cSourceMap).expect("test program checks");
+        let l_rFolded = F_lFoldConsts(&l_lProgram, &l_hmEffects, &mut l_cSourceMap);
+        (l_lProgram, l_rFolded)
+    }
+
+    fn F_lBody<'p>(l_lProgram: &'p [EIrInstr], l_sProc: &str) -> &'p [EIrInstr] {
+        l_lProgram.iter().find_map(|l_cInstr| match l_cInstr {
+            EIrInstr::Proc(l_sName, l_lBody, _, _) if *l_sName == l_sProc => Some(l_lBody.as_slice()),
+            _ => None,
+        }).expect("proc exists")
+    }
+
+    // What proc l_sProc leaves on the stack, run in the interpreter
+    fn F_lRun(l_lProgram: &[EIrInstr], l_sProc: &'static str) -> Vec<i64> {
+        let mut l_cInterp = CStackToInterpreter::F_cNew(l_lProgram, true, &[]).unwrap();
+        let mut l_lStack = Vec::new();
+        l_cInterp.F_vExecuteBody(l_sProc, F_lBody(l_lProgram, l_sProc), &mut l_lStack).unwrap();
+        l_lStack
+    }
+
+    // The single E0108 error of a const that cannot be folded
+    fn F_sError(l_sConst: &str) -> String {
+        let (_, l_rFolded) = F_tFold(&format!("const c {} end\nproc main in c drop end\n", l_sConst));
+        let l_lDiagnostics = l_rFolded.expect_err("const is rejected");
+        assert_eq!(l_lDiagnostics.len(), 1);
+        assert_eq!(l_lDiagnostics[0].l_sCode, "E0108");
+        l_lDiagnostics[0].l_sMessage.clone()
+    }
+
+    #[test]
+    fn overflow_wraps_around() {
+        let (_, l_rFolded) = F_tFold("\
+const max 9223372036854775807 end
+const wrapped max 1 + end
+const doubled max 2 * end
+const shifted 1 64 << end
+proc main in wrapped doubled shifted drop drop drop end
+");
+        let l_lFolded = l_rFolded.unwrap();
+        assert!(matches!(
+            F_lBody(&l_lFolded, "main")[..3],
+            [EIrInstr::PushI64(i64::MIN), EIrInstr::PushI64(-2), EIrInstr::PushI64(1)]
+        ));
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        let l_sExpected = "const c cannot be evaluated at compile time: Division by zero";
+        assert_eq!(F_sError("1 0 /"), l_sExpected);
+        assert_eq!(F_sError("1 0 %"), l_sExpected);
+        assert_eq!(F_sError("1 0 udiv"), l_sExpected);
+        assert_eq!(F_sError("1 0 umod"), l_sExpected);
+    }
+
+    #[test]
+    fn quotient_overflow_is_an_error() {
+        let l_sExpected = "const c cannot be evaluated at compile time: \
+            Division overflow: the quotient of i64::MIN by -1 does not fit in 64 bits";
+        assert_eq!(F_sError("-9223372036854775807 1 - -1 /"), l_sExpected);
+    }
+
+    #[test]
+    fn side_effect_is_an_error() {
+        assert_eq!(
+            F_sError("1 dump 2"),
+            "const c cannot be evaluated at compile time: output is not allowed in a constant expression (Dump)"
+        );
+    }
+
+    #[test]
+    fn folding_keeps_the_results() {
+        let (l_lProgram, l_rFolded) = F_tFold("\
+const answer 6 7 * end
+const half answer 2 / end
+const greeting \"hello\" end
+const rest greeting drop 1 + end
+proc calc ret i64 i64 i64 i64 i64 in
+    answer half greeting swap drop rest @8 greeting drop @8
+end
+proc main in calc drop drop drop drop drop end
+");
+        let l_lFolded = l_rFolded.unwrap();
+        assert!(!F_lBody(&l_lFolded, "calc").iter().any(|l_cInstr| matches!(l_cInstr, EIrInstr::Call(_))));
+        assert_eq!(F_lRun(&l_lFolded, "calc"), [42, 21, 5, b'e' as i64, b'h' as i64]);
+        assert_eq!(F_lRun(&l_lProgram, "calc"), F_lRun(&l_lFolded, "calc"));
+    }
+}
@@ -0,0 +1,191 @@
+#![allow(nonstandard_style)]
+use crate::source::{CSourceMap, CSpan};
+
+// ============================================================================
+// Diagnostics reported by every stage, rendered for humans or as JSON lines.
+//
+// Codes:
+//   E0001  lexical error (unexpected character, unterminated string, ...)
+//   E0002  syntax error
+//   E0003  name declared more than once
+//   E0101  call to an undefined proc
+//   E0102  stack underflow
+//   E0103  type mismatch
+//   E0104  stack effect does not match the declaration
+//   E0105  branches or loop iterations leave different stacks
+//   E0106  const defined in terms of itself
+//   E0107  exported proc or extern has a signature C cannot call
+//   E0108  const that cannot be evaluated at compile time
+//   E0109  `main` with parameters or with a result other than an exit status
+//   E0201  runtime error in the interpreter
+//   E0202  no `main` proc
+//   E0301  code generation error
+//   E0302  assembler or linker failed
+// ============================================================================
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ESeverity {
+    Error,
+}
+
+impl ESeverity {
+    fn F_sName(&self) -> &'static str {
+        match self {
+            ESeverity::Error => "error",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CDiagnostic {
+    pub l_eSeverity: ESeverity,
+    pub l_sCode: &'static str,
+    pub l_oSpan: Option<CSpan>,
+    pub l_sMessage: String,
+    pub l_lNotes: Vec<String>,
+}
+
+impl CDiagnostic {
+    pub fn F_cError(l_sCode: &'static str, l_sMessage: impl Into<String>) -> Self {
+        CDiagnostic { l_eSeverity: ESeverity::Error, l_sCode, l_oSpan: None, l_sMessage: l_sMessage.into(), l_lNotes: Vec::new() }
+    }
+
+    pub fn F_cAt(mut self, l_oSpan: Option<CSpan>) -> Self {
+        self.l_oSpan = l_oSpan;
+        self
+    }
+
+    pub fn F_cNote(mut self, l_sNote: impl Into<String>) -> Self {
+        self.l_lNotes.push(l_sNote.into());
+        self
+    }
+
+    //   error[E0201]: Division by zero
+    //    --> example/div.eb:2:9
+    //     |
+    //   2 |     1 0 / dump
+    //     |         ^
+    //     = note: ...
+    pub fn F_sRenderHuman(&self, l_cSourceMap: &CSourceMap) -> String {
+        let mut l_sOut = format!("{}[{}]: {}", self.l_eSeverity.F_sName(), self.l_sCode, self.l_sMessage);
+        let mut l_sPad = String::new();
+        match self.l_oSpan {
+            Some(l_cSpan) => {
+                let (l_iLine, l_iCol) = l_cSourceMap.F_tLineCol(l_cSpan);
+                l_sPad = " ".repeat(l_iLine.to_string().len());
+                l_sOut += &format!("\n{}--> {}:{}:{}\n{}", l_sPad, l_cSourceMap.l_sFile, l_iLine, l_iCol, l_cSourceMap.F_sSnippet(l_cSpan));
+            }
+            None => l_sOut += &format!("\n --> {}", l_cSourceMap.l_sFile),
+        }
+        for l_sNote in &self.l_lNotes {
+            l_sOut += &format!("\n{} = note: {}", l_sPad, l_sNote);
+        }
+        l_sOut
+    }
+
+    // One JSON object, without a trailing newline:
+    // {"severity":"error","code":"E0201","message":"...","file":"...",
+    //  "span":{"line":2,"column":9,"length":1},"notes":[]}
+    pub fn F_sRenderJson(&self, l_cSourceMap: &CSourceMap) -> String {
+        let l_sSpan = match self.l_oSpan {
+            Some(l_cSpan) => {
+                let (l_iLine, l_iCol) = l_cSourceMap.F_tLineCol(l_cSpan);
+                format!("{{\"line\":{},\"column\":{},\"length\":{}}}", l_iLine, l_iCol, l_cSpan.l_iLen)
+            }
+            None => "null".to_string(),
+        };
+        let l_lNotes: Vec<String> = self.l_lNotes.iter().map(|l_sNote| F_sJsonString(l_sNote)).collect();
+        format!(
+            "{{\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\"file\":{},\"span\":{},\"notes\":[{}]}}",
+            self.l_eSeverity.F_sName(), self.l_sCode, F_sJsonString(&self.l_sMessage),
+            F_sJsonString(&l_cSourceMap.l_sFile), l_sSpan, l_lNotes.join(",")
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EDiagnosticFormat {
+    Human,
+    Json,
+}
+
+// Prints diagnostics to stderr: blank-line separated blocks for humans, one
+// JSON object per line otherwise
+pub fn F_vEmitDiagnostics(l_lDiagnostics: &[CDiagnostic], l_cSourceMap: &CSourceMap, l_eFormat: EDiagnosticFormat) {
+    for l_cDiagnostic in l_lDiagnostics {
+        match l_eFormat {
+            EDiagnosticFormat::Human => eprintln!("{}\n", l_cDiagnostic.F_sRenderHuman(l_cSourceMap)),
+            EDiagnosticFormat::Json => eprintln!("{}", l_cDiagnostic.F_sRenderJson(l_cSourceMap)),
+        }
+    }
+}
+
+fn F_sJsonString(l_sText: &str) -> String {
+    let mut l_sOut = String::from("\"");
+    for l_cChar in l_sText.chars() {
+        match l_cChar {
+            '"' => l_sOut += "\\\"",
+            '\\' => l_sOut += "\\\\",
+            '\n' => l_sOut += "\\n",
+            '\r' => l_sOut += "\\r",
+            '\t' => l_sOut += "\\t",
+            l_cC if (l_cC as u32) < 0x20 => l_sOut += &format!("\\u{:04x}", l_cC as u32),
+            l_cC => l_sOut.push(l_cC),
+        }
+    }
+    l_sOut.push('"');
+    l_sOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "proc main in\n    1 0 / dump\nend\n";
+
+    fn F_cDivision() -> CDiagnostic {
+        CDiagnostic::F_cError("E0201", "Division by zero")
+            .F_cAt(Some(CSpan::F_cNew(21, 1)))
+            .F_cNote("called from proc main at div.eb:5:5")
+    }
+
+    #[test]
+    fn human() {
+        let l_cSourceMap = CSourceMap::F_cNew("div.eb", SOURCE);
+        assert_eq!(F_cDivision().F_sRenderHuman(&l_cSourceMap), "\
+error[E0201]: Division by zero
+ --> div.eb:2:9
+  |
+2 |     1 0 / dump
+  |         ^
+  = note: called from proc main at div.eb:5:5");
+    }
+
+    #[test]
+    fn human_without_span() {
+        let l_cSourceMap = CSourceMap::F_cNew("div.eb", SOURCE);
+        let l_cDiagnostic = CDiagnostic::F_cError("E0202", "no `main` proc declared");
+        assert_eq!(l_cDiagnostic.F_sRenderHuman(&l_cSourceMap), "error[E0202]: no `main` proc declared\n --> div.eb");
+    }
+
+    #[test]
+    fn json() {
+        let l_cSourceMap = CSourceMap::F_cNew("div.eb", SOURCE);
+        assert_eq!(
+            F_cDivision().F_sRenderJson(&l_cSourceMap),
+            "{\"severity\":\"error\",\"code\":\"E0201\",\"message\":\"Division by zero\",\"file\":\"div.eb\",\
+             \"span\":{\"line\":2,\"column\":9,\"length\":1},\"notes\":[\"called from proc main at div.eb:5:5\"]}"
+        );
+    }
+
+    #[test]
+    fn json_without_span_and_escapes() {
+        let l_cSourceMap = CSourceMap::F_cNew("dir\\\"x\".eb", SOURCE);
+        let l_cDiagnostic = CDiagnostic::F_cError("E0302", "nasm failed:\n\tline\u{1}");
+        assert_eq!(
+            l_cDiagnostic.F_sRenderJson(&l_cSourceMap),
+            "{\"severity\":\"error\",\"code\":\"E0302\",\"message\":\"nasm failed:\\n\\tline\\u0001\",\
+             \"file\":\"dir\\\\\\\"x\\\".eb\",\"span\":null,\"notes\":[]}"
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+
+use crate::x86::{Object, RelocKind, Section};
+
+// ============================================================================
+// ELF64 Writer
+// ============================================================================
+//
+// Lays out the sections of an assembled `Object` and writes either a static
+// x86-64 executable (two PT_LOAD segments: headers + .text read/execute,
+// .data + .bss read/write; relocations applied; a symbol table for
+// debuggers) or a relocatable object for the system linker.
+
+const BASE_ADDR: u64 = 0x400000;
+const PAGE: u64 = 0x1000;
+
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+const SHDR_SIZE: u64 = 64;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const R_X86_64_64: u64 = 1;
+const R_X86_64_PC32: u64 = 2;
+const R_X86_64_PLT32: u64 = 4;
+const R_X86_64_32S: u64 = 11;
+
+// Section header indices, in the order they are written
+const SHN_TEXT: u16 = 1;
+const SHN_DATA: u16 = 2;
+const SHN_BSS: u16 = 3;
+
+/// Addresses of the three sections once laid out
+struct Layout {
+    text: u64,
+    data: u64,
+    bss: u64,
+}
+
+impl Layout {
+    fn base(&self, section: Section) -> u64 {
+        match section {
+            Section::Text => self.text,
+            Section::Data => self.data,
+            Section::Bss => self.bss,
+        }
+    }
+}
+
+/// Null-separated string table; index 0 is the empty string
+struct StrTab {
+    bytes: Vec<u8>,
+}
+
+impl StrTab {
+    fn new() -> Self {
+        Self { bytes: vec![0] }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let index = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(s.as_bytes());
+        self.bytes.push(0);
+        index
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+fn put16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put64(out: &mut Vec<u8>, v: u64) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn pad_to(out: &mut Vec<u8>, offset: u64) {
+    out.resize(offset as usize, 0);
+}
+
+fn write_ehdr(out: &mut Vec<u8>, kind: u16, entry: u64, phnum: u16, shoff: u64, shnum: u16, shstrndx: u16) {
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]); // 64-bit, little endian, version 1, SysV
+    out.extend_from_slice(&[0; 8]);
+    put16(out, kind);
+    put16(out, 62); // EM_X86_64
+    put32(out, 1);
+    put64(out, entry);
+    put64(out, if phnum > 0 { EHDR_SIZE } else { 0 });
+    put64(out, shoff);
+    put32(out, 0);
+    put16(out, EHDR_SIZE as u16);
+    put16(out, if phnum > 0 { PHDR_SIZE as u16 } else { 0 });
+    put16(out, phnum);
+    put16(out, SHDR_SIZE as u16);
+    put16(out, shnum);
+    put16(out, shstrndx);
+}
+
+fn write_phdr(out: &mut Vec<u8>, flags: u32, offset: u64, addr: u64, filesz: u64, memsz: u64) {
+    put32(out, 1); // PT_LOAD
+    put32(out, flags);
+    put64(out, offset);
+    put64(out, addr);
+    put64(out, addr);
+    put64(out, filesz);
+    put64(out, memsz);
+    put64(out, PAGE);
+}
+
+fn write_shdrs(out: &mut Vec<u8>, headers: &[SectionHeader]) {
+    out.extend_from_slice(&[0; SHDR_SIZE as usize]);
+    for h in headers {
+        put32(out, h.name);
+        put32(out, h.kind);
+        put64(out, h.flags);
+        put64(out, h.addr);
+        put64(out, h.offset);
+        put64(out, h.size);
+        put32(out, h.link);
+        put32(out, h.info);
+        put64(out, h.align);
+        put64(out, h.entsize);
+    }
+}
+
+fn section_index(section: Section) -> u16 {
+    match section {
+        Section::Text => SHN_TEXT,
+        Section::Data => SHN_DATA,
+        Section::Bss => SHN_BSS,
+    }
+}
+
+/// .symtab entries of every label (locals first, as ELF requires) with values
+/// from `value_of`, then the externs as undefined globals. Returns the table,
+/// the index of the first global and the index of every symbol by name.
+fn symtab(obj: &Object, strtab: &mut StrTab, value_of: impl Fn(Section, usize) -> u64) -> (Vec<u8>, u32, HashMap<String, u32>) {
+    let mut out = vec![0; 24];
+    let mut indices = HashMap::new();
+    let mut symbols: Vec<_> = obj.symbols.iter().collect();
+    symbols.sort_by_key(|s| obj.is_global(&s.name));
+    let first_global = 1 + symbols.iter().take_while(|s| !obj.is_global(&s.name)).count() as u32;
+    for s in symbols {
+        indices.insert(s.name.clone(), (out.len() / 24) as u32);
+        put32(&mut out, strtab.add(&s.name));
+        let binding = if obj.is_global(&s.name) { 1 } else { 0 };
+        let kind = if s.section == Section::Text { 2 } else { 1 }; // STT_FUNC / STT_OBJECT
+        out.push((binding << 4) | kind);
+        out.push(0);
+        put16(&mut out, section_index(s.section));
+        put64(&mut out, value_of(s.section, s.offset));
+        put64(&mut out, 0);
+    }
+    for name in &obj.externs {
+        indices.insert(name.clone(), (out.len() / 24) as u32);
+        put32(&mut out, strtab.add(name));
+        out.push(1 << 4); // STB_GLOBAL, STT_NOTYPE
+        out.push(0);
+        put16(&mut out, 0); // SHN_UNDEF
+        put64(&mut out, 0);
+        put64(&mut out, 0);
+    }
+    (out, first_global, indices)
+}
+
+// ============================================================================
+// Executable
+// ============================================================================
+
+pub fn write_executable(obj: &Object, entry: &str) -> Result<Vec<u8>, String> {
+    if let Some(name) = obj.externs.first() {
+        return Err(format!("`{}` is extern: a static executable cannot import symbols", name));
+    }
+
+    let text_off = EHDR_SIZE + 2 * PHDR_SIZE;
+    let data_off = (text_off + obj.text.len() as u64).next_multiple_of(PAGE);
+    let layout = Layout {
+        text: BASE_ADDR + text_off,
+        data: BASE_ADDR + data_off,
+        bss: (BASE_ADDR + data_off + obj.data.len() as u64).next_multiple_of(16),
+    };
+
+    let mut text = obj.text.clone();
+    let mut data = obj.data.clone();
+    for reloc in &obj.relocs {
+        let symbol = obj.symbol(&reloc.symbol).ok_or_else(|| format!("undefined symbol `{}`", reloc.symbol))?;
+        let target = (layout.base(symbol.section) + symbol.offset as u64) as i64 + reloc.addend;
+        let place = (layout.base(reloc.section) + reloc.offset as u64) as i64;
+        let bytes = match reloc.section {
+            Section::Text => &mut text,
+            Section::Data => &mut data,
+            Section::Bss => return Err("relocation in .bss".to_string()),
+        };
+        let field = &mut bytes[reloc.offset..];
+        match reloc.kind {
+            RelocKind::Abs64 => field[..8].copy_from_slice(&target.to_le_bytes()),
+            RelocKind::Abs32S | RelocKind::Pc32 => {
+                let value = if reloc.kind == RelocKind::Pc32 { target - place } else { target };
+                let value = i32::try_from(value).map_err(|_| format!("address of `{}` out of range", reloc.symbol))?;
+                field[..4].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+
+    let entry = obj.symbol(entry).ok_or_else(|| format!("entry point `{}` is not defined", entry))?;
+    let entry = layout.base(entry.section) + entry.offset as u64;
+
+    let mut shstrtab = StrTab::new();
+    let mut strtab = StrTab::new();
+    let (symbols, first_global, _) = symtab(obj, &mut strtab, |section, offset| layout.base(section) + offset as u64);
+
+    let symtab_off = (data_off + data.len() as u64).next_multiple_of(8);
+    let strtab_off = symtab_off + symbols.len() as u64;
+    let shstrtab_off = strtab_off + strtab.bytes.len() as u64;
+    let headers = [
+        SectionHeader { name: shstrtab.add(".text"), kind: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR, addr: layout.text, offset: text_off, size: text.len() as u64, link: 0, info: 0, align: 16, entsize: 0 },
+        SectionHeader { name: shstrtab.add(".data"), kind: SHT_PROGBITS, flags: SHF_ALLOC | SHF_WRITE, addr: layout.data, offset: data_off, size: data.len() as u64, link: 0, info: 0, align: 8, entsize: 0 },
+        SectionHeader { name: shstrtab.add(".bss"), kind: SHT_NOBITS, flags: SHF_ALLOC | SHF_WRITE, addr: layout.bss, offset: data_off + (layout.bss - layout.data), size: obj.bss_size as u64, link: 0, info: 0, align: 16, entsize: 0 },
+        SectionHeader { name: shstrtab.add(".symtab"), kind: SHT_SYMTAB, flags: 0, addr: 0, offset: symtab_off, size: symbols.len() as u64, link: 5, info: first_global, align: 8, entsize: 24 },
+        SectionHeader { name: shstrtab.add(".strtab"), kind: SHT_STRTAB, flags: 0, addr: 0, offset: strtab_off, size: strtab.bytes.len() as u64, link: 0, info: 0, align: 1, entsize: 0 },
+        SectionHeader { name: shstrtab.add(".shstrtab"), kind: SHT_STRTAB, flags: 0, addr: 0, offset: shstrtab_off, size: 0, link: 0, info: 0, align: 1, entsize: 0 },
+    ];
+    let shoff = (shstrtab_off + shstrtab.bytes.len() as u64).next_multiple_of(8);
+
+    let mut out = Vec::new();
+    write_ehdr(&mut out, 2, entry, 2, shoff, headers.len() as u16 + 1, headers.len() as u16); // ET_EXEC
+    write_phdr(&mut out, 5, 0, BASE_ADDR, text_off + text.len() as u64, text_off + text.len() as u64); // R+X
+    let data_memsz = layout.bss + obj.bss_size as u64 - layout.data;
+    write_phdr(&mut out, 6, data_off, layout.data, data.len() as u64, data_memsz); // R+W
+    out.extend_from_slice(&text);
+    pad_to(&mut out, data_off);
+    out.extend_from_slice(&data);
+    pad_to(&mut out, symtab_off);
+    out.extend_from_slice(&symbols);
+    out.extend_from_slice(&strtab.bytes);
+    out.extend_from_slice(&shstrtab.bytes);
+    pad_to(&mut out, shoff);
+
+    let mut headers = headers;
+    headers[5].size = shstrtab.bytes.len() as u64;
+    write_shdrs(&mut out, &headers);
+    Ok(out)
+}
+
+// ============================================================================
+// Relocatable object
+// ============================================================================
+
+/// ET_REL object for `ld`/`cc`: sections at address 0, every remaining
+/// relocation written to .rela.text / .rela.data against the symbol table
+pub fn write_relocatable(obj: &Object) -> Result<Vec<u8>, String> {
+    let mut shstrtab = StrTab::new();
+    let mut strtab = StrTab::new();
+    let (symbols, first_global, indices) = symtab(obj, &mut strtab, |_, offset| offset as u64);
+
+    let mut rela_text = Vec::new();
+    let mut rela_data = Vec::new();
+    for reloc in &obj.relocs {
+        let symbol = indices.get(&reloc.symbol).ok_or_else(|| format!("undefined symbol `{}`", reloc.symbol))?;
+        let kind = match reloc.kind {
+            RelocKind::Abs64 => R_X86_64_64,
+            RelocKind::Abs32S => R_X86_64_32S,
+            // Calls to imported functions may go through the PLT
+            RelocKind::Pc32 if obj.externs.contains(&reloc.symbol) => R_X86_64_PLT32,
+            RelocKind::Pc32 => R_X86_64_PC32,
+        };
+        let out = match reloc.section {
+            Section::Text => &mut rela_text,
+            Section::Data => &mut rela_data,
+            Section::Bss => return Err("relocation in .bss".to_string()),
+        };
+        put64(out, reloc.offset as u64);
+        put64(out, ((*symbol as u64) << 32) | kind);
+        put64(out, reloc.addend as u64);
+    }
+
+    let text_off = EHDR_SIZE;
+    let data_off = (text_off + obj.text.len() as u64).next_multiple_of(8);
+    let rela_text_off = (data_off + obj.data.len() as u64).next_multiple_of(8);
+    let rela_data_off = rela_text_off + rela_text.len() as u64;
+    let symtab_off = rela_data_off + rela_data.len() as u64;
+    let strtab_off = symtab_off + symbols.len() as u64;
+    let shstrtab_off = strtab_off + strtab.bytes.len() as u64;
+
+    // Indices: 1 .text, 2 .data, 3 .bss (see SHN_*), 4-5 .rela.*, 6 .symtab,
+    // 7 .strtab, 8 .note.GNU-stack (non-executable stack), 9 .shstrtab
+    let mut headers = vec![
+        SectionHeader { name: shstrtab.add(".text"), kind: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR, addr: 0, offset: text_off, size: obj.text.len() as u64, link: 0, info: 0, align: 16, entsize: 0 },
+        SectionHeader { name: shstrtab.add(".data"), kind: SHT_PROGBITS, flags: SHF_ALLOC | SHF_WRITE, addr: 0, offset: data_off, size: obj.data.len() as u64, link: 0, info: 0, align: 8, entsize: 0 },
+        SectionHeader { name: shstrtab.add(".bss"), kind: SHT_NOBITS, flags: SHF_ALLOC | SHF_WRITE, addr: 0, offset: rela_text_off, size: obj.bss_size as u64, link: 0, info: 0, align: 16, entsize: 0 },
+        SectionHeader { name: shstrtab.add(".rela.text"), kind: SHT_RELA, flags: SHF_INFO_LINK, addr: 0, offset: rela_text_off, size: rela_text.len() as u64, link: 6, info: SHN_TEXT as u32, align: 8, entsize: 24 },
+        SectionHeader { name: shstrtab.add(".rela.data"), kind: SHT_RELA, flags: SHF_INFO_LINK, addr: 0, offset: rela_data_off, size: rela_data.len() as u64, link: 6, info: SHN_DATA as u32, align: 8, entsize: 24 },
+        SectionHeader { name: shstrtab.add(".symtab"), kind: SHT_SYMTAB, flags: 0, addr: 0, offset: symtab_off, size: symbols.len() as u64, link: 7, info: first_global, align: 8, entsize: 24 },
+        SectionHeader { name: shstrtab.add(".strtab"), kind: SHT_STRTAB, flags: 0, addr: 0, offset: strtab_off, size: strtab.bytes.len() as u64, link: 0, info: 0, align: 1, entsize: 0 },
+        SectionHeader { name: shstrtab.add(".note.GNU-stack"), kind: SHT_PROGBITS, flags: 0, addr: 0, offset: shstrtab_off, size: 0, link: 0, info: 0, align: 1, entsize: 0 },
+    ];
+    headers.push(SectionHeader { name: shstrtab.add(".shstrtab"), kind: SHT_STRTAB, flags: 0, addr: 0, offset: shstrtab_off, size: 0, link: 0, info: 0, align: 1, entsize: 0 });
+    let last = headers.len() - 1;
+    headers[last].size = shstrtab.bytes.len() as u64;
+    let shoff = (shstrtab_off + shstrtab.bytes.len() as u64).next_multiple_of(8);
+
+    let mut out = Vec::new();
+    write_ehdr(&mut out, 1, 0, 0, shoff, headers.len() as u16 + 1, headers.len() as u16); // ET_REL
+    out.extend_from_slice(&obj.text);
+    pad_to(&mut out, data_off);
+    out.extend_from_slice(&obj.data);
+    pad_to(&mut out, rela_text_off);
+    out.extend_from_slice(&rela_text);
+    out.extend_from_slice(&rela_data);
+    out.extend_from_slice(&symbols);
+    out.extend_from_slice(&strtab.bytes);
+    out.extend_from_slice(&shstrtab.bytes);
+    pad_to(&mut out, shoff);
+    write_shdrs(&mut out, &headers);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::assemble;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// (type, offset, size, info) of every section header after the null one
+    fn sections(elf: &[u8]) -> Vec<(u32, u64, u64, u32)> {
+        let shoff = u64_at(elf, 0x28) as usize;
+        let shnum = u16::from_le_bytes([elf[0x3c], elf[0x3d]]) as usize;
+        (1..shnum)
+            .map(|i| shoff + i * SHDR_SIZE as usize)
+            .map(|h| (u32_at(elf, h + 4), u64_at(elf, h + 24), u64_at(elf, h + 32), u32_at(elf, h + 44)))
+            .collect()
+    }
+
+    #[test]
+    fn relocatable_plt32_for_externs() {
+        let obj = assemble("extern puts\nsection .text\nf:\n    call puts\n    lea rdi, [rel msg]\n    ret\nsection .data\nmsg: db \"hi\", 0").unwrap();
+        let elf = write_relocatable(&obj).unwrap();
+        let (_, offset, size, _) = sections(&elf).into_iter()
+            .find(|&(kind, _, _, info)| kind == SHT_RELA && info == SHN_TEXT as u32)
+            .unwrap();
+        let relas: Vec<_> = (offset..offset + size).step_by(24)
+            .map(|r| r as usize)
+            .map(|r| (u64_at(&elf, r), u64_at(&elf, r + 8) & 0xffff_ffff, u64_at(&elf, r + 16) as i64))
+            .collect();
+        assert_eq!(relas, [(1, R_X86_64_PLT32, -4), (8, R_X86_64_PC32, -4)]);
+    }
+
+    #[test]
+    fn executable_applies_pc32() {
+        let obj = assemble("section .text\n_start:\n    lea rsi, [rel msg + 1]\n    ret\nsection .data\nmsg: db \"hi\", 0").unwrap();
+        let elf = write_executable(&obj, "_start").unwrap();
+        let text = EHDR_SIZE + 2 * PHDR_SIZE;
+        let data = (text + obj.text.len() as u64).next_multiple_of(PAGE);
+        assert_eq!(u64_at(&elf, 0x18), BASE_ADDR + text);
+        // Relative to the end of the 7-byte lea
+        let disp = u32_at(&elf, text as usize + 3) as i32 as u64;
+        assert_eq!(BASE_ADDR + text + 7 + disp, BASE_ADDR + data + 1);
+        assert!(write_executable(&assemble("extern puts\nsection .text\n_start: call puts").unwrap(), "_start").is_err());
+    }
+}
@@ -1,6 +1,4 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 // ============================================================================
 // Stack-Based Assembly Generator for x86_64
@@ -14,6 +12,45 @@ pub struct StackAsmBuilder {
     pub str_count: usize,
     pub stack_reg: String,       // r15 pour pile principale
     pub proc_stack_reg: String,  // r14 pour pile proc
+    pub globals: Vec<String>,
+    pub exported: Vec<String>,   // procs called from C through a shim
+    pub executable: bool,        // false: object file without `_start`
+    pub externs: HashMap<String, (Vec<EType>, Vec<EType>)>,  // C functions: (params, rets)
+    pub optimize: bool,          // -O1: fused instruction pairs and peephole pass
+    pub cache_tos: bool,         // -O2: top of the stack kept in registers
+    pub cached: usize,           // cells currently held in TOS_REGS
+    pub check_stack: bool,       // --check-stack: bounds checks on r14 and r15
+    pub data_stack_cells: usize, // size of `data_stack`
+    pub proc_stack_cells: usize, // size of `data_stack_proc`
+    pub uses_exit: bool,         // the `exit` builtin is compiled somewhere
+}
+
+/// Registers caching the top of the data stack at -O2: the top cell, then the
+/// one below it. Callee-saved and untouched by the helpers and by `syscall`.
+pub const TOS_REGS: [&str; 2] = ["r12", "r13"];
+
+/// Default sizes of the data stack and of the proc stack, in 8-byte cells
+pub const DATA_STACK_CELLS: usize = 4096;
+pub const PROC_STACK_CELLS: usize = 8192;
+
+/// Largest `.bss` (stacks and memories): the code reaches all of it with
+/// RIP-relative 32-bit displacements, which leaves room for the text too
+pub const BSS_SIZE_MAX: usize = 1 << 30;
+
+/// Exit status of a compiled program stopped by a failed stack check
+pub const STACK_ERROR_EXIT: i32 = 3;
+
+/// Failures of the stack checks: local label in every proc and message
+const STACK_ERRORS: [(&str, &str); 3] = [
+    ("data_overflow", "data stack overflow"),
+    ("data_underflow", "data stack underflow"),
+    ("proc_overflow", "proc stack overflow"),
+];
+
+impl Default for StackAsmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl StackAsmBuilder {
@@ -21,8 +58,13 @@ impl StackAsmBuilder {
         Self {
             bss: vec![
                 "    dump_buf: resb 21".to_string(),
-                "    data_stack: resq 4096".to_string(),
-                "    data_stack_proc: resq 8192".to_string(),
+                "    alignb 8".to_string(),
+                "    program_argc: resq 1".to_string(),
+                "    program_argv: resq 1".to_string(),
+                "    program_envp: resq 1".to_string(),
+                "    program_envc: resq 1".to_string(),
+                "    extern_data_top: resq 1".to_string(),
+                "    extern_proc_top: resq 1".to_string(),
             ],
             data: Vec::new(),
             funcs: Vec::new(),
@@ -30,6 +72,17 @@ impl StackAsmBuilder {
             str_count: 0,
             stack_reg: "r15".to_string(),
             proc_stack_reg: "r14".to_string(),
+            globals: Vec::new(),
+            exported: Vec::new(),
+            executable: true,
+            externs: HashMap::new(),
+            optimize: false,
+            cache_tos: false,
+            cached: 0,
+            check_stack: false,
+            data_stack_cells: DATA_STACK_CELLS,
+            proc_stack_cells: PROC_STACK_CELLS,
+            uses_exit: false,
         }
     }
 
@@ -44,7 +97,7 @@ impl StackAsmBuilder {
 
         let mut escaped = String::new();
         for byte in s.bytes() {
-            if byte >= 32 && byte <= 126 && byte != b'"' && byte != b'\\' {
+            if (32..=126).contains(&byte) && byte != b'"' && byte != b'\\' {
                 escaped.push(byte as char);
             } else {
                 escaped.push_str(&format!("\", {}, \"", byte));
@@ -55,6 +108,30 @@ impl StackAsmBuilder {
         (label, len)
     }
 
+    // ========================================================================
+    // Symbols
+    // ========================================================================
+
+    /// Names may contain characters NASM rejects in labels (e.g. `sizeof(u64)`):
+    /// anything other than [A-Za-z0-9_] is written as `_xx` (hex code).
+    pub fn asm_name(name: &str) -> String {
+        let mut out = String::new();
+        for c in name.chars() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                out.push(c);
+            } else {
+                out.push_str(&format!("_{:02x}", c as u32));
+            }
+        }
+        out
+    }
+
+    /// Reserves a zero-initialised `.bss` region for `memory NAME SIZE end`
+    pub fn add_memory(&mut self, name: &str, size: usize) {
+        self.bss.push("    alignb 8".to_string());
+        self.bss.push(format!("    mem_{}: resb {}", Self::asm_name(name), size));
+    }
+
     // ========================================================================
     // Emission helpers
     // ========================================================================
@@ -90,14 +167,32 @@ impl StackAsmBuilder {
     pub fn emit_push_i64(&mut self, value: i64, in_proc: bool) {
         let pile = &self.stack_reg.clone();
         self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
-        self.emit_instr(in_proc, "mov", &format!("qword [{}], {}", pile, value));
+        if i32::try_from(value).is_ok() {
+            self.emit_instr(in_proc, "mov", &format!("qword [{}], {}", pile, value));
+        } else {
+            // A store takes at most a sign-extended 32-bit immediate
+            self.emit_instr(in_proc, "mov", &format!("rax, {}", value));
+            self.emit_instr(in_proc, "mov", &format!("qword [{}], rax", pile));
+        }
     }
 
+    /// Strings live on the stack as two cells: the address (below) and the
+    /// byte length (on top), the same layout as the interpreter.
+    /// Addresses are RIP-relative so the code also links into PIE programs.
     pub fn emit_push_str(&mut self, s: &str, in_proc: bool) {
-        let (label, _) = self.add_string(s);
+        let (label, len) = self.add_string(s);
         let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "lea", &format!("rax, [rel {}]", label));
+        self.emit_instr(in_proc, "sub", &format!("{}, 16", pile));
+        self.emit_instr(in_proc, "mov", &format!("qword [{} + 8], rax", pile));
+        self.emit_instr(in_proc, "mov", &format!("qword [{}], {}", pile, len));
+    }
+
+    pub fn emit_push_mem(&mut self, name: &str, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "lea", &format!("rax, [rel mem_{}]", Self::asm_name(name)));
         self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
-        self.emit_instr(in_proc, "mov", &format!("qword [{}], {}", pile, label));
+        self.emit_instr(in_proc, "mov", &format!("qword [{}], rax", pile));
     }
 
     pub fn emit_add_i64(&mut self, in_proc: bool) {
@@ -142,6 +237,24 @@ impl StackAsmBuilder {
         self.emit_instr(in_proc, "mov", &format!("[{}], rdx", pile)); // rdx = remainder
     }
 
+    pub fn emit_udiv_u64(&mut self, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "mov", &format!("rax, [{} + 8]", pile));
+        self.emit_instr(in_proc, "xor", "rdx, rdx");
+        self.emit_instr(in_proc, "div", &format!("qword [{}]", pile));
+        self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
+        self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
+    }
+
+    pub fn emit_umod_u64(&mut self, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "mov", &format!("rax, [{} + 8]", pile));
+        self.emit_instr(in_proc, "xor", "rdx, rdx");
+        self.emit_instr(in_proc, "div", &format!("qword [{}]", pile));
+        self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
+        self.emit_instr(in_proc, "mov", &format!("[{}], rdx", pile));
+    }
+
     pub fn emit_dup(&mut self, in_proc: bool) {
         let pile = &self.stack_reg.clone();
         self.emit_instr(in_proc, "mov", &format!("rax, [{}]", pile));
@@ -179,6 +292,327 @@ impl StackAsmBuilder {
         self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));      // c -> a pos
     }
 
+    // ========================================================================
+    // Fused Instruction Pairs (-O1)
+    // ========================================================================
+
+    /// Emits two consecutive IR instructions as one shorter sequence when
+    /// there is one: `5 +` is `add qword [r15], 5`, `dup +` a shift and
+    /// `swap swap` nothing. Returns false (emitting nothing) otherwise.
+    pub fn emit_fused(&mut self, first: &EIrInstr, second: &EIrInstr, in_proc: bool) -> bool {
+        let pile = &self.stack_reg.clone();
+        match (first, second) {
+            (EIrInstr::Swap, EIrInstr::Swap) | (EIrInstr::PushI64(_), EIrInstr::Drop) => {}
+            (EIrInstr::Dup, EIrInstr::AddI64) => self.emit_instr(in_proc, "shl", &format!("qword [{}], 1", pile)),
+            // Instructions take at most a sign-extended 32-bit immediate
+            (EIrInstr::PushI64(value), op) if i32::try_from(*value).is_ok() => {
+                let alu = match op {
+                    EIrInstr::AddI64 => "add",
+                    EIrInstr::SubI64 => "sub",
+                    EIrInstr::AndI64 => "and",
+                    EIrInstr::OrI64 => "or",
+                    EIrInstr::XorI64 => "xor",
+                    EIrInstr::ShlI64 if (0..64).contains(value) => "shl",
+                    EIrInstr::ShrU64 if (0..64).contains(value) => "shr",
+                    EIrInstr::SarI64 if (0..64).contains(value) => "sar",
+                    EIrInstr::MulI64 => {
+                        self.emit_instr(in_proc, "imul", &format!("rax, [{}], {}", pile, value));
+                        self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
+                        return true;
+                    }
+                    _ => {
+                        let Some(setcc) = Self::condition_set(op) else { return false };
+                        self.emit_instr(in_proc, "cmp", &format!("qword [{}], {}", pile, value));
+                        self.emit_instr(in_proc, setcc, "al");
+                        self.emit_instr(in_proc, "movzx", "rax, al");
+                        self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
+                        return true;
+                    }
+                };
+                self.emit_instr(in_proc, alu, &format!("qword [{}], {}", pile, value));
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// setcc giving the result of a comparison `a b op` after `cmp a, b`
+    fn condition_set(op: &EIrInstr) -> Option<&'static str> {
+        Some(match op {
+            EIrInstr::EqI64 => "sete",
+            EIrInstr::NeqI64 => "setne",
+            EIrInstr::LtI64 => "setl",
+            EIrInstr::GtI64 => "setg",
+            EIrInstr::LeI64 => "setle",
+            EIrInstr::GeI64 => "setge",
+            EIrInstr::LtU64 => "setb",
+            EIrInstr::GtU64 => "seta",
+            EIrInstr::LeU64 => "setbe",
+            EIrInstr::GeU64 => "setae",
+            _ => return None,
+        })
+    }
+
+    // ========================================================================
+    // Top-of-Stack Caching (-O2)
+    // ========================================================================
+    //
+    // The top `cached` cells (at most two) live in TOS_REGS instead of memory,
+    // and r15 points at the first cell that is not cached. Every label, jump,
+    // call, `ret` and instruction without a cached form sees the plain layout:
+    // `spill` writes the registers back before them.
+
+    /// Writes the cached cells back to the data stack
+    pub fn spill(&mut self, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        match self.cached {
+            0 => return,
+            1 => {
+                self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
+                self.emit_instr(in_proc, "mov", &format!("[{}], {}", pile, TOS_REGS[0]));
+            }
+            _ => {
+                self.emit_instr(in_proc, "sub", &format!("{}, 16", pile));
+                self.emit_instr(in_proc, "mov", &format!("[{} + 8], {}", pile, TOS_REGS[1]));
+                self.emit_instr(in_proc, "mov", &format!("[{}], {}", pile, TOS_REGS[0]));
+            }
+        }
+        self.cached = 0;
+    }
+
+    /// Loads cells from the data stack until the top `count` are cached
+    fn cache(&mut self, count: usize, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        match (self.cached, count) {
+            (0, 1) => {
+                self.emit_instr(in_proc, "mov", &format!("{}, [{}]", TOS_REGS[0], pile));
+                self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
+            }
+            (0, 2) => {
+                self.emit_instr(in_proc, "mov", &format!("{}, [{}]", TOS_REGS[0], pile));
+                self.emit_instr(in_proc, "mov", &format!("{}, [{} + 8]", TOS_REGS[1], pile));
+                self.emit_instr(in_proc, "add", &format!("{}, 16", pile));
+            }
+            (1, 2) => {
+                self.emit_instr(in_proc, "mov", &format!("{}, [{}]", TOS_REGS[1], pile));
+                self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
+            }
+            _ => return,
+        }
+        self.cached = count;
+    }
+
+    /// Pushes `value`: an immediate, a scratch register or the cached top
+    fn push_cached(&mut self, value: &str, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        if self.cached == 2 {
+            self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
+            self.emit_instr(in_proc, "mov", &format!("[{}], {}", pile, TOS_REGS[1]));
+            self.cached = 1;
+        }
+        if self.cached == 1 {
+            self.emit_instr(in_proc, "mov", &format!("{}, {}", TOS_REGS[1], TOS_REGS[0]));
+        }
+        if value != TOS_REGS[0] {
+            self.emit_instr(in_proc, "mov", &format!("{}, {}", TOS_REGS[0], value));
+        }
+        self.cached += 1;
+    }
+
+    /// Removes the top cell, which the caller has consumed
+    fn pop_cached(&mut self, in_proc: bool) {
+        if self.cached == 2 {
+            self.emit_instr(in_proc, "mov", &format!("{}, {}", TOS_REGS[0], TOS_REGS[1]));
+        }
+        self.cached -= 1;
+    }
+
+    /// Emits `instr` on the cached stack, together with `next` when the pair
+    /// has an immediate form. Returns the number of instructions consumed
+    /// after `instr`, or None (emitting nothing) when `instr` needs the
+    /// plain layout: the caller spills and uses the general emitter.
+    pub fn emit_cached(&mut self, instr: &EIrInstr, next: Option<&EIrInstr>, in_proc: bool) -> Option<usize> {
+        let (top, second) = (TOS_REGS[0], TOS_REGS[1]);
+        match (instr, next) {
+            (EIrInstr::Swap, Some(EIrInstr::Swap)) | (EIrInstr::PushI64(_), Some(EIrInstr::Drop)) => return Some(1),
+            (EIrInstr::Dup, Some(EIrInstr::AddI64)) => {
+                self.cache(1, in_proc);
+                self.emit_instr(in_proc, "shl", &format!("{}, 1", top));
+                return Some(1);
+            }
+            _ => {}
+        }
+        match instr {
+            EIrInstr::PushI64(value) if i32::try_from(*value).is_ok() => {
+                if let Some(op) = next
+                    && let Some(alu) = Self::alu_imm(op, *value) {
+                    self.cache(1, in_proc);
+                    match alu {
+                        "imul" => self.emit_instr(in_proc, "imul", &format!("{}, {}, {}", top, top, value)),
+                        "cmp" => {
+                            let setcc = Self::condition_set(op)?;
+                            self.emit_instr(in_proc, "cmp", &format!("{}, {}", top, value));
+                            self.emit_instr(in_proc, setcc, "al");
+                            self.emit_instr(in_proc, "movzx", &format!("{}, al", top));
+                        }
+                        _ => self.emit_instr(in_proc, alu, &format!("{}, {}", top, value)),
+                    }
+                    return Some(1);
+                }
+                self.push_cached(&value.to_string(), in_proc);
+            }
+            EIrInstr::PushI64(value) => {
+                self.emit_instr(in_proc, "mov", &format!("rax, {}", value));
+                self.push_cached("rax", in_proc);
+            }
+            EIrInstr::PushStr(s) => {
+                let (label, len) = self.add_string(s);
+                self.emit_instr(in_proc, "lea", &format!("rax, [rel {}]", label));
+                self.push_cached("rax", in_proc);
+                self.push_cached(&len.to_string(), in_proc);
+            }
+            EIrInstr::PushMem(name) => {
+                self.emit_instr(in_proc, "lea", &format!("rax, [rel mem_{}]", Self::asm_name(name)));
+                self.push_cached("rax", in_proc);
+            }
+            // Binary operations leave their result in `top`, with one cell cached
+            EIrInstr::AddI64 | EIrInstr::MulI64 | EIrInstr::AndI64 | EIrInstr::OrI64 | EIrInstr::XorI64 => {
+                let op = match instr {
+                    EIrInstr::AddI64 => "add",
+                    EIrInstr::MulI64 => "imul",
+                    EIrInstr::AndI64 => "and",
+                    EIrInstr::OrI64 => "or",
+                    _ => "xor",
+                };
+                self.cache(2, in_proc);
+                self.emit_instr(in_proc, op, &format!("{}, {}", top, second));
+                self.cached = 1;
+            }
+            EIrInstr::SubI64 => {
+                self.cache(2, in_proc);
+                self.emit_instr(in_proc, "sub", &format!("{}, {}", second, top));
+                self.emit_instr(in_proc, "mov", &format!("{}, {}", top, second));
+                self.cached = 1;
+            }
+            EIrInstr::DivI64 | EIrInstr::ModI64 | EIrInstr::DivU64 | EIrInstr::ModU64 => {
+                self.cache(2, in_proc);
+                self.emit_instr(in_proc, "mov", &format!("rax, {}", second));
+                if matches!(instr, EIrInstr::DivI64 | EIrInstr::ModI64) {
+                    self.emit_instr(in_proc, "cqo", "");
+                    self.emit_instr(in_proc, "idiv", top);
+                } else {
+                    self.emit_instr(in_proc, "xor", "rdx, rdx");
+                    self.emit_instr(in_proc, "div", top);
+                }
+                let result = if matches!(instr, EIrInstr::DivI64 | EIrInstr::DivU64) { "rax" } else { "rdx" };
+                self.emit_instr(in_proc, "mov", &format!("{}, {}", top, result));
+                self.cached = 1;
+            }
+            EIrInstr::ShlI64 | EIrInstr::ShrU64 | EIrInstr::SarI64 => {
+                let op = match instr {
+                    EIrInstr::ShlI64 => "shl",
+                    EIrInstr::ShrU64 => "shr",
+                    _ => "sar",
+                };
+                self.cache(2, in_proc);
+                self.emit_instr(in_proc, "mov", &format!("rcx, {}", top));
+                self.emit_instr(in_proc, op, &format!("{}, cl", second));
+                self.emit_instr(in_proc, "mov", &format!("{}, {}", top, second));
+                self.cached = 1;
+            }
+            EIrInstr::NotI64 => {
+                self.cache(1, in_proc);
+                self.emit_instr(in_proc, "not", top);
+            }
+            op if Self::condition_set(op).is_some() => {
+                self.cache(2, in_proc);
+                self.emit_instr(in_proc, "cmp", &format!("{}, {}", second, top));
+                self.emit_instr(in_proc, Self::condition_set(op)?, "al");
+                self.emit_instr(in_proc, "movzx", &format!("{}, al", top));
+                self.cached = 1;
+            }
+            EIrInstr::Dup => {
+                self.cache(1, in_proc);
+                self.push_cached(top, in_proc);
+            }
+            EIrInstr::Over => {
+                self.cache(2, in_proc);
+                self.emit_instr(in_proc, "mov", &format!("rax, {}", second));
+                self.push_cached("rax", in_proc);
+            }
+            EIrInstr::Swap => {
+                self.cache(2, in_proc);
+                self.emit_instr(in_proc, "mov", &format!("rax, {}", top));
+                self.emit_instr(in_proc, "mov", &format!("{}, {}", top, second));
+                self.emit_instr(in_proc, "mov", &format!("{}, rax", second));
+            }
+            EIrInstr::Drop if self.cached > 0 => self.pop_cached(in_proc),
+            EIrInstr::Load8 | EIrInstr::Load16 | EIrInstr::Load32 | EIrInstr::Load64 => {
+                self.cache(1, in_proc);
+                match instr {
+                    EIrInstr::Load8 => self.emit_instr(in_proc, "movzx", &format!("{}, byte [{}]", top, top)),
+                    EIrInstr::Load16 => self.emit_instr(in_proc, "movzx", &format!("{}, word [{}]", top, top)),
+                    EIrInstr::Load32 => self.emit_instr(in_proc, "mov", &format!("{}d, dword [{}]", top, top)),
+                    _ => self.emit_instr(in_proc, "mov", &format!("{}, qword [{}]", top, top)),
+                }
+            }
+            EIrInstr::Store8 | EIrInstr::Store16 | EIrInstr::Store32 | EIrInstr::Store64 => {
+                self.cache(2, in_proc);
+                let value = match instr {
+                    EIrInstr::Store8 => format!("{}b", top),
+                    EIrInstr::Store16 => format!("{}w", top),
+                    EIrInstr::Store32 => format!("{}d", top),
+                    _ => top.to_string(),
+                };
+                self.emit_instr(in_proc, "mov", &format!("[{}], {}", second, value));
+                self.cached = 0;
+            }
+            EIrInstr::Dump => {
+                self.cache(1, in_proc);
+                self.emit_instr(in_proc, "mov", &format!("rdi, {}", top));
+                self.pop_cached(in_proc);
+                self.emit_instr(in_proc, "call", "dump_i");
+            }
+            EIrInstr::Puts => {
+                self.cache(2, in_proc);
+                self.emit_instr(in_proc, "mov", &format!("rdi, {}", second));
+                self.emit_instr(in_proc, "mov", &format!("rsi, {}", top));
+                self.cached = 0;
+                self.emit_instr(in_proc, "call", "write_str");
+            }
+            EIrInstr::JumpIfZero(id) => {
+                // The rest of the cache is written back before the test: the
+                // `sub r15` of the spill would change the flags
+                self.cache(1, in_proc);
+                self.emit_instr(in_proc, "mov", &format!("rax, {}", top));
+                self.pop_cached(in_proc);
+                self.spill(in_proc);
+                self.emit_instr(in_proc, "test", "rax, rax");
+                self.emit_instr(in_proc, "jz", &format!(".lbl_{}", id));
+            }
+            _ => return None,
+        }
+        Some(0)
+    }
+
+    /// Instruction applying `op` with the immediate `value` to the top of
+    /// the stack, when there is one
+    fn alu_imm(op: &EIrInstr, value: i64) -> Option<&'static str> {
+        Some(match op {
+            EIrInstr::AddI64 => "add",
+            EIrInstr::SubI64 => "sub",
+            EIrInstr::MulI64 => "imul",
+            EIrInstr::AndI64 => "and",
+            EIrInstr::OrI64 => "or",
+            EIrInstr::XorI64 => "xor",
+            EIrInstr::ShlI64 if (0..64).contains(&value) => "shl",
+            EIrInstr::ShrU64 if (0..64).contains(&value) => "shr",
+            EIrInstr::SarI64 if (0..64).contains(&value) => "sar",
+            op if Self::condition_set(op).is_some() => "cmp",
+            _ => return None,
+        })
+    }
+
     // ========================================================================
     // I/O Operations
     // ========================================================================
@@ -192,41 +626,292 @@ impl StackAsmBuilder {
 
     pub fn emit_puts(&mut self, in_proc: bool) {
         let pile = &self.stack_reg.clone();
-        self.emit_instr(in_proc, "mov", &format!("rdi, [{}]", pile));
+        self.emit_instr(in_proc, "mov", &format!("rdi, [{} + 8]", pile)); // ptr
+        self.emit_instr(in_proc, "mov", &format!("rsi, [{}]", pile));     // len
+        self.emit_instr(in_proc, "add", &format!("{}, 16", pile));
+        self.emit_instr(in_proc, "call", "write_str");
+    }
+
+    // ========================================================================
+    // Control Flow
+    // ========================================================================
+
+    fn emit_line(&mut self, in_proc: bool, line: &str) {
+        if in_proc {
+            self.emit_func_line(line);
+        } else {
+            self.emit_text_line(line);
+        }
+    }
+
+    // Labels are local to the enclosing proc_X label in NASM
+    pub fn emit_label(&mut self, id: usize, in_proc: bool) {
+        self.emit_line(in_proc, &format!(".lbl_{}:", id));
+    }
+
+    pub fn emit_jump(&mut self, id: usize, in_proc: bool) {
+        self.emit_instr(in_proc, "jmp", &format!(".lbl_{}", id));
+    }
+
+    pub fn emit_jump_if_zero(&mut self, id: usize, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "mov", &format!("rax, [{}]", pile));
         self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
-        self.emit_instr(in_proc, "call", "dump_str");
+        self.emit_instr(in_proc, "test", "rax, rax");
+        self.emit_instr(in_proc, "jz", &format!(".lbl_{}", id));
     }
 
     // ========================================================================
     // Function / Procedure Calls
     // ========================================================================
 
+    /// Label of a proc's body. An exported proc gives `proc_NAME` to its C
+    /// shim and is entered at `proc_NAME.stack` from eboth code (`.` never
+    /// appears in `asm_name` output, so this cannot clash with another proc).
+    pub fn proc_label(&self, name: &str) -> String {
+        if self.exported.iter().any(|e| e == name) {
+            format!("proc_{}.stack", Self::asm_name(name))
+        } else {
+            format!("proc_{}", Self::asm_name(name))
+        }
+    }
+
     pub fn emit_call(&mut self, name: &str, in_proc: bool) {
-        self.emit_instr(in_proc, "call", &format!("proc_{}", name));
+        let label = self.proc_label(name);
+        self.emit_instr(in_proc, "call", &label);
+    }
+
+    /// Returns to the address saved on the proc stack by `emit_proc_start`.
+    /// The data stack is left as the body made it: the checker guarantees
+    /// that a proc replaces its parameter cells by exactly its result cells.
+    pub fn emit_ret(&mut self, in_proc: bool) {
+        let procs = &self.proc_stack_reg.clone();
+        self.emit_instr(in_proc, "push", &format!("qword [{}]", procs));
+        self.emit_instr(in_proc, "add", &format!("{}, 8", procs));
+        self.emit_instr(in_proc, "ret", "");
+    }
+
+    /// Moves the return address pushed by `call` from rsp to the proc stack
+    /// (r14), so that rsp stays free for the helpers, syscalls and C calls
+    /// and the depth of eboth calls is bounded by `data_stack_proc`
+    pub fn emit_proc_start(&mut self, name: &str) {
+        let label = self.proc_label(name);
+        let procs = &self.proc_stack_reg.clone();
+        self.emit_func_line(&format!("{}:", label));
+        if self.check_stack {
+            self.emit_entry_checks();
+        }
+        self.emit_func_instr("sub", &format!("{}, 8", procs));
+        self.emit_func_instr("pop", &format!("qword [{}]", procs));
+    }
+
+    /// With --check-stack, the targets of the failed checks of the proc
+    /// `name`: each loads its message and the proc name for `stack_error`
+    pub fn emit_proc_end(&mut self, name: &str) {
+        if !self.check_stack {
+            return;
+        }
+        let (label, len) = self.add_string(&format!("{}\n", name));
+        for (target, message) in STACK_ERRORS {
+            self.emit_func_line(&format!(".{}:", target));
+            self.emit_func_instr("lea", &format!("rdi, [rel msg_{}]", target));
+            self.emit_func_instr("mov", &format!("rsi, {}", Self::stack_message(message).len()));
+            self.emit_func_instr("jmp", ".stack_error");
+        }
+        self.emit_func_line(".stack_error:");
+        self.emit_func_instr("lea", &format!("rdx, [rel {}]", label));
+        self.emit_func_instr("mov", &format!("rcx, {}", len));
+        self.emit_func_instr("jmp", "stack_error");
+    }
+
+    // ========================================================================
+    // Stack Checks (--check-stack)
+    // ========================================================================
+
+    /// At the entry of a proc, before its return address moves to the proc
+    /// stack: r14 must have room for it and r15 must lie within `data_stack`
+    fn emit_entry_checks(&mut self) {
+        let (pile, procs) = (self.stack_reg.clone(), self.proc_stack_reg.clone());
+        self.emit_func_instr("lea", "rax, [rel data_stack_proc + 8]");
+        self.emit_func_instr("cmp", &format!("{}, rax", procs));
+        self.emit_func_instr("jb", ".proc_overflow");
+        self.emit_func_instr("lea", "rax, [rel data_stack]");
+        self.emit_func_instr("cmp", &format!("{}, rax", pile));
+        self.emit_func_instr("jb", ".data_overflow");
+        self.emit_func_instr("lea", &format!("rax, [rel data_stack + {}*8]", self.data_stack_cells));
+        self.emit_func_instr("cmp", &format!("{}, rax", pile));
+        self.emit_func_instr("ja", ".data_underflow");
     }
 
-    pub fn emit_ret(&mut self, in_proc: bool, is_main: bool, _returns_value: bool) {
+    /// Before an instruction pushing `cells` cells: the data stack must have
+    /// room for them and for the cells cached in TOS_REGS
+    pub fn emit_push_check(&mut self, cells: usize, in_proc: bool) {
         let pile = &self.stack_reg.clone();
-        if in_proc && !is_main {
-            // Always try to return the top of stack value
-            // TODO: When type system is complete, use returns_value to differentiate
-            self.emit_instr(in_proc, "mov", &format!("rax, [{}]", pile));
-            self.emit_instr(in_proc, "pop", &pile.clone());
-            self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
-            self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
+        self.emit_instr(in_proc, "lea", &format!("rax, [rel data_stack + {}]", (cells + self.cached) * 8));
+        self.emit_instr(in_proc, "cmp", &format!("{}, rax", pile));
+        self.emit_instr(in_proc, "jb", ".data_overflow");
+    }
+
+    /// Start of what a failed stack check prints, before the proc's name
+    pub fn stack_message(message: &str) -> String {
+        format!("error: {} in proc ", message)
+    }
+
+    /// `stack_error(rdi = message, rsi = its length, rdx = proc name,
+    /// rcx = its length)`: writes both to stderr and exits with
+    /// STACK_ERROR_EXIT. The cached registers are free, nothing runs after.
+    pub fn add_stack_error(&mut self) {
+        for (target, message) in STACK_ERRORS {
+            self.data.push(format!("    msg_{}: db \"{}\", 0", target, Self::stack_message(message)));
         }
-        self.emit_instr(in_proc, "ret", "");
+        self.funcs.push(format!(r#"stack_error:
+    mov     r12, rdx
+    mov     r13, rcx
+    mov     rdx, rsi
+    mov     rsi, rdi
+    mov     rax, 1
+    mov     rdi, 2
+    syscall
+    mov     rdx, r13
+    mov     rsi, r12
+    mov     rax, 1
+    mov     rdi, 2
+    syscall
+    mov     rax, 60
+    mov     rdi, {}
+    syscall"#, STACK_ERROR_EXIT));
     }
 
-    pub fn emit_proc_start(&mut self, name: &str, is_main: bool) {
-        self.emit_func_line(&format!("proc_{}:", name));
-        if !is_main {
-            self.emit_func_instr("push", &self.stack_reg.clone());
+    // ========================================================================
+    // C ABI
+    // ========================================================================
+
+    /// System V entry point `proc_NAME` for an exported proc: saves the
+    /// callee-saved registers the generated code uses, pushes the integer
+    /// argument registers onto the data stack (first parameter deepest),
+    /// calls the body and returns the top of the stack in rax.
+    /// A call from C starts at the top of both stacks, unless eboth code is
+    /// waiting on an extern call: then it starts below r15/r14 as that call
+    /// left them in extern_data_top/extern_proc_top, restored on return.
+    pub fn emit_c_shim(&mut self, name: &str, params: &[EType], rets: &[EType]) {
+        const ARGS: [(&str, &str, &str, &str); 6] = [
+            ("rdi", "edi", "di", "dil"), ("rsi", "esi", "si", "sil"), ("rdx", "edx", "dx", "dl"),
+            ("rcx", "ecx", "cx", "cl"), ("r8", "r8d", "r8w", "r8b"), ("r9", "r9d", "r9w", "r9b"),
+        ];
+        let symbol = format!("proc_{}", Self::asm_name(name));
+        let body = self.proc_label(name);
+        let pile = self.stack_reg.clone();
+        let saved = ["rbx", "r12", "r13", "r14", "r15"];
+
+        self.globals.push(symbol.clone());
+        self.emit_func_line(&format!("{}:", symbol));
+        for reg in saved {
+            self.emit_func_instr("push", reg);
+        }
+        self.emit_func_instr("push", "qword [rel extern_data_top]");
+        self.emit_func_instr("push", "qword [rel extern_proc_top]");
+        if !self.executable {
+            // No `_start` saved the startup arrays: make both empty, ended by
+            // the NULL that program_argc (always 0 here, like program_envc) holds
+            self.emit_func_instr("lea", "rax, [rel program_argc]");
+            self.emit_func_instr("mov", "[rel program_argv], rax");
+            self.emit_func_instr("mov", "[rel program_envp], rax");
+        }
+        // 0 when no eboth code is running
+        for (reg, top, stack, cells) in [
+            (&pile, "extern_data_top", "data_stack", self.data_stack_cells),
+            (&self.proc_stack_reg.clone(), "extern_proc_top", "data_stack_proc", self.proc_stack_cells),
+        ] {
+            self.emit_func_instr("lea", &format!("rax, [rel {} + {}*8]", stack, cells));
+            self.emit_func_instr("mov", &format!("{}, [rel {}]", reg, top));
+            self.emit_func_instr("test", &format!("{}, {}", reg, reg));
+            self.emit_func_instr("cmovz", &format!("{}, rax", reg));
+        }
+
+        // One cell per argument register; narrow C types are extended
+        let cells: Vec<&EType> = params.iter()
+            .flat_map(|ty| if *ty == EType::Str { vec![&EType::Ptr, &EType::I64] } else { vec![ty] })
+            .collect();
+        if !cells.is_empty() {
+            self.emit_func_instr("sub", &format!("{}, {}", pile, cells.len() * 8));
+        }
+        for (i, ty) in cells.iter().enumerate() {
+            let (q, d, w, b) = ARGS[i];
+            let offset = (cells.len() - 1 - i) * 8;
+            let slot = if offset == 0 { format!("[{}]", pile) } else { format!("[{} + {}]", pile, offset) };
+            let extend = match ty {
+                EType::I8 => Some(("movsx", "rax", b)),
+                EType::U8 | EType::Bool => Some(("movzx", "eax", b)),
+                EType::I16 => Some(("movsx", "rax", w)),
+                EType::U16 => Some(("movzx", "eax", w)),
+                EType::I32 => Some(("movsxd", "rax", d)),
+                EType::U32 => Some(("mov", "eax", d)),
+                _ => None,
+            };
+            match extend {
+                Some((op, dst, src)) => {
+                    self.emit_func_instr(op, &format!("{}, {}", dst, src));
+                    self.emit_func_instr("mov", &format!("qword {}, rax", slot));
+                }
+                None => self.emit_func_instr("mov", &format!("qword {}, {}", slot, q)),
+            }
         }
+
+        self.emit_func_instr("call", &body);
+        if !rets.is_empty() {
+            self.emit_func_instr("mov", &format!("rax, [{}]", pile));
+        }
+        self.emit_func_instr("pop", "qword [rel extern_proc_top]");
+        self.emit_func_instr("pop", "qword [rel extern_data_top]");
+        for reg in saved.iter().rev() {
+            self.emit_func_instr("pop", reg);
+        }
+        self.emit_func_instr("ret", "");
     }
 
-    pub fn emit_proc_end(&mut self) {
-        // Nothing special needed, ret handles cleanup
+    /// Call of the C function `name` declared by `extern`: the parameter
+    /// cells are popped into the integer argument registers (last parameter
+    /// on top), rsp is aligned to 16 bytes around the call and rax is pushed,
+    /// extended from the C return type. r15 is callee-saved in System V, so
+    /// the data stack survives.
+    pub fn emit_call_extern(&mut self, name: &str, in_proc: bool) {
+        const ARGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+        let pile = self.stack_reg.clone();
+        let (params, rets) = self.externs[name].clone();
+        let cells: usize = params.iter().map(|ty| if *ty == EType::Str { 2 } else { 1 }).sum();
+
+        for (i, reg) in ARGS.iter().take(cells).enumerate() {
+            let offset = (cells - 1 - i) * 8;
+            let slot = if offset == 0 { format!("[{}]", pile) } else { format!("[{} + {}]", pile, offset) };
+            self.emit_instr(in_proc, "mov", &format!("{}, {}", reg, slot));
+        }
+        if cells > 0 {
+            self.emit_instr(in_proc, "add", &format!("{}, {}", pile, cells * 8));
+        }
+        // Where an exported proc called back from C starts, see emit_c_shim
+        if !self.exported.is_empty() {
+            self.emit_instr(in_proc, "mov", &format!("[rel extern_data_top], {}", pile));
+            self.emit_instr(in_proc, "mov", &format!("[rel extern_proc_top], {}", self.proc_stack_reg));
+        }
+        self.emit_instr(in_proc, "mov", "rbx, rsp");
+        self.emit_instr(in_proc, "and", "rsp, -16");
+        // al: number of vector registers used, for variadic functions
+        self.emit_instr(in_proc, "xor", "eax, eax");
+        self.emit_instr(in_proc, "call", &format!("{} wrt ..plt", name));
+        if let Some(ret) = rets.first() {
+            match ret {
+                EType::I8 => self.emit_instr(in_proc, "movsx", "rax, al"),
+                EType::U8 | EType::Bool => self.emit_instr(in_proc, "movzx", "eax, al"),
+                EType::I16 => self.emit_instr(in_proc, "movsx", "rax, ax"),
+                EType::U16 => self.emit_instr(in_proc, "movzx", "eax, ax"),
+                EType::I32 => self.emit_instr(in_proc, "movsxd", "rax, eax"),
+                EType::U32 => self.emit_instr(in_proc, "mov", "eax, eax"),
+                _ => {}
+            }
+            self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
+            self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
+        }
+        self.emit_instr(in_proc, "mov", "rsp, rbx");
     }
 
     // ========================================================================
@@ -240,7 +925,7 @@ impl StackAsmBuilder {
             0 => {
                 self.emit_instr(in_proc, "mov", &format!("rax, [{}]", pile));
                 self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
-                self.emit_instr(in_proc, "syscall", "");
+                self.emit_raw_syscall(in_proc);
                 self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
                 self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
             }
@@ -248,7 +933,7 @@ impl StackAsmBuilder {
                 self.emit_instr(in_proc, "mov", &format!("rax, [{} + 8]", pile));
                 self.emit_instr(in_proc, "mov", &format!("rdi, [{}]", pile));
                 self.emit_instr(in_proc, "add", &format!("{}, 16", pile));
-                self.emit_instr(in_proc, "syscall", "");
+                self.emit_raw_syscall(in_proc);
                 self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
                 self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
             }
@@ -257,7 +942,7 @@ impl StackAsmBuilder {
                 self.emit_instr(in_proc, "mov", &format!("rdi, [{} + 8]", pile));
                 self.emit_instr(in_proc, "mov", &format!("rsi, [{}]", pile));
                 self.emit_instr(in_proc, "add", &format!("{}, 24", pile));
-                self.emit_instr(in_proc, "syscall", "");
+                self.emit_raw_syscall(in_proc);
                 self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
                 self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
             }
@@ -267,7 +952,7 @@ impl StackAsmBuilder {
                 self.emit_instr(in_proc, "mov", &format!("rsi, [{} + 8]", pile));
                 self.emit_instr(in_proc, "mov", &format!("rdx, [{}]", pile));
                 self.emit_instr(in_proc, "add", &format!("{}, 32", pile));
-                self.emit_instr(in_proc, "syscall", "");
+                self.emit_raw_syscall(in_proc);
                 self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
                 self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
             }
@@ -278,7 +963,7 @@ impl StackAsmBuilder {
                 self.emit_instr(in_proc, "mov", &format!("rdx, [{} + 8]", pile));
                 self.emit_instr(in_proc, "mov", &format!("r10, [{}]", pile));
                 self.emit_instr(in_proc, "add", &format!("{}, 40", pile));
-                self.emit_instr(in_proc, "syscall", "");
+                self.emit_raw_syscall(in_proc);
                 self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
                 self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
             }
@@ -290,7 +975,7 @@ impl StackAsmBuilder {
                 self.emit_instr(in_proc, "mov", &format!("r10, [{} + 8]", pile));
                 self.emit_instr(in_proc, "mov", &format!("r8, [{}]", pile));
                 self.emit_instr(in_proc, "add", &format!("{}, 48", pile));
-                self.emit_instr(in_proc, "syscall", "");
+                self.emit_raw_syscall(in_proc);
                 self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
                 self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
             }
@@ -303,7 +988,7 @@ impl StackAsmBuilder {
                 self.emit_instr(in_proc, "mov", &format!("r8, [{} + 8]", pile));
                 self.emit_instr(in_proc, "mov", &format!("r9, [{}]", pile));
                 self.emit_instr(in_proc, "add", &format!("{}, 56", pile));
-                self.emit_instr(in_proc, "syscall", "");
+                self.emit_raw_syscall(in_proc);
                 self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
                 self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
             }
@@ -311,6 +996,15 @@ impl StackAsmBuilder {
         }
     }
 
+    /// `syscall` of eboth code: with libc linked in, its stdio buffers are
+    /// flushed first so that C output written before stays before
+    fn emit_raw_syscall(&mut self, in_proc: bool) {
+        if self.links_libc() {
+            self.emit_instr(in_proc, "call", "flush_stdio");
+        }
+        self.emit_instr(in_proc, "syscall", "");
+    }
+
     // ========================================================================
     // Comparison Operations
     // ========================================================================
@@ -375,6 +1069,34 @@ impl StackAsmBuilder {
         self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
     }
 
+    // Unsigned comparisons: same shape as the signed ones, with the
+    // below/above condition codes
+    fn emit_cmp_set(&mut self, setcc: &str, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "mov", &format!("rax, [{} + 8]", pile));
+        self.emit_instr(in_proc, "cmp", &format!("rax, [{}]", pile));
+        self.emit_instr(in_proc, setcc, "al");
+        self.emit_instr(in_proc, "movzx", "rax, al");
+        self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
+        self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
+    }
+
+    pub fn emit_ult(&mut self, in_proc: bool) {
+        self.emit_cmp_set("setb", in_proc);
+    }
+
+    pub fn emit_ugt(&mut self, in_proc: bool) {
+        self.emit_cmp_set("seta", in_proc);
+    }
+
+    pub fn emit_ule(&mut self, in_proc: bool) {
+        self.emit_cmp_set("setbe", in_proc);
+    }
+
+    pub fn emit_uge(&mut self, in_proc: bool) {
+        self.emit_cmp_set("setae", in_proc);
+    }
+
     // ========================================================================
     // Bitwise Operations
     // ========================================================================
@@ -426,6 +1148,15 @@ impl StackAsmBuilder {
         self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
     }
 
+    pub fn emit_sar(&mut self, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "mov", &format!("rcx, [{}]", pile));
+        self.emit_instr(in_proc, "mov", &format!("rax, [{} + 8]", pile));
+        self.emit_instr(in_proc, "sar", "rax, cl");
+        self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
+        self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
+    }
+
     // ========================================================================
     // Memory Operations
     // ========================================================================
@@ -494,38 +1225,46 @@ impl StackAsmBuilder {
     // Helper Functions (added to output)
     // ========================================================================
 
-    pub fn add_dump_helper(&mut self) {
-        self.funcs.push(r#"dump_i:
-        sub     rsp, 40
-        xor     r9d, r9d
-        test    rdi, rdi
-        jns     .L2
-        neg     rdi
-        mov     r9d, 1
+    /// Adds `dump_i` (for `dump`) and `write_str` (for `puts`) when used, and
+    /// `flush_stdio` when libc is linked in
+    pub fn add_dump_helper(&mut self, dump: bool, puts: bool) {
+        if self.links_libc() {
+            self.add_flush_stdio();
+        }
+        if dump {
+            self.add_dump_i();
+        }
+        if puts {
+            self.add_write_str();
+        }
+    }
+
+    fn add_dump_i(&mut self) {
+        // Digits of |rdi| as an unsigned value, so that i64::MIN (which neg
+        // leaves as 2^63) prints too
+        self.funcs.push(format!(r#"dump_i:{}
+    sub     rsp, 40
+    xor     r9d, r9d
+    mov     rax, rdi
+    test    rax, rax
+    jns     .L2
+    neg     rax
+    mov     r9d, 1
 .L2:
-        mov  rsi, 7378697629483820647
-        mov     ecx, 32
+    mov     ecx, 32
+    mov     r8d, 10
 .L3:
-        mov     rax, rdi
-        mov     r8, rcx
-        sub     rcx, 1
-        imul    rsi
-        mov     rax, rdi
-        sar     rax, 63
-        sar     rdx, 2
-        sub     rdx, rax
-        lea     rax, [rdx+rdx*4]
-        add     rax, rax
-        sub     rdi, rax
-        add     edi, 48
-        mov     BYTE [rsp+rcx], dil
-        mov     rdi, rdx
-        test    rdx, rdx
-        jne     .L3
-        test    r9d, r9d
-        je      .L4
-        mov     BYTE [rsp-2+r8], 45
-        lea     rcx, [r8-2]
+    xor     edx, edx
+    div     r8
+    add     edx, 48
+    sub     rcx, 1
+    mov     BYTE [rsp+rcx], dl
+    test    rax, rax
+    jne     .L3
+    test    r9d, r9d
+    je      .L4
+    sub     rcx, 1
+    mov     BYTE [rsp+rcx], 45
 .L4:
     mov     rdx, 32
     lea     rsi, [rsp+rcx]
@@ -534,35 +1273,123 @@ impl StackAsmBuilder {
     mov     rdi, 1
     syscall
     add     rsp, 40
-    ret"#.to_string());
+    ret"#, self.flush_call()));
+    }
 
-        self.funcs.push(r#"dump_str:
-    push    rbx
-    mov     rbx, rdi
-    xor     rax, rax
-.loop:
-    cmp     byte [rdi + rax], 0
-    je      .done
-    inc     rax
-    jmp     .loop
-.done:
-    mov     rdx, rax
-    mov     rsi, rbx
+    fn add_write_str(&mut self) {
+        // write_str(rdi = ptr, rsi = len): write(1, ptr, len)
+        self.funcs.push(format!(r#"write_str:{}
+    mov     rdx, rsi
+    mov     rsi, rdi
     mov     rax, 1
     mov     rdi, 1
-    pop     rbx
     syscall
+    ret"#, self.flush_call()));
+    }
+
+    /// First line of the helpers writing to stdout directly
+    fn flush_call(&self) -> &'static str {
+        if self.links_libc() { "\n    call    flush_stdio" } else { "" }
+    }
+
+    fn add_flush_stdio(&mut self) {
+        // fflush(NULL), keeping every register a syscall or helper may take
+        // as an argument; rsp aligned to 16 bytes around the call
+        self.funcs.push(r#"flush_stdio:
+    push    rax
+    push    rcx
+    push    rdx
+    push    rsi
+    push    rdi
+    push    r8
+    push    r9
+    push    r10
+    push    r11
+    push    rbx
+    mov     rbx, rsp
+    and     rsp, -16
+    xor     edi, edi
+    call    fflush wrt ..plt
+    mov     rsp, rbx
+    pop     rbx
+    pop     r11
+    pop     r10
+    pop     r9
+    pop     r8
+    pop     rdi
+    pop     rsi
+    pop     rdx
+    pop     rcx
+    pop     rax
     ret"#.to_string());
     }
 
     // ========================================================================
-    // Exit
+    // Process: arguments and exit
     // ========================================================================
 
-    pub fn emit_exit(&mut self) {
-        self.emit_text_instr("mov", "rax, 60");
-        self.emit_text_instr("xor", "rdi, rdi");
-        self.emit_text_instr("syscall", "");
+    /// `argc`: the count `_start` saved
+    pub fn emit_argc(&mut self, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "mov", "rax, [rel program_argc]");
+        self.emit_instr(in_proc, "sub", &format!("{}, 8", pile));
+        self.emit_instr(in_proc, "mov", &format!("qword [{}], rax", pile));
+    }
+
+    /// `argv` / `envp` ( index -- ptr ): entry `index` of the array whose
+    /// address `_start` saved in `global`, holding `count` entries before its
+    /// NULL. An index out of the array (negative ones too, compared unsigned)
+    /// reads that NULL.
+    pub fn emit_startup_array(&mut self, global: &str, count: &str, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.emit_instr(in_proc, "mov", &format!("rax, [rel {}]", global));
+        self.emit_instr(in_proc, "mov", &format!("rcx, [{}]", pile));
+        self.emit_instr(in_proc, "mov", &format!("rdx, [rel {}]", count));
+        self.emit_instr(in_proc, "cmp", "rcx, rdx");
+        self.emit_instr(in_proc, "cmova", "rcx, rdx");
+        self.emit_instr(in_proc, "mov", "rax, [rax + rcx*8]");
+        self.emit_instr(in_proc, "mov", &format!("[{}], rax", pile));
+    }
+
+    /// `exit`: pops the status and ends the process
+    pub fn emit_exit_builtin(&mut self, in_proc: bool) {
+        let pile = &self.stack_reg.clone();
+        self.uses_exit = true;
+        self.emit_instr(in_proc, "mov", &format!("rdi, [{}]", pile));
+        self.emit_instr(in_proc, "add", &format!("{}, 8", pile));
+        if self.links_libc() {
+            self.emit_instr(in_proc, "and", "rsp, -16");
+        }
+        self.emit_exit_call(in_proc);
+    }
+
+    /// Executables declaring an `extern` are linked against libc, and an
+    /// object file always ends up in a C program
+    fn links_libc(&self) -> bool {
+        !self.externs.is_empty() || !self.executable
+    }
+
+    /// Ends the process with the status in rdi. With libc linked in, exit
+    /// through it so its atexit handlers run and stdio is flushed.
+    fn emit_exit_call(&mut self, in_proc: bool) {
+        if self.links_libc() {
+            self.emit_instr(in_proc, "call", "exit wrt ..plt");
+            return;
+        }
+        self.emit_instr(in_proc, "mov", "rax, 60");
+        self.emit_instr(in_proc, "syscall", "");
+    }
+
+    /// After `main` returns: its result is the exit status when `status`,
+    /// otherwise the status is 0
+    pub fn emit_exit(&mut self, status: bool) {
+        if status {
+            let pile = &self.stack_reg.clone();
+            self.emit_text_instr("mov", &format!("rdi, [{}]", pile));
+        } else {
+            self.emit_text_instr("xor", "edi, edi");
+        }
+        self.emit_exit_call(false);
     }
 
     // ========================================================================
@@ -575,6 +1402,9 @@ impl StackAsmBuilder {
         // BSS Section
         output.push("section .bss".to_string());
         output.extend(self.bss.clone());
+        output.push("    alignb 8".to_string());
+        output.push(format!("    data_stack: resq {}", self.data_stack_cells));
+        output.push(format!("    data_stack_proc: resq {}", self.proc_stack_cells));
 
         // Data Section
         output.push("\nsection .data".to_string());
@@ -586,103 +1416,402 @@ impl StackAsmBuilder {
         // Functions
         if !self.funcs.is_empty() {
             output.push(String::new());
-            output.extend(self.funcs.clone());
+            if self.optimize {
+                output.extend(peephole::optimize(&self.funcs));
+            } else {
+                output.extend(self.funcs.clone());
+            }
         }
 
-        // Entry point
-        output.push("\nglobal _start".to_string());
-        output.push("_start:".to_string());
-        output.push(format!("    lea      r15, [data_stack + 4096*8]"));
-        output.push("    call     proc_main".to_string());
+        for global in &self.globals {
+            output.push(format!("global {}", global));
+        }
+        let mut externs: BTreeSet<&str> = self.externs.keys().map(String::as_str).collect();
+        if self.links_libc() {
+            externs.insert("fflush");
+        }
+        if self.links_libc() && (self.executable || self.uses_exit) {
+            externs.insert("exit");
+        }
+        for name in externs {
+            output.push(format!("extern {}", name));
+        }
 
-        // Main code
-        output.extend(self.text.clone());
+        // Entry point
+        if self.executable {
+            output.push("\nglobal _start".to_string());
+            output.push("_start:".to_string());
+            // The kernel leaves argc at [rsp], then argv and envp, each ended by NULL
+            output.push("    mov      rax, [rsp]".to_string());
+            output.push("    mov      [rel program_argc], rax".to_string());
+            output.push("    lea      rcx, [rsp + 8]".to_string());
+            output.push("    mov      [rel program_argv], rcx".to_string());
+            output.push("    lea      rcx, [rcx + rax*8 + 8]".to_string());
+            output.push("    mov      [rel program_envp], rcx".to_string());
+            output.push("    mov      rdx, -1".to_string());
+            output.push(".count_env:".to_string());
+            output.push("    inc      rdx".to_string());
+            output.push("    cmp      qword [rcx + rdx*8], 0".to_string());
+            output.push("    jne      .count_env".to_string());
+            output.push("    mov      [rel program_envc], rdx".to_string());
+            output.push(format!("    lea      r15, [rel data_stack + {}*8]", self.data_stack_cells));
+            output.push(format!("    lea      r14, [rel data_stack_proc + {}*8]", self.proc_stack_cells));
+            output.push(format!("    call     {}", self.proc_label("main")));
+
+            // Main code
+            output.extend(self.text.clone());
+        } else {
+            // Without it, ld assumes the object needs an executable stack
+            output.push("\nsection .note.GNU-stack noalloc noexec nowrite progbits".to_string());
+        }
 
         output.join("\n")
     }
-
-    pub fn write_to_file(&self, path: &str) -> std::io::Result<()> {
-        let mut file = File::create(path)?;
-        file.write_all(self.build().as_bytes())?;
-        Ok(())
-    }
 }
 
 // ============================================================================
 // Compiler from IR to Assembly
 // ============================================================================
 
-use crate::{EIrInstr, EType};
+use crate::{CStackToInterpreter, EIrInstr, EType};
+use crate::diagnostic::CDiagnostic;
+use crate::peephole;
 
 pub struct StackCompiler;
 
+/// How `StackCompiler` generates code
+pub struct CompileOptions {
+    pub opt_level: u8,               // -O0 to -O2
+    pub check_stack: bool,           // bounds checks on the data and proc stacks
+    pub data_stack_cells: usize,
+    pub proc_stack_cells: usize,
+}
+
+/// What the compiler leaves out of the assembly: procs and consts that no
+/// entry point reaches and output helpers that nothing calls
+pub struct DeadCode {
+    pub procs: Vec<&'static str>,
+    pub consts: Vec<&'static str>,
+    pub helpers: Vec<&'static str>,
+}
+
 impl StackCompiler {
-    pub fn compile(ir: &[EIrInstr]) -> Result<String, String> {
+    /// Assembly of a whole program, entered at `_start`
+    pub fn compile(ir: &[EIrInstr], options: &CompileOptions) -> Result<String, CDiagnostic> {
+        Self::compile_program(ir, true, options)
+    }
+
+    /// Assembly of an object file for C programs: no `_start`, `main` is
+    /// optional and exported procs are the entry points
+    pub fn compile_object(ir: &[EIrInstr], options: &CompileOptions) -> Result<String, CDiagnostic> {
+        Self::compile_program(ir, false, options)
+    }
+
+    /// Procs and consts reachable through calls from the entry points:
+    /// `main` for executables and the exported procs. Consts only used in
+    /// `memory` sizes are evaluated at compile time and are not reachable.
+    pub fn reachable(ir: &[EIrInstr], executable: bool) -> HashSet<&'static str> {
+        let mut bodies: HashMap<&'static str, &Vec<EIrInstr>> = HashMap::new();
+        let mut work = Vec::new();
+        for instr in ir {
+            match instr {
+                EIrInstr::Proc(name, body, _, _) | EIrInstr::Const(name, body) => {
+                    bodies.insert(name, body);
+                    if executable && *name == "main" {
+                        work.push(*name);
+                    }
+                }
+                EIrInstr::Export(name) => work.push(name),
+                _ => {}
+            }
+        }
+
+        let mut reached = HashSet::new();
+        while let Some(name) = work.pop() {
+            if !reached.insert(name) {
+                continue;
+            }
+            for instr in bodies.get(name).map_or(&[][..], |body| body.as_slice()) {
+                if let EIrInstr::Call(callee) = instr {
+                    work.push(callee);
+                }
+            }
+        }
+        reached
+    }
+
+    /// Procs and consts (in source order) and helpers that `compile` or
+    /// `compile_object` drop, for `--verbose`
+    pub fn dead_code(ir: &[EIrInstr], executable: bool) -> DeadCode {
+        let reached = Self::reachable(ir, executable);
+        let (mut procs, mut consts) = (Vec::new(), Vec::new());
+        for instr in ir {
+            let (list, name) = match instr {
+                EIrInstr::Proc(name, _, _, _) => (&mut procs, name),
+                EIrInstr::Const(name, _) => (&mut consts, name),
+                _ => continue,
+            };
+            if !reached.contains(name) && !list.contains(name) {
+                list.push(*name);
+            }
+        }
+        let (dump, puts) = Self::used_helpers(ir, &reached);
+        let mut helpers = Vec::new();
+        if !dump {
+            helpers.push("dump_i");
+        }
+        if !puts {
+            helpers.push("write_str");
+        }
+        DeadCode { procs, consts, helpers }
+    }
+
+    /// Whether the reachable bodies use `dump` and `puts`
+    fn used_helpers(ir: &[EIrInstr], reached: &HashSet<&'static str>) -> (bool, bool) {
+        let bodies = ir.iter().filter_map(|instr| match instr {
+            EIrInstr::Proc(name, body, _, _) | EIrInstr::Const(name, body) if reached.contains(name) => Some(body),
+            _ => None,
+        });
+        let (mut dump, mut puts) = (false, false);
+        for instr in bodies.flatten() {
+            dump |= matches!(instr, EIrInstr::Dump);
+            puts |= matches!(instr, EIrInstr::Puts);
+        }
+        (dump, puts)
+    }
+
+    fn compile_program(ir: &[EIrInstr], executable: bool, options: &CompileOptions) -> Result<String, CDiagnostic> {
         let mut asm = StackAsmBuilder::new();
-        let mut procs: HashMap<&'static str, &Vec<EIrInstr>> = HashMap::new();
+        asm.executable = executable;
+        asm.optimize = options.opt_level >= 1;
+        asm.cache_tos = options.opt_level >= 2;
+        asm.check_stack = options.check_stack;
+        asm.data_stack_cells = options.data_stack_cells;
+        asm.proc_stack_cells = options.proc_stack_cells;
+        let mut bss_size = (options.data_stack_cells + options.proc_stack_cells) * 8;
+        let reached = Self::reachable(ir, executable);
+        // Procs and consts in source order, so that the same program always
+        // gives the same assembly
+        let mut procs: Vec<(&'static str, &Vec<EIrInstr>)> = Vec::new();
         let mut has_main = false;
-
-        // Add helper functions
-        asm.add_dump_helper();
+        let mut main_status = false;
 
         // Collect all procedures
         for instr in ir {
             match instr {
-                EIrInstr::Proc(name, body, _, _) => {
+                EIrInstr::Proc(name, body, _, rets) => {
                     if *name == "main" {
                         has_main = true;
+                        main_status = !rets.is_empty();
                     }
-                    procs.insert(name, body);
+                    if reached.contains(name) {
+                        procs.push((name, body));
+                    }
+                }
+                EIrInstr::Const(name, body) if reached.contains(name) => procs.push((name, body)),
+                EIrInstr::Memory(name, size) => {
+                    let size = CStackToInterpreter::F_iMemorySize(ir, name, size)
+                        .map_err(|e| CDiagnostic::F_cError("E0301", e))?;
+                    bss_size += size.next_multiple_of(8);
+                    asm.add_memory(name, size);
                 }
-                EIrInstr::Const(name, body) => {
-                    procs.insert(name, body);
+                EIrInstr::Export(name) => asm.exported.push(name.to_string()),
+                EIrInstr::Extern(name, params, rets) => {
+                    asm.externs.insert(name.to_string(), (params.clone(), rets.clone()));
                 }
                 _ => {}
             }
         }
 
-        if !has_main {
-            return Err("Erreur: proc main non declaree".to_string());
+        if executable && !has_main {
+            return Err(CDiagnostic::F_cError("E0202", "no `main` proc declared"));
+        }
+        if bss_size > BSS_SIZE_MAX {
+            return Err(CDiagnostic::F_cError("E0301", format!(
+                "the stacks and memories take {} bytes, more than the {} the compiled code can address",
+                bss_size, BSS_SIZE_MAX
+            )));
         }
 
-        // Collect return types
-        let mut ret_types: HashMap<&'static str, EType> = HashMap::new();
-        for instr in ir {
-            if let EIrInstr::Proc(name, _, _, ret_type) = instr {
-                ret_types.insert(name, ret_type.clone());
-            }
+        // Add helper functions, once the externs tell whether libc is linked in
+        let (dump, puts) = Self::used_helpers(ir, &reached);
+        asm.add_dump_helper(dump, puts);
+        if asm.check_stack {
+            asm.add_stack_error();
         }
 
         // Compile all procedures
         for (name, body) in &procs {
-            let is_main = *name == "main";
-            let returns_value = ret_types.get(name).map_or(false, |t| *t != EType::Void);
-            asm.emit_proc_start(name, is_main);
-            Self::compile_instrs(&mut asm, body, true, is_main, returns_value);
+            asm.emit_proc_start(name);
+            Self::compile_instrs(&mut asm, body, true);
+            asm.emit_proc_end(name);
+        }
+
+        // C entry points of exported procs
+        for instr in ir {
+            if let EIrInstr::Proc(name, _, params, rets) = instr
+                && asm.exported.iter().any(|e| e == name) {
+                asm.emit_c_shim(name, params, rets);
+            }
         }
 
         // Emit exit
-        asm.emit_exit();
+        asm.emit_exit(main_status);
 
         Ok(asm.build())
     }
 
-    fn compile_instrs(asm: &mut StackAsmBuilder, instrs: &[EIrInstr], in_proc: bool, is_main: bool, returns_value: bool) {
-        for instr in instrs {
+    /// C declarations of the exported procs, for `emit-header`
+    pub fn c_header(ir: &[EIrInstr], guard: &str) -> String {
+        let c_type = |ty: &EType| match ty {
+            EType::I8 => "int8_t",
+            EType::U8 => "uint8_t",
+            EType::I16 => "int16_t",
+            EType::U16 => "uint16_t",
+            EType::I32 => "int32_t",
+            EType::U32 => "uint32_t",
+            EType::U64 => "uint64_t",
+            EType::Ptr => "void *",
+            EType::Bool => "bool",
+            _ => "int64_t",
+        };
+
+        let mut out = vec![
+            "/* Generated by eboth: C declarations of the exported procs. */".to_string(),
+            format!("#ifndef {}", guard),
+            format!("#define {}", guard),
+            String::new(),
+            "#include <stdbool.h>".to_string(),
+            "#include <stdint.h>".to_string(),
+            String::new(),
+            "#ifdef __cplusplus".to_string(),
+            "extern \"C\" {".to_string(),
+            "#endif".to_string(),
+            String::new(),
+        ];
+        for instr in ir {
+            let EIrInstr::Proc(name, _, params, rets) = instr else { continue };
+            if !ir.iter().any(|i| matches!(i, EIrInstr::Export(e) if e == name)) {
+                continue;
+            }
+            let mut args = Vec::new();
+            for ty in params {
+                match ty {
+                    // A str is passed as its two cells
+                    EType::Str => {
+                        args.push("const char *".to_string());
+                        args.push("int64_t".to_string());
+                    }
+                    _ => args.push(c_type(ty).to_string()),
+                }
+            }
+            let args = if args.is_empty() { "void".to_string() } else { args.join(", ") };
+            let ret = rets.first().map_or("void", c_type);
+            out.push(format!("{} proc_{}({});", ret, StackAsmBuilder::asm_name(name), args));
+        }
+        out.extend([
+            String::new(),
+            "#ifdef __cplusplus".to_string(),
+            "}".to_string(),
+            "#endif".to_string(),
+            String::new(),
+            format!("#endif /* {} */", guard),
+            String::new(),
+        ]);
+        out.join("\n")
+    }
+
+    /// Cells that `instr` adds to the data stack, for --check-stack. A call
+    /// is checked at the callee's entry and by its own pushes.
+    fn pushed_cells(asm: &StackAsmBuilder, instr: &EIrInstr) -> usize {
+        let cells = |types: &[EType]| -> usize { types.iter().map(|ty| if *ty == EType::Str { 2 } else { 1 }).sum() };
+        match instr {
+            EIrInstr::PushI64(_) | EIrInstr::PushMem(_) | EIrInstr::Dup | EIrInstr::Over | EIrInstr::Argc => 1,
+            EIrInstr::PushStr(_) => 2,
+            EIrInstr::CallExtern(name) => {
+                let (params, rets) = &asm.externs[*name];
+                cells(rets).saturating_sub(cells(params))
+            }
+            _ => 0,
+        }
+    }
+
+    fn compile_instrs(asm: &mut StackAsmBuilder, instrs: &[EIrInstr], in_proc: bool) {
+        let mut i = 0;
+        while i < instrs.len() {
+            let instr = &instrs[i];
+            i += 1;
+            if asm.check_stack {
+                let cells = Self::pushed_cells(asm, instr);
+                if cells > 0 {
+                    asm.emit_push_check(cells, in_proc);
+                }
+            }
+            if asm.cache_tos {
+                if let Some(consumed) = asm.emit_cached(instr, instrs.get(i), in_proc) {
+                    i += consumed;
+                    continue;
+                }
+                asm.spill(in_proc);
+            } else if asm.optimize
+                && let Some(next) = instrs.get(i)
+                && asm.emit_fused(instr, next, in_proc) {
+                i += 1;
+                continue;
+            }
             match instr {
                 EIrInstr::PushI64(val) => asm.emit_push_i64(*val, in_proc),
                 EIrInstr::PushStr(s) => asm.emit_push_str(s, in_proc),
+                EIrInstr::PushMem(name) => asm.emit_push_mem(name, in_proc),
                 EIrInstr::AddI64 => asm.emit_add_i64(in_proc),
                 EIrInstr::SubI64 => asm.emit_sub_i64(in_proc),
                 EIrInstr::MulI64 => asm.emit_mul_i64(in_proc),
                 EIrInstr::DivI64 => asm.emit_div_i64(in_proc),
                 EIrInstr::ModI64 => asm.emit_mod_i64(in_proc),
+                EIrInstr::DivU64 => asm.emit_udiv_u64(in_proc),
+                EIrInstr::ModU64 => asm.emit_umod_u64(in_proc),
+                EIrInstr::EqI64 => asm.emit_eq(in_proc),
+                EIrInstr::NeqI64 => asm.emit_neq(in_proc),
+                EIrInstr::LtI64 => asm.emit_lt(in_proc),
+                EIrInstr::GtI64 => asm.emit_gt(in_proc),
+                EIrInstr::LeI64 => asm.emit_le(in_proc),
+                EIrInstr::GeI64 => asm.emit_ge(in_proc),
+                EIrInstr::LtU64 => asm.emit_ult(in_proc),
+                EIrInstr::GtU64 => asm.emit_ugt(in_proc),
+                EIrInstr::LeU64 => asm.emit_ule(in_proc),
+                EIrInstr::GeU64 => asm.emit_uge(in_proc),
+                EIrInstr::AndI64 => asm.emit_and(in_proc),
+                EIrInstr::OrI64 => asm.emit_or(in_proc),
+                EIrInstr::XorI64 => asm.emit_xor(in_proc),
+                EIrInstr::NotI64 => asm.emit_not(in_proc),
+                EIrInstr::ShlI64 => asm.emit_shl(in_proc),
+                EIrInstr::ShrU64 => asm.emit_shr(in_proc),
+                EIrInstr::SarI64 => asm.emit_sar(in_proc),
                 EIrInstr::Dup => asm.emit_dup(in_proc),
                 EIrInstr::Swap => asm.emit_swap(in_proc),
+                EIrInstr::Drop => asm.emit_drop(in_proc),
+                EIrInstr::Over => asm.emit_over(in_proc),
+                EIrInstr::Rot => asm.emit_rot(in_proc),
                 EIrInstr::Dump => asm.emit_dump(in_proc),
                 EIrInstr::Puts => asm.emit_puts(in_proc),
+                EIrInstr::Load8 => asm.emit_load8(in_proc),
+                EIrInstr::Load16 => asm.emit_load16(in_proc),
+                EIrInstr::Load32 => asm.emit_load32(in_proc),
+                EIrInstr::Load64 => asm.emit_load64(in_proc),
+                EIrInstr::Store8 => asm.emit_store8(in_proc),
+                EIrInstr::Store16 => asm.emit_store16(in_proc),
+                EIrInstr::Store32 => asm.emit_store32(in_proc),
+                EIrInstr::Store64 => asm.emit_store64(in_proc),
+                EIrInstr::Label(id) => asm.emit_label(*id, in_proc),
+                EIrInstr::Jump(id) => asm.emit_jump(*id, in_proc),
+                EIrInstr::JumpIfZero(id) => asm.emit_jump_if_zero(*id, in_proc),
                 EIrInstr::Call(name) => asm.emit_call(name, in_proc),
-                EIrInstr::Ret => asm.emit_ret(in_proc, is_main, returns_value),
+                EIrInstr::CallExtern(name) => asm.emit_call_extern(name, in_proc),
+                EIrInstr::Ret => asm.emit_ret(in_proc),
+                EIrInstr::Argc => asm.emit_argc(in_proc),
+                EIrInstr::Argv => asm.emit_startup_array("program_argv", "program_argc", in_proc),
+                EIrInstr::Envp => asm.emit_startup_array("program_envp", "program_envc", in_proc),
+                EIrInstr::Exit => asm.emit_exit_builtin(in_proc),
                 EIrInstr::Syscall0 => asm.emit_syscall(0, in_proc),
                 EIrInstr::Syscall1 => asm.emit_syscall(1, in_proc),
                 EIrInstr::Syscall2 => asm.emit_syscall(2, in_proc),
@@ -690,7 +1819,7 @@ impl StackCompiler {
                 EIrInstr::Syscall4 => asm.emit_syscall(4, in_proc),
                 EIrInstr::Syscall5 => asm.emit_syscall(5, in_proc),
                 EIrInstr::Syscall6 => asm.emit_syscall(6, in_proc),
-                EIrInstr::Proc(_, _, _, _) | EIrInstr::Const(_, _) => {
+                EIrInstr::Proc(_, _, _, _) | EIrInstr::Const(_, _) | EIrInstr::Memory(_, _) | EIrInstr::Export(_) | EIrInstr::Extern(_, _, _) => {
                     // Skip nested proc/const definitions
                 }
                 _ => {
@@ -0,0 +1,287 @@
+#![allow(nonstandard_style)]
+use std::collections::{HashMap, HashSet};
+use crate::EIrInstr;
+
+// ============================================================================
+// Inliner: runs on a checked program before code generation and replaces the
+// calls to consts and to small procs by the callee's body, with its labels
+// renumbered. Procs keep their own definition (for the calls that remain and
+// for exported procs); unreachable ones are dropped by the compiler.
+// ============================================================================
+
+// A proc whose body (once its own calls are inlined) has at most this many
+// instructions is inlined without an `inline` annotation
+const INLINE_THRESHOLD: usize = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+enum EInlinePolicy {
+    Auto,
+    Always,
+    Never,
+}
+
+// What the inliner did, for --verbose
+pub struct CInlineReport {
+    // (callee, number of call sites replaced), in source order
+    pub l_lInlined: Vec<(&'static str, usize)>,
+    // `inline` procs that some calls still go to, with the reason
+    pub l_lKept: Vec<(&'static str, String)>,
+}
+
+struct CInliner<'a> {
+    l_hmBodies: HashMap<&'static str, &'a [EIrInstr]>,
+    l_hmPolicies: HashMap<&'static str, EInlinePolicy>,
+    l_hsConsts: HashSet<&'static str>,
+    // Bodies with their own calls inlined, without the final Ret
+    l_hmExpanded: HashMap<&'static str, Vec<EIrInstr>>,
+    l_lInProgress: Vec<&'static str>,
+    // Callees reached again while their own body was being expanded
+    l_hsRecursive: HashSet<&'static str>,
+    l_hmSites: HashMap<&'static str, usize>,
+    l_lKept: Vec<(&'static str, String)>,
+    l_iNextLabel: usize,
+}
+
+impl<'a> CInliner<'a> {
+    fn F_cNew(l_lIr: &'a [EIrInstr]) -> Self {
+        let mut l_hmBodies = HashMap::new();
+        let mut l_hmPolicies = HashMap::new();
+        let mut l_hsConsts = HashSet::new();
+        let mut l_iNextLabel = 0;
+        for l_cInstr in l_lIr {
+            match l_cInstr {
+                EIrInstr::Proc(l_sName, l_lBody, _, _) => {
+                    l_hmBodies.insert(*l_sName, l_lBody.as_slice());
+                    l_hsConsts.remove(l_sName);
+                }
+                EIrInstr::Const(l_sName, l_lBody) => {
+                    l_hmBodies.insert(*l_sName, l_lBody.as_slice());
+                    l_hsConsts.insert(*l_sName);
+                }
+                EIrInstr::Inline(l_sName) => { l_hmPolicies.insert(*l_sName, EInlinePolicy::Always); }
+                EIrInstr::NoInline(l_sName) => { l_hmPolicies.insert(*l_sName, EInlinePolicy::Never); }
+                _ => {}
+            }
+        }
+        // New labels are numbered after every label of the program
+        for l_lBody in l_hmBodies.values() {
+            for l_cInstr in l_lBody.iter() {
+                if let EIrInstr::Label(l_iLabel) = l_cInstr {
+                    l_iNextLabel = l_iNextLabel.max(l_iLabel + 1);
+                }
+            }
+        }
+        CInliner {
+            l_hmBodies, l_hmPolicies, l_hsConsts, l_hmExpanded: HashMap::new(), l_lInProgress: Vec::new(),
+            l_hsRecursive: HashSet::new(), l_hmSites: HashMap::new(), l_lKept: Vec::new(), l_iNextLabel,
+        }
+    }
+
+    // Body of l_sName with its own calls inlined, without the final Ret
+    fn F_lExpand(&mut self, l_sName: &'static str) -> Vec<EIrInstr> {
+        if let Some(l_lExpanded) = self.l_hmExpanded.get(l_sName) {
+            return l_lExpanded.clone();
+        }
+        let l_lBody = self.l_hmBodies[l_sName];
+        let l_lBody = match l_lBody.last() {
+            Some(EIrInstr::Ret) => &l_lBody[..l_lBody.len() - 1],
+            _ => l_lBody,
+        };
+
+        self.l_lInProgress.push(l_sName);
+        let mut l_lExpanded = Vec::new();
+        for l_cInstr in l_lBody {
+            match l_cInstr {
+                EIrInstr::Call(l_sCallee) => match self.F_oInlinedBody(l_sCallee) {
+                    Some(l_lCallee) => {
+                        *self.l_hmSites.entry(l_sCallee).or_default() += 1;
+                        l_lExpanded.extend(self.F_lRelabel(l_lCallee));
+                    }
+                    None => l_lExpanded.push(l_cInstr.clone()),
+                },
+                _ => l_lExpanded.push(l_cInstr.clone()),
+            }
+        }
+        self.l_lInProgress.pop();
+
+        self.l_hmExpanded.insert(l_sName, l_lExpanded.clone());
+        l_lExpanded
+    }
+
+    // The body to put in place of a call to l_sCallee, None to keep the call
+    fn F_oInlinedBody(&mut self, l_sCallee: &'static str) -> Option<Vec<EIrInstr>> {
+        let l_ePolicy = self.l_hmPolicies.get(l_sCallee).copied().unwrap_or(EInlinePolicy::Auto);
+        if l_ePolicy == EInlinePolicy::Never || !self.l_hmBodies.contains_key(l_sCallee) {
+            return None;
+        }
+        if self.l_lInProgress.contains(&l_sCallee) {
+            self.l_hsRecursive.insert(l_sCallee);
+            return self.F_oKeep(l_sCallee, l_ePolicy, "it is recursive".to_string());
+        }
+        let l_lBody = self.F_lExpand(l_sCallee);
+        if self.l_hsRecursive.contains(l_sCallee) {
+            return self.F_oKeep(l_sCallee, l_ePolicy, "it is recursive".to_string());
+        }
+        let l_bSmall = l_lBody.len() <= INLINE_THRESHOLD;
+        let l_bConst = self.l_hsConsts.contains(l_sCallee);
+        (l_bConst || l_ePolicy == EInlinePolicy::Always || l_bSmall).then_some(l_lBody)
+    }
+
+    // Keeps the call, noting why when the callee was declared `inline`
+    fn F_oKeep(&mut self, l_sCallee: &'static str, l_ePolicy: EInlinePolicy, l_sReason: String) -> Option<Vec<EIrInstr>> {
+        if l_ePolicy == EInlinePolicy::Always && !self.l_lKept.iter().any(|(l_sName, _)| *l_sName == l_sCallee) {
+            self.l_lKept.push((l_sCallee, l_sReason));
+        }
+        None
+    }
+
+    // Copy of an inlined body with fresh labels, so a body can be inlined
+    // several times in the same proc
+    fn F_lRelabel(&mut self, l_lBody: Vec<EIrInstr>) -> Vec<EIrInstr> {
+        let mut l_hmLabels: HashMap<usize, usize> = HashMap::new();
+        for l_cInstr in &l_lBody {
+            if let EIrInstr::Label(l_iLabel) = l_cInstr {
+                l_hmLabels.insert(*l_iLabel, self.l_iNextLabel);
+                self.l_iNextLabel += 1;
+            }
+        }
+        l_lBody.into_iter()
+            .map(|l_cInstr| match l_cInstr {
+                EIrInstr::Label(l_iLabel) => EIrInstr::Label(l_hmLabels[&l_iLabel]),
+                EIrInstr::Jump(l_iLabel) => EIrInstr::Jump(l_hmLabels[&l_iLabel]),
+                EIrInstr::JumpIfZero(l_iLabel) => EIrInstr::JumpIfZero(l_hmLabels[&l_iLabel]),
+                l_cOther => l_cOther,
+            })
+            .collect()
+    }
+}
+
+// The program with calls inlined in every proc and const body
+pub fn F_tInlineProgram(l_lIr: &[EIrInstr]) -> (Vec<EIrInstr>, CInlineReport) {
+    let mut l_cInliner = CInliner::F_cNew(l_lIr);
+    let mut l_lProgram = Vec::new();
+    for l_cInstr in l_lIr {
+        l_lProgram.push(match l_cInstr {
+            EIrInstr::Proc(l_sName, _, l_lParams, l_lRets) => {
+                let mut l_lBody = l_cInliner.F_lExpand(l_sName);
+                l_lBody.push(EIrInstr::Ret);
+                EIrInstr::Proc(l_sName, l_lBody, l_lParams.clone(), l_lRets.clone())
+            }
+            EIrInstr::Const(l_sName, _) => {
+                let mut l_lBody = l_cInliner.F_lExpand(l_sName);
+                l_lBody.push(EIrInstr::Ret);
+                EIrInstr::Const(l_sName, l_lBody)
+            }
+            l_cOther => l_cOther.clone(),
+        });
+    }
+
+    let mut l_lInlined = Vec::new();
+    for l_cInstr in l_lIr {
+        if let EIrInstr::Proc(l_sName, ..) | EIrInstr::Const(l_sName, _) = l_cInstr
+            && let Some(l_iSites) = l_cInliner.l_hmSites.remove(l_sName) {
+            l_lInlined.push((*l_sName, l_iSites));
+        }
+    }
+    (l_lProgram, CInlineReport { l_lInlined, l_lKept: l_cInliner.l_lKept })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::CSourceMap;
+    use crate::syntax::F_lParseProgram;
+
+    fn F_tInline(l_sCode: &str) -> (Vec<EIrInstr>, CInlineReport) {
+        let mut l_cSourceMap = CSourceMap::F_cNew("test.eb", l_sCode);
+        let l_lProgram = F_lParseProgram(l_sCode, &mut l_cSourceMap).expect("test program parses");
+        F_tInlineProgram(&l_lProgram)
+    }
+
+    fn F_lBody<'p>(l_lProgram: &'p [EIrInstr], l_sProc: &str) -> &'p [EIrInstr] {
+        l_lProgram.iter().find_map(|l_cInstr| match l_cInstr {
+            EIrInstr::Proc(l_sName, l_lBody, _, _) if *l_sName == l_sProc => Some(l_lBody.as_slice()),
+            _ => None,
+        }).expect("proc exists")
+    }
+
+    fn F_bCalls(l_lBody: &[EIrInstr], l_sCallee: &str) -> bool {
+        l_lBody.iter().any(|l_cInstr| matches!(l_cInstr, EIrInstr::Call(l_sName) if *l_sName == l_sCallee))
+    }
+
+    #[test]
+    fn recursive_proc_is_not_inlined() {
+        let (l_lProgram, l_cReport) = F_tInline("\
+inline proc fact i64 ret i64 in
+    dup 1 > if dup 1 - fact * end
+end
+proc main in 5 fact dump end
+");
+        assert!(F_bCalls(F_lBody(&l_lProgram, "main"), "fact"));
+        assert!(F_bCalls(F_lBody(&l_lProgram, "fact"), "fact"));
+        assert!(l_cReport.l_lInlined.is_empty());
+        assert_eq!(l_cReport.l_lKept, [("fact", "it is recursive".to_string())]);
+    }
+
+    #[test]
+    fn mutually_recursive_procs_are_not_inlined_into_callers() {
+        let (l_lProgram, _) = F_tInline("\
+proc even i64 ret bool in dup 0 = if drop 1 1 = else 1 - odd end end
+proc odd i64 ret bool in dup 0 = if drop 1 0 = else 1 - even end end
+proc main in 4 even drop end
+");
+        assert!(F_bCalls(F_lBody(&l_lProgram, "main"), "even"));
+        assert!(F_bCalls(F_lBody(&l_lProgram, "even"), "odd"));
+        assert!(F_bCalls(F_lBody(&l_lProgram, "odd"), "even"));
+    }
+
+    #[test]
+    fn labels_stay_unique_when_inlined_twice() {
+        let (l_lProgram, l_cReport) = F_tInline("\
+proc abs i64 ret i64 in dup 0 < if 0 swap - end end
+proc main in -1 abs -2 abs + dump end
+");
+        assert_eq!(l_cReport.l_lInlined, [("abs", 2)]);
+        let l_lMain = F_lBody(&l_lProgram, "main");
+        assert!(!F_bCalls(l_lMain, "abs"));
+
+        // Labels are numbered program-wide: no two anywhere are the same
+        let mut l_hsLabels = HashSet::new();
+        for l_cInstr in &l_lProgram {
+            let EIrInstr::Proc(_, l_lBody, _, _) = l_cInstr else { continue };
+            for l_cBodyInstr in l_lBody {
+                if let EIrInstr::Label(l_iLabel) = l_cBodyInstr {
+                    assert!(l_hsLabels.insert(*l_iLabel), "label {} defined twice", l_iLabel);
+                }
+            }
+        }
+        // and every jump in main goes to a label of main
+        let l_lMainLabels: Vec<usize> = l_lMain.iter().filter_map(|l_cInstr| match l_cInstr {
+            EIrInstr::Label(l_iLabel) => Some(*l_iLabel),
+            _ => None,
+        }).collect();
+        assert_eq!(l_lMainLabels.len(), 2);
+        for l_cInstr in l_lMain {
+            if let EIrInstr::Jump(l_iLabel) | EIrInstr::JumpIfZero(l_iLabel) = l_cInstr {
+                assert!(l_lMainLabels.contains(l_iLabel));
+            }
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let (l_lProgram, l_cReport) = F_tInline("\
+noinline proc one ret i64 in 1 end
+inline proc big i64 ret i64 in 1 + 1 + 1 + 1 + 1 + end
+proc huge i64 ret i64 in 1 + 1 + 1 + 1 + 1 + end
+const two 2 end
+proc main in one big huge two + + dump end
+");
+        let l_lMain = F_lBody(&l_lProgram, "main");
+        assert!(F_bCalls(l_lMain, "one"));
+        assert!(!F_bCalls(l_lMain, "big"));
+        assert!(F_bCalls(l_lMain, "huge"));
+        assert!(!F_bCalls(l_lMain, "two"));
+        assert_eq!(l_cReport.l_lInlined, [("big", 1), ("two", 1)]);
+    }
+}
@@ -1,10 +1,25 @@
 #![allow(nonstandard_style)]
 mod syntax;
-pub mod engine;
+mod engine;
+mod checker;
+mod source;
+mod diagnostic;
+mod cli;
+mod x86;
+mod elf;
+mod peephole;
+mod inliner;
+mod consteval;
 use syntax::F_lParseProgram;
-use engine::StackCompiler;
+use cli::{EBackend, ECommand};
+use source::CSourceMap;
+use diagnostic::{CDiagnostic, F_vEmitDiagnostics};
+use checker::F_vCheckProgram;
+use engine::{CompileOptions, StackCompiler};
 
 use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::ffi::OsStringExt;
 
 #[derive(PartialEq, Clone, Debug)]
 enum EType {
@@ -24,28 +39,12 @@ enum EType {
     Void,
 }
 
-#[derive(Clone, Debug)]
-enum EValue {
-    I8(i8),
-    U8(u8),
-    I16(i16),
-    U16(u16),
-    I32(i32),
-    U32(u32),
-    I64(i64),
-    U64(u64),
-    F32(f32),
-    F64(f64),
-    Ptr(u64),
-    Str(&'static str),
-    Bool(bool),
-}
-
 #[derive(Clone, Debug)]
 enum EIrInstr {
     // Data manipulation
     PushI64(i64),               // number
     PushStr(&'static str),      // "..."
+    PushMem(&'static str),      // [name_memory] (address of the region)
 
     // Arithmetic
     AddI64,                     // +
@@ -53,13 +52,49 @@ enum EIrInstr {
     MulI64,                     // *
     DivI64,                     // /
     ModI64,                     // %
+    DivU64,                     // udiv
+    ModU64,                     // umod
+
+    // Comparison (push 1 or 0)
+    EqI64,                      // =
+    NeqI64,                     // !=
+    LtI64,                      // <
+    GtI64,                      // >
+    LeI64,                      // <=
+    GeI64,                      // >=
+    LtU64,                      // ult
+    GtU64,                      // ugt
+    LeU64,                      // ule
+    GeU64,                      // uge
+
+    // Bitwise
+    AndI64,                     // &
+    OrI64,                      // |
+    XorI64,                     // ^
+    NotI64,                     // ~
+    ShlI64,                     // <<
+    ShrU64,                     // >> (logical)
+    SarI64,                     // sar (arithmetic)
 
     // Stack manipulation
     Dup,                        // dup
     Swap,                       // swap
+    Drop,                       // drop
+    Over,                       // over
+    Rot,                        // rot
     Puts,                       // puts
     Dump,                       // dump
 
+    // Memory (loads zero-extend, stores truncate)
+    Load8,                      // @8  ( addr -- value )
+    Load16,                     // @16
+    Load32,                     // @32
+    Load64,                     // @64
+    Store8,                     // !8  ( addr value -- )
+    Store16,                    // !16
+    Store32,                    // !32
+    Store64,                    // !64
+
     // Syscalls
     Syscall0,                   // syscall
     Syscall1,                   // syscall1
@@ -69,75 +104,480 @@ enum EIrInstr {
     Syscall5,                   // syscall5
     Syscall6,                   // syscall6 
 
-    // Control flow (not implemented in this example)
+    // Process
+    Argc,                       // argc ( -- count )
+    Argv,                       // argv ( index -- ptr ), C string, NULL at index argc
+    Envp,                       // envp ( index -- ptr ), "NAME=value" C string, NULL after the last
+    Exit,                       // exit ( status -- ), does not return
+
+    // Control flow
+    Label(usize),               // jump target
+    Jump(usize),                // else / end
+    JumpIfZero(usize),          // if (pops the condition)
     Call(&'static str),         // [name_proc/const]
+    CallExtern(&'static str),   // [name_extern] (C function, System V ABI)
     Ret,                        // end (for proc)
-    RetType,                    // -- [type] (for proc)
 
 
-    Proc(&'static str, Vec<EIrInstr>, Vec<EType>, EType),  // proc [name] in ... end
+    Proc(&'static str, Vec<EIrInstr>, Vec<EType>, Vec<EType>),  // proc [name] [params] ret [rets] in ... end
     Const(&'static str, Vec<EIrInstr>), // const [name] in ... end
+    Memory(&'static str, Vec<EIrInstr>), // memory [name] [size] end
+    Export(&'static str),               // export proc [name] ... (follows the Proc)
+    Inline(&'static str),               // inline proc [name] ... (follows the Proc)
+    NoInline(&'static str),             // noinline proc [name] ... (follows the Proc)
+    Extern(&'static str, Vec<EType>, Vec<EType>), // extern [name] [params] ret [rets]
+}
+
+// Instructions a constant expression may run, so that `while 1 do end`
+// in a const cannot hang the compiler
+const CONST_EVAL_STEPS: usize = 10_000_000;
+
+// Largest size of a memory: the compiled code must reach it RIP-relative
+const MEMORY_SIZE_MAX: usize = engine::BSS_SIZE_MAX;
+
+// Interpreter memory: a single fixed-size arena allocated before execution.
+// `@`/`!` may only touch addresses inside it, so every access is checked and
+// out-of-bounds loads/stores are reported instead of corrupting the host.
+// Addresses are the arena's real host addresses, which keeps pointers usable
+// by syscalls (e.g. write(fd, ptr, len)).
+//
+// Memory C handed out (a pointer returned by an `extern`, like malloc's or
+// getenv's) is reachable too: the mapping holding it is recorded, and any
+// access inside a recorded mapping goes straight to the host memory.
+struct CMemory {
+    l_lBytes: Box<[u8]>,
+    l_iUsed: usize,
+    l_lForeign: Vec<CForeignRange>,
+}
+
+// A readable mapping of the process, from /proc/self/maps
+struct CForeignRange {
+    l_iStart: u64,
+    l_iEnd: u64,
+    l_bWritable: bool,
 }
 
-struct CStackToInterpreter;
+impl CMemory {
+    // Zeroed by the allocator, so pages of a large memory the program never
+    // touches cost nothing; a failed allocation is an error, not an abort
+    fn F_cNew(l_iSize: usize) -> Result<Self, String> {
+        let l_sError = || format!("interpreter memory exhausted: cannot allocate {} bytes", l_iSize);
+        if l_iSize == 0 {
+            return Ok(CMemory { l_lBytes: Box::new([]), l_iUsed: 0, l_lForeign: Vec::new() });
+        }
+        let l_cLayout = std::alloc::Layout::array::<u8>(l_iSize).map_err(|_| l_sError())?;
+        // SAFETY: l_cLayout is not zero-sized, and the non-null block it
+        // describes is what a Box<[u8]> of l_iSize bytes owns
+        let l_lBytes = unsafe {
+            let l_pBytes = std::alloc::alloc_zeroed(l_cLayout);
+            if l_pBytes.is_null() {
+                return Err(l_sError());
+            }
+            Box::from_raw(std::ptr::slice_from_raw_parts_mut(l_pBytes, l_iSize))
+        };
+        Ok(CMemory { l_lBytes, l_iUsed: 0, l_lForeign: Vec::new() })
+    }
+
+    fn F_iBase(&self) -> i64 {
+        self.l_lBytes.as_ptr() as i64
+    }
+
+    // Reserves l_iSize zeroed bytes (8-byte aligned) and returns their address
+    fn F_iAlloc(&mut self, l_iSize: usize) -> Result<i64, String> {
+        let l_iAddr = self.F_iBase() + self.l_iUsed as i64;
+        let l_iUsed = l_iSize.checked_next_multiple_of(8)
+            .and_then(|l_iSize| self.l_iUsed.checked_add(l_iSize))
+            .filter(|l_iUsed| *l_iUsed <= self.l_lBytes.len())
+            .ok_or_else(|| format!(
+                "interpreter memory exhausted: {} bytes requested, {} left", l_iSize, self.l_lBytes.len() - self.l_iUsed
+            ))?;
+        self.l_iUsed = l_iUsed;
+        Ok(l_iAddr)
+    }
+
+    fn F_iOffset(&self, l_iAddr: i64, l_iSize: usize) -> Result<usize, String> {
+        let l_iOffset = l_iAddr.wrapping_sub(self.F_iBase());
+        if l_iOffset < 0 || l_iOffset as usize + l_iSize > self.l_lBytes.len() {
+            return Err(format!("Invalid memory access: {} bytes at 0x{:x}", l_iSize, l_iAddr));
+        }
+        Ok(l_iOffset as usize)
+    }
+
+    // Records the mapping that holds l_iAddr, a pointer C returned
+    fn F_vAddForeign(&mut self, l_iAddr: i64) {
+        let l_iAddr = l_iAddr as u64;
+        if l_iAddr == 0 || self.l_lForeign.iter().any(|l_cRange| (l_cRange.l_iStart..l_cRange.l_iEnd).contains(&l_iAddr)) {
+            return;
+        }
+        let Ok(l_sMaps) = std::fs::read_to_string("/proc/self/maps") else { return };
+        // 7f1c2a000000-7f1c2a021000 rw-p 00000000 00:00 0
+        for l_sLine in l_sMaps.lines() {
+            let mut l_lFields = l_sLine.split_whitespace();
+            let (Some(l_sRange), Some(l_sPerms)) = (l_lFields.next(), l_lFields.next()) else { continue };
+            let Some((l_sStart, l_sEnd)) = l_sRange.split_once('-') else { continue };
+            let (Ok(l_iStart), Ok(l_iEnd)) = (u64::from_str_radix(l_sStart, 16), u64::from_str_radix(l_sEnd, 16)) else { continue };
+            if !(l_iStart..l_iEnd).contains(&l_iAddr) {
+                continue;
+            }
+            if l_sPerms.starts_with('r') {
+                let l_bWritable = l_sPerms.as_bytes().get(1) == Some(&b'w');
+                // A heap that grew since it was recorded replaces the old range
+                self.l_lForeign.retain(|l_cRange| l_cRange.l_iStart != l_iStart);
+                self.l_lForeign.push(CForeignRange { l_iStart, l_iEnd, l_bWritable });
+            }
+            return;
+        }
+    }
+
+    // Whether l_iSize bytes at l_iAddr lie in memory C handed out
+    fn F_bForeign(&self, l_iAddr: i64, l_iSize: usize, l_bWrite: bool) -> bool {
+        let l_iAddr = l_iAddr as u64;
+        let Some(l_iEnd) = l_iAddr.checked_add(l_iSize as u64) else { return false };
+        self.l_lForeign.iter().any(|l_cRange| {
+            l_cRange.l_iStart <= l_iAddr && l_iEnd <= l_cRange.l_iEnd && (l_cRange.l_bWritable || !l_bWrite)
+        })
+    }
+
+    // Loads are zero-extended, like movzx in the compiled code
+    fn F_iLoad(&self, l_iAddr: i64, l_iSize: usize) -> Result<i64, String> {
+        let mut l_lBuf = [0u8; 8];
+        l_lBuf[..l_iSize].copy_from_slice(self.F_lSlice(l_iAddr, l_iSize)?);
+        Ok(i64::from_le_bytes(l_lBuf))
+    }
+
+    // Stores keep the low l_iSize bytes of the value
+    fn F_vStore(&mut self, l_iAddr: i64, l_iSize: usize, l_iVal: i64) -> Result<(), String> {
+        let l_lVal = &l_iVal.to_le_bytes()[..l_iSize];
+        match self.F_iOffset(l_iAddr, l_iSize) {
+            Ok(l_iOffset) => self.l_lBytes[l_iOffset..l_iOffset + l_iSize].copy_from_slice(l_lVal),
+            Err(_) if self.F_bForeign(l_iAddr, l_iSize, true) => {
+                // SAFETY: the bytes lie in a writable mapping of the process,
+                // in memory C gave the program
+                unsafe { std::ptr::copy_nonoverlapping(l_lVal.as_ptr(), l_iAddr as *mut u8, l_iSize) };
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    // Stores l_lStrings NUL-terminated and returns the address of a
+    // NULL-terminated array of pointers to them, like argv and envp
+    fn F_iAllocCStrings(&mut self, l_lStrings: &[Vec<u8>]) -> Result<i64, String> {
+        let l_iArray = self.F_iAlloc((l_lStrings.len() + 1) * 8)?;
+        for (l_iIdx, l_lBytes) in l_lStrings.iter().enumerate() {
+            let l_iAddr = self.F_iAlloc(l_lBytes.len() + 1)?;
+            for (l_iOffset, l_iByte) in l_lBytes.iter().enumerate() {
+                self.F_vStore(l_iAddr + l_iOffset as i64, 1, *l_iByte as i64)?;
+            }
+            self.F_vStore(l_iArray + l_iIdx as i64 * 8, 8, l_iAddr)?;
+        }
+        Ok(l_iArray)
+    }
+
+    // Bytes taken by F_iAllocCStrings
+    fn F_iCStringsSize(l_lStrings: &[Vec<u8>]) -> usize {
+        (l_lStrings.len() + 1) * 8 + l_lStrings.iter().map(|l_lBytes| (l_lBytes.len() + 8) & !7).sum::<usize>()
+    }
+
+    fn F_lSlice(&self, l_iAddr: i64, l_iLen: usize) -> Result<&[u8], String> {
+        match self.F_iOffset(l_iAddr, l_iLen) {
+            Ok(l_iOffset) => Ok(&self.l_lBytes[l_iOffset..l_iOffset + l_iLen]),
+            // SAFETY: the bytes lie in a readable mapping of the process, in
+            // memory C gave the program
+            Err(_) if self.F_bForeign(l_iAddr, l_iLen, false) => Ok(unsafe {
+                std::slice::from_raw_parts(l_iAddr as *const u8, l_iLen)
+            }),
+            Err(e) => Err(e),
+        }
+    }
+}
 
-impl CStackToInterpreter {
-    fn F_vInterpret(l_lIr: &[EIrInstr]) -> Result<(), String> {
+// The calls leading to the failing instruction of an error trace, as
+// (proc, instruction index, times): the same call repeated by a recursion
+// is listed once
+fn F_lCallers(l_lTrace: &[(&'static str, usize)]) -> Vec<(&'static str, usize, usize)> {
+    let mut l_lCallers: Vec<(&'static str, usize, usize)> = Vec::new();
+    for &(l_sName, l_iPc) in l_lTrace.iter().skip(1) {
+        match l_lCallers.last_mut() {
+            Some((l_sLast, l_iLastPc, l_iTimes)) if *l_sLast == l_sName && *l_iLastPc == l_iPc => *l_iTimes += 1,
+            _ => l_lCallers.push((l_sName, l_iPc, 1)),
+        }
+    }
+    l_lCallers
+}
+
+// Suffix of a note for a call repeated l_iTimes times
+fn F_sTimes(l_iTimes: usize) -> String {
+    match l_iTimes {
+        1 => String::new(),
+        _ => format!(" ({} times)", l_iTimes),
+    }
+}
+
+struct CStackToInterpreter<'a> {
+    l_hmProcs: HashMap<&'static str, &'a Vec<EIrInstr>>,
+    l_hmLabels: HashMap<usize, usize>,
+    // Every string literal and its address in l_cMemory, keyed by the literal's
+    // own pointer so each occurrence gets its own copy, like the compiled str_N labels
+    l_hmStrings: HashMap<usize, (&'static str, i64)>,
+    l_hmMemories: HashMap<&'static str, i64>,
+    // (params, rets) of the C functions declared by `extern`
+    l_hmExterns: HashMap<&'static str, (&'a [EType], &'a [EType])>,
+    l_cMemory: CMemory,
+    // argc, and the addresses of the argv and envp arrays in l_cMemory with
+    // the number of variables
+    l_iArgc: i64,
+    l_iArgv: i64,
+    l_iEnvp: i64,
+    l_iEnvc: i64,
+    // Compile-time evaluation: anything with a side effect is rejected, and
+    // so is running for more than CONST_EVAL_STEPS instructions
+    l_bConstEval: bool,
+    l_iSteps: usize,
+    // (proc, instruction index) of the instruction that failed, then of the
+    // calls that led to it, innermost first
+    l_lErrorTrace: Vec<(&'static str, usize)>,
+    // Procs being run, bounded like the compiled proc stack; the proc that
+    // would have gone past PROC_STACK_CELLS
+    l_iDepth: usize,
+    l_oProcOverflow: Option<&'static str>,
+}
+
+impl<'a> CStackToInterpreter<'a> {
+    // Runs main with l_lArgs as its argv and returns its exit status. A
+    // runtime error is located at the failing instruction, with a note for
+    // each call leading to it
+    fn F_iInterpret(l_lIr: &'a [EIrInstr], l_cSourceMap: &CSourceMap, l_lArgs: &[String]) -> Result<i64, CDiagnostic> {
+        let mut l_lDataStack: Vec<i64> = Vec::new();
+        let mut l_cInterp = Self::F_cNew(l_lIr, false, l_lArgs)
+            .map_err(|e| CDiagnostic::F_cError("E0201", e))?;
+
+        if !l_cInterp.l_hmProcs.contains_key("main") {
+            return Err(CDiagnostic::F_cError("E0202", "no `main` proc declared"));
+        }
+
+        let l_rMain = l_cInterp.F_vExecuteProc("main", &mut l_lDataStack);
+        if let Some(l_sProc) = l_cInterp.l_oProcOverflow {
+            // Reported and ended like a failed --check-stack check, after
+            // the output written so far
+            let _ = std::io::stdout().flush();
+            if !l_cInterp.l_hmExterns.is_empty() {
+                unsafe { libc::fflush(std::ptr::null_mut()) };
+            }
+            eprintln!("{}{}", engine::StackAsmBuilder::stack_message("proc stack overflow"), l_sProc);
+            return Ok(engine::STACK_ERROR_EXIT as i64);
+        }
+        l_rMain.map_err(|e| {
+            let (l_sName, l_iPc) = l_cInterp.l_lErrorTrace[0];
+            let mut l_cDiagnostic = CDiagnostic::F_cError("E0201", e).F_cAt(l_cSourceMap.F_oSpan(l_sName, Some(l_iPc)));
+            for (l_sName, l_iPc, l_iTimes) in F_lCallers(&l_cInterp.l_lErrorTrace) {
+                if let Some(l_cSpan) = l_cSourceMap.F_oSpan(l_sName, Some(l_iPc)) {
+                    let (l_iLine, l_iCol) = l_cSourceMap.F_tLineCol(l_cSpan);
+                    l_cDiagnostic = l_cDiagnostic.F_cNote(format!(
+                        "called from proc {} at {}:{}:{}{}", l_sName, l_cSourceMap.l_sFile, l_iLine, l_iCol, F_sTimes(l_iTimes)
+                    ));
+                }
+            }
+            l_cDiagnostic
+        })?;
+        // The checker allows main to leave nothing (status 0) or its exit status
+        Ok(l_lDataStack.last().copied().unwrap_or(0))
+    }
+
+    // Evaluates a constant expression of the program (e.g. a memory size)
+    // to exactly one value
+    fn F_iEvalConst(l_lIr: &'a [EIrInstr], l_lExpr: &'a [EIrInstr]) -> Result<i64, String> {
         let mut l_lDataStack: Vec<i64> = Vec::new();
+        let mut l_cInterp = Self::F_cNew(l_lIr, true, &[])?;
+        l_cInterp.F_vExecuteBody("", l_lExpr, &mut l_lDataStack)?;
+        match l_lDataStack.as_slice() {
+            [l_iVal] => Ok(*l_iVal),
+            _ => Err(format!("constant expression must leave exactly one value, got {}", l_lDataStack.len())),
+        }
+    }
+
+    fn F_iMemorySize(l_lIr: &'a [EIrInstr], l_sName: &str, l_lExpr: &'a [EIrInstr]) -> Result<usize, String> {
+        let l_iSize = Self::F_iEvalConst(l_lIr, l_lExpr)
+            .map_err(|e| format!("memory {}: {}", l_sName, e))?;
+        match usize::try_from(l_iSize) {
+            Ok(l_iSize) if l_iSize <= MEMORY_SIZE_MAX => Ok(l_iSize),
+            Ok(_) => Err(format!("memory {}: size {} is larger than the {} bytes a memory can hold", l_sName, l_iSize, MEMORY_SIZE_MAX)),
+            Err(_) => Err(format!("memory {}: negative size {}", l_sName, l_iSize)),
+        }
+    }
+
+    // l_lArgs (the program's argv) and the host's environment are only
+    // laid out outside of compile-time evaluation
+    fn F_cNew(l_lIr: &'a [EIrInstr], l_bConstEval: bool, l_lArgs: &[String]) -> Result<Self, String> {
         let mut l_hmProcs: HashMap<&'static str, &Vec<EIrInstr>> = HashMap::new();
-        let mut l_hmInfoProc: HashMap<&'static str, EIrInstr> = HashMap::new();
-        let mut l_bHasMain = false;
+        let mut l_hmLabels: HashMap<usize, usize> = HashMap::new();
+        let mut l_lMemories: Vec<(&'static str, usize)> = Vec::new();
+        let mut l_lStrings: Vec<&'static str> = Vec::new();
+        let mut l_hmExterns = HashMap::new();
 
         for l_cInstr in l_lIr {
-            if let EIrInstr::Proc(l_sName, l_lBody, l_lTypes, l_eRetType) = l_cInstr {
-                if *l_sName == "main" { l_bHasMain = true; }
-                l_hmProcs.insert(l_sName, l_lBody);
-                l_hmInfoProc.insert(l_sName, l_cInstr.clone());
-            } else if let EIrInstr::Const(l_sName, l_lBody) = l_cInstr {
-                l_hmProcs.insert(l_sName, l_lBody);
-                l_hmInfoProc.insert(l_sName, EIrInstr::Proc(l_sName, l_lBody.clone(), vec![EType::Void], EType::Void));
+            let l_lBody = match l_cInstr {
+                EIrInstr::Extern(l_sName, l_lParams, l_lRets) => {
+                    l_hmExterns.insert(*l_sName, (l_lParams.as_slice(), l_lRets.as_slice()));
+                    continue;
+                }
+                EIrInstr::Proc(l_sName, l_lBody, _, _) => {
+                    l_hmProcs.insert(l_sName, l_lBody);
+                    l_lBody
+                }
+                EIrInstr::Const(l_sName, l_lBody) => {
+                    l_hmProcs.insert(l_sName, l_lBody);
+                    l_lBody
+                }
+                EIrInstr::Memory(l_sName, l_lBody) => {
+                    // Sizes are themselves evaluated by a const interpreter
+                    if !l_bConstEval {
+                        l_lMemories.push((l_sName, Self::F_iMemorySize(l_lIr, l_sName, l_lBody)?));
+                    }
+                    l_lBody
+                }
+                _ => continue,
+            };
+
+            // Label ids are unique program-wide: map each one to its index in its body
+            for (l_iPc, l_cBodyInstr) in l_lBody.iter().enumerate() {
+                match l_cBodyInstr {
+                    EIrInstr::Label(l_iLabel) => { l_hmLabels.insert(*l_iLabel, l_iPc); }
+                    EIrInstr::PushStr(l_sStr) => l_lStrings.push(l_sStr),
+                    _ => {}
+                }
             }
         }
 
-        if !l_bHasMain { return Err("Erreur: proc main non declaree".to_string()); }
+        let l_lArgv: Vec<Vec<u8>> = l_lArgs.iter().map(|l_sArg| l_sArg.as_bytes().to_vec()).collect();
+        let l_lEnvp: Vec<Vec<u8>> = match l_bConstEval {
+            true => Vec::new(),
+            false => std::env::vars_os()
+                .map(|(l_sKey, l_sValue)| [l_sKey.into_vec(), b"=".to_vec(), l_sValue.into_vec()].concat())
+                .collect(),
+        };
 
-        Self::F_vExecuteProc("main", &l_hmProcs, &l_hmInfoProc, &mut l_lDataStack, true)?;
-        Ok(())
+        // Lay out the string literals (NUL-terminated like in .data), the
+        // zero-initialised memory regions, then argv and envp
+        let l_iMemSize = l_lStrings.iter().map(|l_sStr| (l_sStr.len() + 8) & !7).sum::<usize>()
+            .saturating_add(l_lMemories.iter().map(|(_, l_iSize)| (l_iSize + 7) & !7).fold(0, usize::saturating_add))
+            .saturating_add(CMemory::F_iCStringsSize(&l_lArgv) + CMemory::F_iCStringsSize(&l_lEnvp));
+        let mut l_cMemory = CMemory::F_cNew(l_iMemSize)?;
+        let mut l_hmStrings = HashMap::new();
+        for l_sStr in l_lStrings {
+            let l_iAddr = l_cMemory.F_iAlloc(l_sStr.len() + 1)?;
+            for (l_iIdx, l_iByte) in l_sStr.bytes().enumerate() {
+                l_cMemory.F_vStore(l_iAddr + l_iIdx as i64, 1, l_iByte as i64)?;
+            }
+            l_hmStrings.insert(l_sStr.as_ptr() as usize, (l_sStr, l_iAddr));
+        }
+        let mut l_hmMemories = HashMap::new();
+        for (l_sName, l_iSize) in l_lMemories {
+            l_hmMemories.insert(l_sName, l_cMemory.F_iAlloc(l_iSize)?);
+        }
+        let l_iArgv = l_cMemory.F_iAllocCStrings(&l_lArgv)?;
+        let l_iEnvp = l_cMemory.F_iAllocCStrings(&l_lEnvp)?;
+
+        Ok(CStackToInterpreter {
+            l_hmProcs, l_hmLabels, l_hmStrings, l_hmMemories, l_hmExterns, l_cMemory,
+            l_iArgc: l_lArgv.len() as i64, l_iArgv, l_iEnvp, l_iEnvc: l_lEnvp.len() as i64, l_bConstEval, l_iSteps: 0,
+            l_lErrorTrace: Vec::new(), l_iDepth: 0, l_oProcOverflow: None,
+        })
     }
 
     fn F_vExecuteProc(
+        &mut self,
         l_sName: &str,
-        l_hmProcs: &HashMap<&'static str, &Vec<EIrInstr>>,
-        l_hmInfo: &HashMap<&'static str, EIrInstr>,
-        l_lDataStack: &mut Vec<i64>,
-        l_bIsMain: bool
+        l_lDataStack: &mut Vec<i64>
     ) -> Result<(), String> {
-        let l_lBody = l_hmProcs.get(l_sName)
-            .ok_or_else(|| format!("Proc {} non trouvee", l_sName))?;
+        let (l_sName, l_lBody): (&&'static str, &&'a Vec<EIrInstr>) = self.l_hmProcs.get_key_value(l_sName)
+            .ok_or_else(|| format!("undefined proc {}", l_sName))?;
+        // Each call takes a cell of the compiled proc stack, main's included
+        if self.l_iDepth == engine::PROC_STACK_CELLS {
+            self.l_oProcOverflow = Some(l_sName);
+            return Err(format!("proc stack overflow in proc {}", l_sName));
+        }
+        self.l_iDepth += 1;
+        let l_rResult = self.F_vExecuteBody(l_sName, l_lBody, l_lDataStack);
+        self.l_iDepth -= 1;
+        l_rResult
+    }
 
-        let l_iStackBase = l_lDataStack.len();
+    // Runs the body of l_sName; on error the failing instruction (or call) is
+    // appended to l_lErrorTrace
+    fn F_vExecuteBody(
+        &mut self,
+        l_sName: &'static str,
+        l_lBody: &'a [EIrInstr],
+        l_lDataStack: &mut Vec<i64>
+    ) -> Result<(), String> {
+        let mut l_iPc = 0;
+        let l_rResult = self.F_vRunBody(l_lBody, l_lDataStack, &mut l_iPc);
+        if l_rResult.is_err() {
+            self.l_lErrorTrace.push((l_sName, l_iPc - 1));
+        }
+        l_rResult
+    }
 
-        for l_cInstr in l_lBody.iter() {
+    // l_iPc is left on the instruction after the one being executed
+    fn F_vRunBody(
+        &mut self,
+        l_lBody: &'a [EIrInstr],
+        l_lDataStack: &mut Vec<i64>,
+        l_iPc: &mut usize
+    ) -> Result<(), String> {
+        while *l_iPc < l_lBody.len() {
+            let l_cInstr = &l_lBody[*l_iPc];
+            *l_iPc += 1;
+            if self.l_bConstEval {
+                Self::F_vCheckConstInstr(l_cInstr)?;
+                self.l_iSteps += 1;
+                if self.l_iSteps > CONST_EVAL_STEPS {
+                    return Err(format!("constant expression still running after {} instructions", CONST_EVAL_STEPS));
+                }
+            }
+            if F_bIsSyscall(l_cInstr) {
+                // puts/dump go through Rust's buffered stdout: keep the order
+                // with output written directly by the syscall
+                std::io::stdout().flush().map_err(|e| e.to_string())?;
+            }
+            if !self.l_hmExterns.is_empty() && (F_bIsSyscall(l_cInstr) || matches!(l_cInstr, EIrInstr::Puts | EIrInstr::Dump)) {
+                // C output written so far goes first, like the compiled
+                // flush_stdio before its own writes
+                unsafe { libc::fflush(std::ptr::null_mut()) };
+            }
+            if matches!(l_cInstr, EIrInstr::DivI64 | EIrInstr::ModI64 | EIrInstr::DivU64 | EIrInstr::ModU64)
+                && l_lDataStack.last() == Some(&0) {
+                return Err("Division by zero".to_string());
+            }
+            // idiv faults on i64::MIN / -1 in the compiled code
+            if matches!(l_cInstr, EIrInstr::DivI64 | EIrInstr::ModI64)
+                && l_lDataStack.len() >= 2
+                && l_lDataStack[l_lDataStack.len() - 2..] == [i64::MIN, -1] {
+                return Err("Division overflow: the quotient of i64::MIN by -1 does not fit in 64 bits".to_string());
+            }
             match *l_cInstr {
                 EIrInstr::PushI64(l_iVal) => l_lDataStack.push(l_iVal),
                 EIrInstr::PushStr(l_sStr) => {
-                    l_lDataStack.push(l_sStr.as_ptr() as i64);
+                    l_lDataStack.push(self.l_hmStrings[&(l_sStr.as_ptr() as usize)].1);
                     l_lDataStack.push(l_sStr.len() as i64);
                 }
+                EIrInstr::PushMem(l_sName) => l_lDataStack.push(self.l_hmMemories[l_sName]),
                 EIrInstr::AddI64 => {
                     let l_iB = l_lDataStack.pop().ok_or("Stack underflow add")?;
                     let l_iA = l_lDataStack.pop().ok_or("Stack underflow add")?;
-                    l_lDataStack.push(l_iA + l_iB);
+                    // Two's complement wrap-around, like the compiled add
+                    l_lDataStack.push(l_iA.wrapping_add(l_iB));
                 }
                 EIrInstr::SubI64 => {
                     let l_iB = l_lDataStack.pop().ok_or("Stack underflow sub")?;
                     let l_iA = l_lDataStack.pop().ok_or("Stack underflow sub")?;
-                    l_lDataStack.push(l_iA - l_iB);
+                    l_lDataStack.push(l_iA.wrapping_sub(l_iB));
                 }
                 EIrInstr::MulI64 => {
                     let l_iB = l_lDataStack.pop().ok_or("Stack underflow mul")?;
                     let l_iA = l_lDataStack.pop().ok_or("Stack underflow mul")?;
-                    l_lDataStack.push(l_iA * l_iB);
+                    l_lDataStack.push(l_iA.wrapping_mul(l_iB));
                 }
                 EIrInstr::DivI64 => {
                     let l_iB = l_lDataStack.pop().ok_or("Stack underflow div")?;
@@ -149,6 +589,43 @@ impl CStackToInterpreter {
                     let l_iA = l_lDataStack.pop().ok_or("Stack underflow mod")?;
                     l_lDataStack.push(l_iA % l_iB);
                 }
+                EIrInstr::DivU64 => Self::F_vBinary(l_lDataStack, "udiv", |a, b| ((a as u64) / (b as u64)) as i64)?,
+                EIrInstr::ModU64 => Self::F_vBinary(l_lDataStack, "umod", |a, b| ((a as u64) % (b as u64)) as i64)?,
+                EIrInstr::EqI64 => Self::F_vBinary(l_lDataStack, "=", |a, b| (a == b) as i64)?,
+                EIrInstr::NeqI64 => Self::F_vBinary(l_lDataStack, "!=", |a, b| (a != b) as i64)?,
+                EIrInstr::LtI64 => Self::F_vBinary(l_lDataStack, "<", |a, b| (a < b) as i64)?,
+                EIrInstr::GtI64 => Self::F_vBinary(l_lDataStack, ">", |a, b| (a > b) as i64)?,
+                EIrInstr::LeI64 => Self::F_vBinary(l_lDataStack, "<=", |a, b| (a <= b) as i64)?,
+                EIrInstr::GeI64 => Self::F_vBinary(l_lDataStack, ">=", |a, b| (a >= b) as i64)?,
+                EIrInstr::LtU64 => Self::F_vBinary(l_lDataStack, "ult", |a, b| ((a as u64) < (b as u64)) as i64)?,
+                EIrInstr::GtU64 => Self::F_vBinary(l_lDataStack, "ugt", |a, b| ((a as u64) > (b as u64)) as i64)?,
+                EIrInstr::LeU64 => Self::F_vBinary(l_lDataStack, "ule", |a, b| ((a as u64) <= (b as u64)) as i64)?,
+                EIrInstr::GeU64 => Self::F_vBinary(l_lDataStack, "uge", |a, b| ((a as u64) >= (b as u64)) as i64)?,
+                EIrInstr::AndI64 => Self::F_vBinary(l_lDataStack, "&", |a, b| a & b)?,
+                EIrInstr::OrI64 => Self::F_vBinary(l_lDataStack, "|", |a, b| a | b)?,
+                EIrInstr::XorI64 => Self::F_vBinary(l_lDataStack, "^", |a, b| a ^ b)?,
+                // Shift counts are masked to 6 bits, like the x86 shift instructions
+                EIrInstr::ShlI64 => Self::F_vBinary(l_lDataStack, "<<", |a, b| a.wrapping_shl(b as u32))?,
+                EIrInstr::ShrU64 => Self::F_vBinary(l_lDataStack, ">>", |a, b| (a as u64).wrapping_shr(b as u32) as i64)?,
+                EIrInstr::SarI64 => Self::F_vBinary(l_lDataStack, "sar", |a, b| a.wrapping_shr(b as u32))?,
+                EIrInstr::NotI64 => {
+                    let l_iA = l_lDataStack.pop().ok_or("Stack underflow ~")?;
+                    l_lDataStack.push(!l_iA);
+                }
+                EIrInstr::Drop => {
+                    l_lDataStack.pop().ok_or("Stack underflow drop")?;
+                }
+                EIrInstr::Over => {
+                    let l_iLen = l_lDataStack.len();
+                    if l_iLen < 2 { return Err("Stack underflow over".to_string()); }
+                    l_lDataStack.push(l_lDataStack[l_iLen - 2]);
+                }
+                EIrInstr::Rot => {
+                    // a b c -> b c a
+                    let l_iLen = l_lDataStack.len();
+                    if l_iLen < 3 { return Err("Stack underflow rot".to_string()); }
+                    l_lDataStack[l_iLen - 3..].rotate_left(1);
+                }
                 EIrInstr::Swap => {
                     let l_iB = l_lDataStack.pop().ok_or("Stack underflow swap")?;
                     let l_iA = l_lDataStack.pop().ok_or("Stack underflow swap")?;
@@ -161,32 +638,61 @@ impl CStackToInterpreter {
                 }
                 EIrInstr::Puts => {
                     let l_iLen = l_lDataStack.pop().ok_or("Stack underflow puts")? as usize;
-                    let l_pBuf = l_lDataStack.pop().ok_or("Stack underflow puts")? as *const u8;
-                    let l_sStr = unsafe {
-                        std::str::from_utf8_unchecked(std::slice::from_raw_parts(l_pBuf, l_iLen))
-                    };
-                    print!("{}", l_sStr);
+                    let l_iBuf = l_lDataStack.pop().ok_or("Stack underflow puts")?;
+                    let l_lBytes = self.l_cMemory.F_lSlice(l_iBuf, l_iLen)?;
+                    // Raw bytes, like write(1, ptr, len) in the compiled code
+                    let mut l_cStdout = std::io::stdout();
+                    l_cStdout.write_all(l_lBytes).map_err(|e| e.to_string())?;
+                }
+                EIrInstr::Load8 | EIrInstr::Load16 | EIrInstr::Load32 | EIrInstr::Load64 => {
+                    let l_iSize = F_iAccessSize(l_cInstr);
+                    let l_iAddr = l_lDataStack.pop().ok_or("Stack underflow load")?;
+                    l_lDataStack.push(self.l_cMemory.F_iLoad(l_iAddr, l_iSize)?);
+                }
+                EIrInstr::Store8 | EIrInstr::Store16 | EIrInstr::Store32 | EIrInstr::Store64 => {
+                    let l_iSize = F_iAccessSize(l_cInstr);
+                    let l_iVal = l_lDataStack.pop().ok_or("Stack underflow store")?;
+                    let l_iAddr = l_lDataStack.pop().ok_or("Stack underflow store")?;
+                    self.l_cMemory.F_vStore(l_iAddr, l_iSize, l_iVal)?;
                 }
                 EIrInstr::Dump => {
                     let l_iVal = l_lDataStack.pop().ok_or("Stack underflow dump")?;
                     print!("{}", l_iVal);
                 }
-                EIrInstr::Call(l_sTarget) => {
-                    Self::F_vExecuteProc(l_sTarget, l_hmProcs, l_hmInfo, l_lDataStack, false)?;
-                }
-                EIrInstr::Ret => {
-                    if !l_bIsMain {
-                        if l_lDataStack.len() > l_iStackBase {
-                            // Procedure returns a value
-                            let l_iResult = l_lDataStack.pop().ok_or("Stack underflow ret")?;
-                            l_lDataStack.truncate(l_iStackBase);
-                            l_lDataStack.push(l_iResult);
-                        } else {
-                            // Procedure returns Void (stack is at base level)
-                            l_lDataStack.truncate(l_iStackBase);
-                        }
+                EIrInstr::Label(_) => {}
+                EIrInstr::Jump(l_iLabel) => {
+                    *l_iPc = self.l_hmLabels[&l_iLabel];
+                }
+                EIrInstr::JumpIfZero(l_iLabel) => {
+                    let l_iCond = l_lDataStack.pop().ok_or("Stack underflow if")?;
+                    if l_iCond == 0 {
+                        *l_iPc = self.l_hmLabels[&l_iLabel];
                     }
-                    return Ok(());
+                }
+                EIrInstr::Call(l_sTarget) => {
+                    self.F_vExecuteProc(l_sTarget, l_lDataStack)?;
+                }
+                EIrInstr::CallExtern(l_sTarget) => self.F_vCallExtern(l_sTarget, l_lDataStack)?,
+                // The body has replaced its parameters by its results, which
+                // the checker verified against the declaration: nothing to undo
+                EIrInstr::Ret => return Ok(()),
+                EIrInstr::Argc => l_lDataStack.push(self.l_iArgc),
+                EIrInstr::Argv | EIrInstr::Envp => {
+                    let (l_iArray, l_iCount, l_sOp) = match l_cInstr {
+                        EIrInstr::Argv => (self.l_iArgv, self.l_iArgc, "argv"),
+                        _ => (self.l_iEnvp, self.l_iEnvc, "envp"),
+                    };
+                    let l_iIndex = l_lDataStack.pop().ok_or_else(|| format!("Stack underflow {}", l_sOp))?;
+                    // Out of the array, the NULL that ends it, like the compiled code
+                    let l_iIndex = if (0..=l_iCount).contains(&l_iIndex) { l_iIndex } else { l_iCount };
+                    l_lDataStack.push(self.l_cMemory.F_iLoad(l_iArray + l_iIndex * 8, 8)?);
+                }
+                EIrInstr::Exit => {
+                    let l_iStatus = l_lDataStack.pop().ok_or("Stack underflow exit")?;
+                    std::io::stdout().flush().map_err(|e| e.to_string())?;
+                    unsafe { libc::fflush(std::ptr::null_mut()) };
+                    // Truncated to 8 bits by the kernel, like the compiled exit
+                    std::process::exit(l_iStatus as i32);
                 }
                 EIrInstr::Syscall0 => {
                     let l_iSysno = l_lDataStack.pop().ok_or("Stack underflow syscall0")? as libc::c_long;
@@ -250,64 +756,417 @@ impl CStackToInterpreter {
         Ok(())
     }
 
+    fn F_vCheckConstInstr(l_cInstr: &EIrInstr) -> Result<(), String> {
+        let l_sWhat = match l_cInstr {
+            l_cSyscall if F_bIsSyscall(l_cSyscall) => "syscall",
+            EIrInstr::Store8 | EIrInstr::Store16 | EIrInstr::Store32 | EIrInstr::Store64 => "memory write",
+            EIrInstr::Puts | EIrInstr::Dump => "output",
+            EIrInstr::PushMem(_) => "memory address",
+            EIrInstr::Argc | EIrInstr::Argv | EIrInstr::Envp => "program argument",
+            EIrInstr::Exit => "exit",
+            EIrInstr::CallExtern(_) => "C function call",
+            _ => return Ok(()),
+        };
+        Err(format!("{} is not allowed in a constant expression ({:?})", l_sWhat, l_cInstr))
+    }
+
+    // Calls a C function of the host process, found by name like the dynamic
+    // linker does for the compiled program. Arguments are the parameter cells
+    // (last one on top) and the result is extended from its C type. Output of
+    // puts/dump still buffered is written first, so the order matches the
+    // compiled code.
+    fn F_vCallExtern(&mut self, l_sName: &str, l_lDataStack: &mut Vec<i64>) -> Result<(), String> {
+        type FExtern0 = unsafe extern "C" fn() -> i64;
+        type FExtern1 = unsafe extern "C" fn(i64) -> i64;
+        type FExtern2 = unsafe extern "C" fn(i64, i64) -> i64;
+        type FExtern3 = unsafe extern "C" fn(i64, i64, i64) -> i64;
+        type FExtern4 = unsafe extern "C" fn(i64, i64, i64, i64) -> i64;
+        type FExtern5 = unsafe extern "C" fn(i64, i64, i64, i64, i64) -> i64;
+        type FExtern6 = unsafe extern "C" fn(i64, i64, i64, i64, i64, i64) -> i64;
+
+        let (l_lParams, l_lRets) = self.l_hmExterns[l_sName];
+        let l_iCells: usize = l_lParams.iter().map(|l_eType| if *l_eType == EType::Str { 2 } else { 1 }).sum();
+        if l_lDataStack.len() < l_iCells {
+            return Err(format!("Stack underflow {}", l_sName));
+        }
+        let l_lArgs = l_lDataStack.split_off(l_lDataStack.len() - l_iCells);
+
+        let l_sSymbol = std::ffi::CString::new(l_sName).map_err(|e| e.to_string())?;
+        let l_pFunc = unsafe { libc::dlsym(libc::RTLD_DEFAULT, l_sSymbol.as_ptr()) };
+        if l_pFunc.is_null() {
+            return Err(format!("undefined C function {}", l_sName));
+        }
+
+        std::io::stdout().flush().map_err(|e| e.to_string())?;
+        // SAFETY: the signature comes from the `extern` declaration; like in
+        // the compiled code, a wrong one is undefined behaviour
+        let l_iRet = unsafe {
+            match *l_lArgs.as_slice() {
+                [] => std::mem::transmute::<*mut libc::c_void, FExtern0>(l_pFunc)(),
+                [a] => std::mem::transmute::<*mut libc::c_void, FExtern1>(l_pFunc)(a),
+                [a, b] => std::mem::transmute::<*mut libc::c_void, FExtern2>(l_pFunc)(a, b),
+                [a, b, c] => std::mem::transmute::<*mut libc::c_void, FExtern3>(l_pFunc)(a, b, c),
+                [a, b, c, d] => std::mem::transmute::<*mut libc::c_void, FExtern4>(l_pFunc)(a, b, c, d),
+                [a, b, c, d, e] => std::mem::transmute::<*mut libc::c_void, FExtern5>(l_pFunc)(a, b, c, d, e),
+                [a, b, c, d, e, f] => std::mem::transmute::<*mut libc::c_void, FExtern6>(l_pFunc)(a, b, c, d, e, f),
+                _ => return Err(format!("{} takes more than 6 argument registers", l_sName)),
+            }
+        };
+
+        if let Some(l_eRet) = l_lRets.first() {
+            if *l_eRet == EType::Ptr {
+                self.l_cMemory.F_vAddForeign(l_iRet);
+            }
+            l_lDataStack.push(match l_eRet {
+                EType::I8 => l_iRet as i8 as i64,
+                EType::U8 | EType::Bool => l_iRet as u8 as i64,
+                EType::I16 => l_iRet as i16 as i64,
+                EType::U16 => l_iRet as u16 as i64,
+                EType::I32 => l_iRet as i32 as i64,
+                EType::U32 => l_iRet as u32 as i64,
+                _ => l_iRet,
+            });
+        }
+        Ok(())
+    }
+
+    fn F_vBinary(l_lDataStack: &mut Vec<i64>, l_sOp: &str, l_fOp: impl Fn(i64, i64) -> i64) -> Result<(), String> {
+        let l_iB = l_lDataStack.pop().ok_or_else(|| format!("Stack underflow {}", l_sOp))?;
+        let l_iA = l_lDataStack.pop().ok_or_else(|| format!("Stack underflow {}", l_sOp))?;
+        l_lDataStack.push(l_fOp(l_iA, l_iB));
+        Ok(())
+    }
+
+}
+
+fn F_bIsSyscall(l_cInstr: &EIrInstr) -> bool {
+    matches!(l_cInstr,
+        EIrInstr::Syscall0 | EIrInstr::Syscall1 | EIrInstr::Syscall2 | EIrInstr::Syscall3 |
+        EIrInstr::Syscall4 | EIrInstr::Syscall5 | EIrInstr::Syscall6)
+}
+
+// Width in bytes of a load/store instruction
+fn F_iAccessSize(l_cInstr: &EIrInstr) -> usize {
+    match l_cInstr {
+        EIrInstr::Load8 | EIrInstr::Store8 => 1,
+        EIrInstr::Load16 | EIrInstr::Store16 => 2,
+        EIrInstr::Load32 | EIrInstr::Store32 => 4,
+        _ => 8,
+    }
 }
 
 
+// Human-readable listing of the IR, one declaration header per proc/const/memory
+fn F_sFormatIr(l_lProgram: &[EIrInstr]) -> String {
+    let mut l_sOut = String::new();
+    for l_cInstr in l_lProgram {
+        let (l_sHeader, l_lBody) = match l_cInstr {
+            EIrInstr::Proc(l_sName, l_lBody, l_lTypes, l_lRetTypes) => {
+                (format!("Proc {} {:?} -> {:?}", l_sName, l_lTypes, l_lRetTypes), l_lBody)
+            }
+            EIrInstr::Const(l_sName, l_lBody) => (format!("Const {}", l_sName), l_lBody),
+            EIrInstr::Memory(l_sName, l_lBody) => (format!("Memory {}", l_sName), l_lBody),
+            EIrInstr::Extern(l_sName, l_lTypes, l_lRetTypes) => {
+                l_sOut += &format!("Extern {} {:?} -> {:?}\n", l_sName, l_lTypes, l_lRetTypes);
+                continue;
+            }
+            _ => {
+                l_sOut += &format!("{:?}\n", l_cInstr);
+                continue;
+            }
+        };
+        l_sOut += &l_sHeader;
+        l_sOut.push('\n');
+        for l_cBodyInstr in l_lBody {
+            l_sOut += &format!("    {:?}\n", l_cBodyInstr);
+        }
+    }
+    l_sOut
+}
+
+// Writes to the -o path, or to stdout without one
+fn F_vWriteOutput(l_oOutput: &Option<String>, l_sText: &str) -> Result<(), String> {
+    match l_oOutput {
+        Some(l_sPath) => std::fs::write(l_sPath, l_sText).map_err(|e| format!("cannot write {}: {}", l_sPath, e)),
+        None => {
+            print!("{}", l_sText);
+            Ok(())
+        }
+    }
+}
+
+// Assembles (and links when l_bLink) in a temporary directory. With
+// --keep-asm the assembly is written next to the output and assembled from
+// there, so that assembler errors point at a file that still exists afterwards.
+fn F_vAssembleAndLink(l_sAsm: &str, l_sOutput: &str, l_bLink: bool, l_lLibs: &[String], l_cOptions: &cli::COptions) -> Result<(), CDiagnostic> {
+    let l_cTmpDir = F_cCreateTempDir()
+        .map_err(|e| CDiagnostic::F_cError("E0302", format!("cannot create a directory in {}: {}", std::env::temp_dir().display(), e)))?;
+    // Removed whether the tools succeeded or not
+    let l_cResult = F_vRunToolchain(&l_cTmpDir, l_sAsm, l_sOutput, l_bLink, l_lLibs, l_cOptions);
+    let _ = std::fs::remove_dir_all(&l_cTmpDir);
+    l_cResult
+}
+
+// A new directory with a random name, readable by the user only. mkdtemp
+// fails instead of reusing a path that already exists, so another user
+// cannot plant files or links where the assembly and object are written.
+fn F_cCreateTempDir() -> std::io::Result<std::path::PathBuf> {
+    let l_cTemplate = std::env::temp_dir().join("eboth-XXXXXX");
+    let mut l_lTemplate = std::ffi::CString::new(l_cTemplate.into_os_string().into_vec())?.into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(l_lTemplate.as_mut_ptr().cast()) }.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    l_lTemplate.pop();
+    Ok(std::path::PathBuf::from(std::ffi::OsString::from_vec(l_lTemplate)))
+}
+
+// A program linked against libraries is a dynamic executable, loaded by the
+// glibc x86-64 dynamic linker
+fn F_vRunToolchain(
+    l_cTmpDir: &std::path::Path,
+    l_sAsm: &str,
+    l_sOutput: &str,
+    l_bLink: bool,
+    l_lLibs: &[String],
+    l_cOptions: &cli::COptions
+) -> Result<(), CDiagnostic> {
+    let l_sAsmPath = match l_cOptions.l_bKeepAsm {
+        true => format!("{}.asm", l_sOutput),
+        false => l_cTmpDir.join("out.asm").to_string_lossy().into_owned(),
+    };
+    let l_sObjPath = match l_bLink {
+        true => l_cTmpDir.join("out.o").to_string_lossy().into_owned(),
+        false => l_sOutput.to_string(),
+    };
+    std::fs::write(&l_sAsmPath, l_sAsm)
+        .map_err(|e| CDiagnostic::F_cError("E0302", format!("cannot write {}: {}", l_sAsmPath, e)))?;
+
+    F_vRunTool("assembler", "--assembler", &l_cOptions.l_sAssembler, &["-f", "elf64", "-o", &l_sObjPath, &l_sAsmPath])?;
+    if !l_bLink {
+        return Ok(());
+    }
+    let l_lLibFlags: Vec<String> = l_lLibs.iter().map(|l_sLib| format!("-l{}", l_sLib)).collect();
+    let mut l_lArgs = vec!["-o", l_sOutput, &l_sObjPath];
+    if !l_lLibs.is_empty() {
+        l_lArgs.extend(["-dynamic-linker", "/lib64/ld-linux-x86-64.so.2"]);
+        l_lArgs.extend(l_lLibFlags.iter().map(String::as_str));
+    }
+    F_vRunTool("linker", "--linker", &l_cOptions.l_sLinker, &l_lArgs)
+}
+
+// Runs one external tool; its stderr becomes the notes of the diagnostic
+fn F_vRunTool(l_sRole: &str, l_sFlag: &str, l_sTool: &str, l_lArgs: &[&str]) -> Result<(), CDiagnostic> {
+    let l_cOutput = match std::process::Command::new(l_sTool).args(l_lArgs).output() {
+        Ok(l_cOutput) => l_cOutput,
+        Err(e) => {
+            let l_cDiagnostic = CDiagnostic::F_cError("E0302", format!("cannot run {} '{}': {}", l_sRole, l_sTool, e));
+            return Err(match e.kind() {
+                std::io::ErrorKind::NotFound => l_cDiagnostic.F_cNote(format!("install {} or pass {} <cmd>", l_sTool, l_sFlag)),
+                _ => l_cDiagnostic,
+            });
+        }
+    };
+    let l_sStderr = String::from_utf8_lossy(&l_cOutput.stderr);
+    if !l_cOutput.status.success() {
+        let l_cDiagnostic = CDiagnostic::F_cError("E0302", format!("{} '{}' failed ({})", l_sRole, l_sTool, l_cOutput.status));
+        return Err(l_sStderr.lines().filter(|l_sLine| !l_sLine.trim().is_empty())
+            .fold(l_cDiagnostic, |l_cDiagnostic, l_sLine| l_cDiagnostic.F_cNote(l_sLine.trim())));
+    }
+    // Warnings of a successful run are passed through
+    eprint!("{}", l_sStderr);
+    Ok(())
+}
+
+// Encodes the assembly and writes the ELF executable (or relocatable object
+// when !l_bExecutable) without external tools
+fn F_vWriteElf(l_sAsm: &str, l_sOutput: &str, l_bExecutable: bool, l_cOptions: &cli::COptions) -> Result<(), CDiagnostic> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if l_cOptions.l_bKeepAsm {
+        let l_sAsmPath = format!("{}.asm", l_sOutput);
+        std::fs::write(&l_sAsmPath, l_sAsm)
+            .map_err(|e| CDiagnostic::F_cError("E0302", format!("cannot write {}: {}", l_sAsmPath, e)))?;
+    }
+    let l_cObject = x86::assemble(l_sAsm).map_err(|e| CDiagnostic::F_cError("E0301", format!("cannot encode the assembly: {}", e)))?;
+    let l_lElf = match l_bExecutable {
+        true => elf::write_executable(&l_cObject, "_start").map_err(|e| CDiagnostic::F_cError("E0301", format!("cannot link: {}", e)))?,
+        false => elf::write_relocatable(&l_cObject).map_err(|e| CDiagnostic::F_cError("E0301", format!("cannot write the object: {}", e)))?,
+    };
+    let l_iMode = if l_bExecutable { 0o755 } else { 0o644 };
+    std::fs::write(l_sOutput, l_lElf)
+        .and_then(|_| std::fs::set_permissions(l_sOutput, std::fs::Permissions::from_mode(l_iMode)))
+        .map_err(|e| CDiagnostic::F_cError("E0302", format!("cannot write {}: {}", l_sOutput, e)))
+}
+
+// EBOTH_<STEM>_H, from the header path (or the source file without -o)
+fn F_sHeaderGuard(l_sPath: &str) -> String {
+    let l_sStem = std::path::Path::new(l_sPath).file_stem().map(|l_sStem| l_sStem.to_string_lossy().into_owned()).unwrap_or_default();
+    let l_sStem: String = l_sStem.chars()
+        .map(|l_cChar| if l_cChar.is_ascii_alphanumeric() { l_cChar.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("EBOTH_{}_H", l_sStem)
+}
+
+// The interpreter takes a native stack frame or three per eboth call:
+// enough for PROC_STACK_CELLS nested calls, in a debug build as well. Only
+// the pages actually used are backed by memory.
+const NATIVE_STACK_SIZE: usize = 256 << 20;
+
 fn main() {
-    // get argument filename
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <source_file>", args[0]);
-        return;
-    }
-
-    let l_sFilename = &args[1];
-    let l_sCode = std::fs::read_to_string(l_sFilename)
-        .expect("Erreur lecture fichier");
-
-    match F_lParseProgram(l_sCode.as_str()) {
-        Ok(l_lProgram) => {
-            println!("Programme parsé!");
-
-            //=== IR ===
-            println!("\n=== IR ===");
-            for l_cInstr in &l_lProgram {
-                // si l'instruction est une proc/const, afficher son nom et son corps
-                match l_cInstr {
-                    EIrInstr::Proc(l_sName, l_lBody, l_lTypes, l_eRetType) => {
-                        //proc name (param)
-                        print!("Proc {} (", l_sName);
-                        for (i, l_cType) in l_lTypes.iter().enumerate() {
-                            if i > 0 {
-                                print!(", ");
-                            }
-                            print!("{:?}", l_cType);
-                        }
-                        println!(") -> {:?}", l_eRetType);
-                        for l_cBodyInstr in l_lBody {
-                            println!("    {:?}", l_cBodyInstr);
-                        }
-                    },
-                    _ => {
-                        println!("{:?}", l_cInstr);
-                    }
+    let l_cThread = std::thread::Builder::new()
+        .stack_size(NATIVE_STACK_SIZE)
+        .spawn(F_vMain)
+        .unwrap_or_else(|e| {
+            eprintln!("error: cannot start: {}", e);
+            std::process::exit(1);
+        });
+    // A panic has already been reported by the thread
+    if l_cThread.join().is_err() {
+        std::process::exit(101);
+    }
+}
+
+fn F_vMain() {
+    let l_lArgs: Vec<String> = std::env::args().skip(1).collect();
+    let l_cOptions = match cli::F_oParseArgs(&l_lArgs) {
+        Ok(Some(l_cOptions)) => l_cOptions,
+        Ok(None) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n{}\nTry 'eboth --help' for more information.", e, cli::USAGE.lines().next().unwrap());
+            std::process::exit(2);
+        }
+    };
+
+    let l_sCode = match std::fs::read_to_string(&l_cOptions.l_sInput) {
+        Ok(l_sCode) => l_sCode,
+        Err(e) => {
+            eprintln!("error: cannot read {}: {}", l_cOptions.l_sInput, e);
+            std::process::exit(1);
+        }
+    };
+    let mut l_cSourceMap = CSourceMap::F_cNew(&l_cOptions.l_sInput, &l_sCode);
+    let F_vFail = |l_lDiagnostics: &[CDiagnostic], l_cSourceMap: &CSourceMap| -> ! {
+        let _ = std::io::stdout().flush();
+        F_vEmitDiagnostics(l_lDiagnostics, l_cSourceMap, l_cOptions.l_eFormat);
+        std::process::exit(1);
+    };
+    let F_vFailWith = |l_sMessage: String| -> ! {
+        eprintln!("error: {}", l_sMessage);
+        std::process::exit(1);
+    };
+
+    let l_lProgram = match F_lParseProgram(l_sCode.as_str(), &mut l_cSourceMap) {
+        Ok(l_lProgram) => l_lProgram,
+        Err(l_lDiagnostics) => F_vFail(&l_lDiagnostics, &l_cSourceMap),
+    };
+    let l_hmEffects = match F_vCheckProgram(&l_lProgram, &l_cSourceMap) {
+        Ok(l_hmEffects) => l_hmEffects,
+        Err(l_lDiagnostics) => F_vFail(&l_lDiagnostics, &l_cSourceMap),
+    };
+    // Every command works on the program with its consts evaluated
+    let l_lProgram = match consteval::F_lFoldConsts(&l_lProgram, &l_hmEffects, &mut l_cSourceMap) {
+        Ok(l_lProgram) => l_lProgram,
+        Err(l_lDiagnostics) => F_vFail(&l_lDiagnostics, &l_cSourceMap),
+    };
+
+    match l_cOptions.l_eCommand {
+        ECommand::Check => {
+            if !l_cOptions.l_bQuiet {
+                println!("{}: ok", l_cOptions.l_sInput);
+            }
+        }
+        ECommand::EmitIr => {
+            F_vWriteOutput(&l_cOptions.l_oOutput, &F_sFormatIr(&l_lProgram)).unwrap_or_else(|e| F_vFailWith(e));
+        }
+        ECommand::Run => {
+            // argv[0] is the source file, where a compiled program gets its own path
+            let l_lArgv: Vec<String> = std::iter::once(l_cOptions.l_sInput.clone())
+                .chain(l_cOptions.l_lProgramArgs.iter().cloned())
+                .collect();
+            match CStackToInterpreter::F_iInterpret(&l_lProgram, &l_cSourceMap, &l_lArgv) {
+                Ok(l_iStatus) => {
+                    let _ = std::io::stdout().flush();
+                    std::process::exit(l_iStatus as i32);
+                }
+                Err(l_cDiagnostic) => F_vFail(&[l_cDiagnostic], &l_cSourceMap),
+            }
+        }
+        ECommand::EmitHeader => {
+            let l_sGuard = F_sHeaderGuard(l_cOptions.l_oOutput.as_deref().unwrap_or(&l_cOptions.l_sInput));
+            F_vWriteOutput(&l_cOptions.l_oOutput, &StackCompiler::c_header(&l_lProgram, &l_sGuard)).unwrap_or_else(|e| F_vFailWith(e));
+        }
+        ECommand::EmitAsm | ECommand::Build | ECommand::EmitObj => {
+            let l_bExecutable = l_cOptions.l_eCommand != ECommand::EmitObj;
+            // -O0 compiles every instruction as written, to compare with -O1
+            let (l_lProgram, l_cInlined) = match l_cOptions.l_iOptLevel {
+                0 => (l_lProgram, inliner::CInlineReport { l_lInlined: Vec::new(), l_lKept: Vec::new() }),
+                _ => inliner::F_tInlineProgram(&l_lProgram),
+            };
+            let l_cCompileOptions = CompileOptions {
+                opt_level: l_cOptions.l_iOptLevel,
+                check_stack: l_cOptions.l_bCheckStack,
+                data_stack_cells: l_cOptions.l_iDataStack,
+                proc_stack_cells: l_cOptions.l_iProcStack,
+            };
+            let l_rAsm = match l_bExecutable {
+                true => StackCompiler::compile(&l_lProgram, &l_cCompileOptions),
+                false => StackCompiler::compile_object(&l_lProgram, &l_cCompileOptions),
+            };
+            let l_sAsm = match l_rAsm {
+                Ok(l_sAsm) => l_sAsm,
+                Err(l_cDiagnostic) => F_vFail(&[l_cDiagnostic], &l_cSourceMap),
+            };
+            // On stderr: emit-asm may be writing the assembly to stdout
+            if l_cOptions.l_bVerbose {
+                for (l_sName, l_iSites) in &l_cInlined.l_lInlined {
+                    eprintln!("{}: inlined {} at {} call site(s)", l_cOptions.l_sInput, l_sName, l_iSites);
+                }
+                for (l_sName, l_sReason) in &l_cInlined.l_lKept {
+                    eprintln!("{}: inline proc {} is still called: {}", l_cOptions.l_sInput, l_sName, l_sReason);
                 }
+                let l_cDead = StackCompiler::dead_code(&l_lProgram, l_bExecutable);
+                for l_sProc in l_cDead.procs {
+                    eprintln!("{}: removed unreachable proc {}", l_cOptions.l_sInput, l_sProc);
+                }
+                for l_sConst in l_cDead.consts {
+                    eprintln!("{}: removed unreachable const {}", l_cOptions.l_sInput, l_sConst);
+                }
+                for l_sHelper in l_cDead.helpers {
+                    eprintln!("{}: removed unused helper {}", l_cOptions.l_sInput, l_sHelper);
+                }
+            }
+            if l_cOptions.l_eCommand == ECommand::EmitAsm {
+                F_vWriteOutput(&l_cOptions.l_oOutput, &l_sAsm).unwrap_or_else(|e| F_vFailWith(e));
+                return;
+            }
+            // Default output: the source file without its extension (with .o for objects)
+            let l_sOutput = l_cOptions.l_oOutput.clone().unwrap_or_else(|| {
+                let l_sExtension = if l_bExecutable { "" } else { "o" };
+                std::path::Path::new(&l_cOptions.l_sInput).with_extension(l_sExtension).to_string_lossy().into_owned()
+            });
+            // libc as soon as C functions are called, then the -l libraries
+            let mut l_lLibs = l_cOptions.l_lLibs.clone();
+            if l_lProgram.iter().any(|l_cInstr| matches!(l_cInstr, EIrInstr::Extern(..))) && !l_lLibs.iter().any(|l_sLib| l_sLib == "c") {
+                l_lLibs.insert(0, "c".to_string());
             }
-            
-            println!("=== INTERPRETATION ===");
-            match CStackToInterpreter::F_vInterpret(&l_lProgram) {
-                Ok(_) => {},
-                Err(e) => eprintln!("Erreur: {}", e),
+            let l_cResult = match l_cOptions.l_eBackend {
+                EBackend::Nasm => F_vAssembleAndLink(&l_sAsm, &l_sOutput, l_bExecutable, &l_lLibs, &l_cOptions),
+                EBackend::Builtin if l_bExecutable && !l_lLibs.is_empty() => Err(
+                    CDiagnostic::F_cError("E0301", "the builtin backend cannot link against shared libraries")
+                        .F_cNote(format!("needs {}", l_lLibs.iter().map(|l_sLib| format!("lib{}", l_sLib)).collect::<Vec<_>>().join(", ")))
+                        .F_cNote("build with --backend=nasm, or use emit-obj and link the object with cc")
+                ),
+                EBackend::Builtin => F_vWriteElf(&l_sAsm, &l_sOutput, l_bExecutable, &l_cOptions),
+            };
+            if let Err(l_cDiagnostic) = l_cResult {
+                F_vFail(&[l_cDiagnostic], &l_cSourceMap);
             }
-            
-            println!("\n=== COMPILATION X86_64 ===");
-            match StackCompiler::compile(&l_lProgram) {
-                Ok(l_sAsm) => {
-                    std::fs::write("out.asm", l_sAsm).unwrap();
-                },
-                Err(e) => eprintln!("Erreur: {}", e),
+            if !l_cOptions.l_bQuiet {
+                println!("{}: built {}", l_cOptions.l_sInput, l_sOutput);
             }
         }
-        Err(e) => eprintln!("Parse error: {}", e),
     }
 }
@@ -0,0 +1,240 @@
+// ============================================================================
+// Peephole optimizer over the generated procedure bodies (-O1)
+// ============================================================================
+//
+// Works on the lines of `StackAsmBuilder::funcs`, in the exact format of
+// `emit_func_instr`. Two rewrites:
+//
+// - `add/sub r15, n` is held back and the following `[r15 + k]` operands are
+//   rebased instead, so a push after a pop (or the reverse) cancels out. The
+//   pending adjustment is written before anything that looks at r15 itself:
+//   labels, jumps, calls, `ret`, `push r15`, ...
+// - a load of the stack slot that the previous line stored to reuses the
+//   stored register or immediate.
+//
+// Lines the pass does not understand (helpers pushed as a block, labels) end
+// the current run, so it never looks across a jump target.
+
+/// Register of the data stack, see `StackAsmBuilder::stack_reg`
+const STACK: &str = "r15";
+
+/// Peephole-optimized copy of `lines`
+pub fn optimize(lines: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    // r15 is `pending` bytes below its value in the unoptimized code
+    let mut pending: i64 = 0;
+
+    for line in lines {
+        let Some((mnemonic, operands)) = split(line) else {
+            flush(&mut out, &mut pending, None);
+            out.push(line.clone());
+            continue;
+        };
+
+        if let Some(delta) = stack_adjust(mnemonic, &operands) {
+            pending += delta;
+            continue;
+        }
+
+        match rebase(&operands, pending) {
+            Some(operands) if !is_control(mnemonic) => push_instr(&mut out, mnemonic, &operands),
+            _ => {
+                flush(&mut out, &mut pending, Some(mnemonic));
+                out.push(line.clone());
+            }
+        }
+    }
+    flush(&mut out, &mut pending, None);
+    out
+}
+
+/// Mnemonic and operands of a single instruction line
+fn split(line: &str) -> Option<(&str, Vec<String>)> {
+    let body = line.strip_prefix("    ")?;
+    if line.contains('\n') || body.starts_with(' ') || body.ends_with(':') || body.contains(';') {
+        return None;
+    }
+    let (mnemonic, rest) = body.split_once(' ').unwrap_or((body, ""));
+    let operands = rest.split(',').map(str::trim).filter(|op| !op.is_empty()).map(str::to_string).collect();
+    Some((mnemonic, operands))
+}
+
+/// Bytes added to r15 by `add r15, n` / `sub r15, n`
+fn stack_adjust(mnemonic: &str, operands: &[String]) -> Option<i64> {
+    let [dst, amount] = operands else { return None };
+    if dst != STACK {
+        return None;
+    }
+    let amount: i64 = amount.parse().ok()?;
+    match mnemonic {
+        "add" => Some(amount),
+        "sub" => Some(-amount),
+        _ => None,
+    }
+}
+
+/// Jumps and calls see r15, and a `ret` hands it back to the caller
+fn is_control(mnemonic: &str) -> bool {
+    mnemonic.starts_with('j') || matches!(mnemonic, "call" | "ret" | "syscall" | "leave")
+}
+
+/// Flags-consuming instructions: the adjustment before them must be a `lea`
+fn reads_flags(mnemonic: &str) -> bool {
+    (mnemonic.starts_with('j') && mnemonic != "jmp")
+        || mnemonic.starts_with("set")
+        || mnemonic.starts_with("cmov")
+        || matches!(mnemonic, "adc" | "sbb")
+}
+
+/// Writes the held back adjustment of r15 before an instruction `next`
+fn flush(out: &mut Vec<String>, pending: &mut i64, next: Option<&str>) {
+    if *pending == 0 {
+        return;
+    }
+    if next.is_some_and(reads_flags) {
+        out.push(format_instr("lea", &[STACK.to_string(), slot(*pending)]));
+    } else if *pending > 0 {
+        out.push(format_instr("add", &[STACK.to_string(), pending.to_string()]));
+    } else {
+        out.push(format_instr("sub", &[STACK.to_string(), (-*pending).to_string()]));
+    }
+    *pending = 0;
+}
+
+/// Operands with their `[r15 + k]` memory references moved by `pending`;
+/// None when r15 appears in any other way
+fn rebase(operands: &[String], pending: i64) -> Option<Vec<String>> {
+    operands.iter().map(|op| {
+        if !op.contains(STACK) {
+            return Some(op.clone());
+        }
+        let (size, offset) = parse_slot(op)?;
+        Some(format!("{}{}", size, slot(offset + pending)))
+    }).collect()
+}
+
+/// (size prefix, offset) of `[r15]`, `qword [r15 + 8]`, ...
+fn parse_slot(op: &str) -> Option<(&str, i64)> {
+    let open = op.find('[')?;
+    let (size, address) = op.split_at(open);
+    let inner = address.strip_prefix('[')?.strip_suffix(']')?.replace(' ', "");
+    let rest = inner.strip_prefix(STACK)?;
+    let offset = match rest {
+        "" => 0,
+        _ if rest.starts_with('+') => rest[1..].parse().ok()?,
+        _ if rest.starts_with('-') => -rest[1..].parse::<i64>().ok()?,
+        _ => return None,
+    };
+    Some((size, offset))
+}
+
+fn slot(offset: i64) -> String {
+    match offset {
+        0 => format!("[{}]", STACK),
+        o if o > 0 => format!("[{} + {}]", STACK, o),
+        o => format!("[{} - {}]", STACK, -o),
+    }
+}
+
+fn format_instr(mnemonic: &str, operands: &[String]) -> String {
+    format!("    {:8} {}", mnemonic, operands.join(", "))
+}
+
+/// Appends an instruction, replacing `mov reg, [slot]` right after a store
+/// of a register or an immediate to that slot by a copy of the stored value
+fn push_instr(out: &mut Vec<String>, mnemonic: &str, operands: &[String]) {
+    if let Some(value) = reloaded(out.last(), mnemonic, operands) {
+        // The value is already in the register: nothing to load
+        if value != operands[0] {
+            out.push(format_instr("mov", &[operands[0].clone(), value]));
+        }
+        return;
+    }
+    out.push(format_instr(mnemonic, operands));
+}
+
+/// The value stored by `previous` when `mnemonic operands` loads it back
+/// into a 64-bit register
+fn reloaded(previous: Option<&String>, mnemonic: &str, operands: &[String]) -> Option<String> {
+    let qword = |size: &str| matches!(size.trim(), "" | "qword");
+    let [dst, src] = operands else { return None };
+    let (size, offset) = parse_slot(src)?;
+    if mnemonic != "mov" || dst.contains('[') || !qword(size) {
+        return None;
+    }
+    let (prev_mnemonic, prev_operands) = split(previous?)?;
+    let [stored_to, value] = prev_operands.as_slice() else { return None };
+    let (stored_size, stored_offset) = parse_slot(stored_to)?;
+    let full_width = is_reg64(value) || value.parse::<i64>().is_ok();
+    (prev_mnemonic == "mov" && is_reg64(dst) && qword(stored_size) && stored_offset == offset && full_width)
+        .then(|| value.clone())
+}
+
+fn is_reg64(name: &str) -> bool {
+    const REGS_64: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+    REGS_64.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines as `emit_func_instr` writes them; labels stay as they are
+    fn lines(source: &[&str]) -> Vec<String> {
+        source.iter().map(|line| match line.split_once(' ') {
+            _ if line.ends_with(':') => line.to_string(),
+            Some((mnemonic, operands)) => format!("    {:8} {}", mnemonic, operands),
+            None => format!("    {:8} ", line),
+        }).collect()
+    }
+
+    fn check(source: &[&str], expected: &[&str]) {
+        assert_eq!(optimize(&lines(source)), lines(expected));
+    }
+
+    #[test]
+    fn held_back_adjustments_cancel() {
+        check(&["sub r15, 8", "add r15, 8", "ret"], &["ret"]);
+        check(&["add r15, 8", "add r15, 8", "ret"], &["add r15, 16", "ret"]);
+        check(&["add r15, 8", "sub r15, 24", "call proc_f"], &["sub r15, 16", "call proc_f"]);
+    }
+
+    #[test]
+    fn slots_are_rebased() {
+        check(
+            &["add r15, 8", "mov rax, qword [r15]", "mov rbx, [r15 - 8]", "sub r15, 16", "mov qword [r15], rax", "ret"],
+            &["mov rax, qword [r15 + 8]", "mov rbx, [r15]", "mov qword [r15 - 8], rax", "sub r15, 8", "ret"],
+        );
+        // Any other use of r15 sees its real value
+        check(&["sub r15, 8", "mov rax, r15", "ret"], &["sub r15, 8", "mov rax, r15", "ret"]);
+        check(&["add r15, 8", ".loop:", "ret"], &["add r15, 8", ".loop:", "ret"]);
+    }
+
+    #[test]
+    fn lea_before_flag_readers() {
+        check(&["add r15, 16", "cmp rax, rbx", "jl .else"], &["cmp rax, rbx", "lea r15, [r15 + 16]", "jl .else"]);
+        check(&["sub r15, 8", "test rax, rax", "jz .end"], &["test rax, rax", "lea r15, [r15 - 8]", "jz .end"]);
+        check(&["add r15, 8", "cmp rax, rbx", "jmp .end"], &["cmp rax, rbx", "add r15, 8", "jmp .end"]);
+    }
+
+    #[test]
+    fn stores_forward_to_reloads() {
+        check(&["mov qword [r15], rax", "mov rbx, qword [r15]"], &["mov qword [r15], rax", "mov rbx, rax"]);
+        check(&["mov qword [r15], rax", "mov rax, [r15]"], &["mov qword [r15], rax"]);
+        check(&["mov qword [r15 + 8], 42", "mov rcx, [r15 + 8]"], &["mov qword [r15 + 8], 42", "mov rcx, 42"]);
+        // Through a held back adjustment: the same slot once rebased
+        check(
+            &["sub r15, 8", "mov qword [r15], rax", "add r15, 8", "mov rbx, [r15 - 8]", "ret"],
+            &["mov qword [r15 - 8], rax", "mov rbx, rax", "ret"],
+        );
+        check(&["mov qword [r15], rax", "mov rbx, [r15 + 8]"], &["mov qword [r15], rax", "mov rbx, [r15 + 8]"]);
+    }
+
+    #[test]
+    fn narrow_stores_are_not_forwarded() {
+        for store in ["mov byte [r15], al", "mov [r15], r8d", "mov [r15], r12b", "mov dword [r15], 1"] {
+            check(&[store, "mov rax, [r15]"], &[store, "mov rax, [r15]"]);
+        }
+        check(&["mov [r15], rax", "mov ecx, [r15]"], &["mov [r15], rax", "mov ecx, [r15]"]);
+    }
+}
@@ -0,0 +1,103 @@
+#![allow(nonstandard_style)]
+
+// ============================================================================
+// Source locations: spans on tokens, and a side table mapping every
+// instruction of every proc/const/memory body back to the source text.
+// ============================================================================
+
+// A range of characters in the source text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CSpan {
+    pub l_iStart: usize,
+    pub l_iLen: usize,
+}
+
+impl CSpan {
+    pub fn F_cNew(l_iStart: usize, l_iLen: usize) -> Self {
+        CSpan { l_iStart, l_iLen }
+    }
+}
+
+// Spans of one top-level declaration: its name, then one per body instruction
+// (same indices as the body Vec in the IR)
+struct CDeclSpans {
+    l_sName: &'static str,
+    l_cName: CSpan,
+    l_lBody: Vec<CSpan>,
+}
+
+pub struct CSourceMap {
+    pub l_sFile: String,
+    l_lChars: Vec<char>,
+    l_lDecls: Vec<CDeclSpans>,
+}
+
+impl CSourceMap {
+    pub fn F_cNew(l_sFile: &str, l_sText: &str) -> Self {
+        CSourceMap { l_sFile: l_sFile.to_string(), l_lChars: l_sText.chars().collect(), l_lDecls: Vec::new() }
+    }
+
+    pub fn F_vAddDecl(&mut self, l_sName: &'static str, l_cName: CSpan, l_lBody: Vec<CSpan>) {
+        self.l_lDecls.push(CDeclSpans { l_sName, l_cName, l_lBody });
+    }
+
+    // Instruction l_iPc of l_sDecl was replaced by l_iCount instructions, all
+    // located where it was
+    pub fn F_vSplice(&mut self, l_sDecl: &str, l_iPc: usize, l_iCount: usize) {
+        let Some(l_cDecl) = self.l_lDecls.iter_mut().rev().find(|l_cDecl| l_cDecl.l_sName == l_sDecl) else { return };
+        if let Some(l_cSpan) = l_cDecl.l_lBody.get(l_iPc).copied() {
+            l_cDecl.l_lBody.splice(l_iPc..=l_iPc, std::iter::repeat_n(l_cSpan, l_iCount));
+        }
+    }
+
+    // Span of instruction l_oPc of a declaration's body, or of its name when
+    // l_oPc is None.
+    pub fn F_oSpan(&self, l_sDecl: &str, l_oPc: Option<usize>) -> Option<CSpan> {
+        let l_cDecl = self.l_lDecls.iter().rev().find(|l_cDecl| l_cDecl.l_sName == l_sDecl)?;
+        match l_oPc {
+            Some(l_iPc) => l_cDecl.l_lBody.get(l_iPc).copied(),
+            None => Some(l_cDecl.l_cName),
+        }
+    }
+
+    // 1-based line and column of a span's first character
+    pub fn F_tLineCol(&self, l_cSpan: CSpan) -> (usize, usize) {
+        let mut l_iLine = 1;
+        let mut l_iCol = 1;
+        for l_cChar in self.l_lChars.iter().take(l_cSpan.l_iStart) {
+            if *l_cChar == '\n' {
+                l_iLine += 1;
+                l_iCol = 1;
+            } else {
+                l_iCol += 1;
+            }
+        }
+        (l_iLine, l_iCol)
+    }
+
+    // The source line of a span with the span underlined, rustc-style:
+    //
+    //    |
+    //  2 |     1 0 / dump
+    //    |         ^
+    pub fn F_sSnippet(&self, l_cSpan: CSpan) -> String {
+        let (l_iLine, l_iCol) = self.F_tLineCol(l_cSpan);
+
+        let l_iLineStart = l_cSpan.l_iStart - (l_iCol - 1);
+        let l_sLine: String = self.l_lChars[l_iLineStart..].iter()
+            .take_while(|l_cChar| **l_cChar != '\n')
+            .collect();
+        let l_iWidth = l_cSpan.l_iLen.clamp(1, (l_sLine.chars().count() + 1).saturating_sub(l_iCol).max(1));
+
+        // Keep tabs under the caret so it lines up with the source line
+        let l_sIndent: String = l_sLine.chars().take(l_iCol - 1)
+            .map(|l_cChar| if l_cChar == '\t' { '\t' } else { ' ' })
+            .collect();
+        let l_sGutter = l_iLine.to_string();
+        let l_sPad = " ".repeat(l_sGutter.len());
+        format!(
+            "{} |\n{} | {}\n{} | {}{}",
+            l_sPad, l_sGutter, l_sLine, l_sPad, l_sIndent, "^".repeat(l_iWidth)
+        )
+    }
+}
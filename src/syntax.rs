@@ -1,5 +1,7 @@
 #![allow(nonstandard_style)]
 use crate::{EIrInstr, EType};
+use crate::source::{CSourceMap, CSpan};
+use crate::diagnostic::CDiagnostic;
 
 #[derive(Debug, Clone, PartialEq)]
 enum EToken {
@@ -8,19 +10,58 @@ enum EToken {
     Ident(String),
     Const,
     Proc,
+    Memory,
+    Export,
+    Extern,
+    Inline,
+    NoInline,
     In,
     End,
+    If,
+    Else,
+    While,
+    Do,
+    Break,
+    Continue,
 
     Plus,
     Minus,
     Mul,
     Div,
     Mod,
+    UDiv,
+    UMod,
+
+    // Comparison
+    Eq,
+    Neq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    ULt,
+    UGt,
+    ULe,
+    UGe,
+
+    // Bitwise
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    Sar,
 
     Dup,
     Swap,
+    Drop,
+    Over,
+    Rot,
     Puts,
     Dump,
+    Load(u8),
+    Store(u8),
     Syscall0,
     Syscall1,
     Syscall2,
@@ -28,6 +69,10 @@ enum EToken {
     Syscall4,
     Syscall5,
     Syscall6,
+    Argc,
+    Argv,
+    Envp,
+    Exit,
 
     // Types
     I8,
@@ -48,20 +93,126 @@ enum EToken {
     RetType,
 }
 
+impl EToken {
+    // The token as it is written in the source, for error messages
+    fn F_sSpelling(&self) -> String {
+        let l_sKeyword = match self {
+            EToken::Number(l_iN) => return l_iN.to_string(),
+            EToken::String(l_sStr) => return format!("{:?}", l_sStr),
+            EToken::Ident(l_sName) => return l_sName.clone(),
+            EToken::Load(l_iBits) => return format!("@{}", l_iBits),
+            EToken::Store(l_iBits) => return format!("!{}", l_iBits),
+            EToken::Const => "const",
+            EToken::Proc => "proc",
+            EToken::Memory => "memory",
+            EToken::Export => "export",
+            EToken::Extern => "extern",
+            EToken::Inline => "inline",
+            EToken::NoInline => "noinline",
+            EToken::In => "in",
+            EToken::End => "end",
+            EToken::If => "if",
+            EToken::Else => "else",
+            EToken::While => "while",
+            EToken::Do => "do",
+            EToken::Break => "break",
+            EToken::Continue => "continue",
+            EToken::Plus => "+",
+            EToken::Minus => "-",
+            EToken::Mul => "*",
+            EToken::Div => "/",
+            EToken::Mod => "%",
+            EToken::UDiv => "udiv",
+            EToken::UMod => "umod",
+            EToken::Eq => "=",
+            EToken::Neq => "!=",
+            EToken::Lt => "<",
+            EToken::Gt => ">",
+            EToken::Le => "<=",
+            EToken::Ge => ">=",
+            EToken::ULt => "ult",
+            EToken::UGt => "ugt",
+            EToken::ULe => "ule",
+            EToken::UGe => "uge",
+            EToken::BitAnd => "&",
+            EToken::BitOr => "|",
+            EToken::BitXor => "^",
+            EToken::BitNot => "~",
+            EToken::Shl => "<<",
+            EToken::Shr => ">>",
+            EToken::Sar => "sar",
+            EToken::Dup => "dup",
+            EToken::Swap => "swap",
+            EToken::Drop => "drop",
+            EToken::Over => "over",
+            EToken::Rot => "rot",
+            EToken::Puts => "puts",
+            EToken::Dump => "dump",
+            EToken::Syscall0 => "syscall",
+            EToken::Syscall1 => "syscall1",
+            EToken::Syscall2 => "syscall2",
+            EToken::Syscall3 => "syscall3",
+            EToken::Syscall4 => "syscall4",
+            EToken::Syscall5 => "syscall5",
+            EToken::Syscall6 => "syscall6",
+            EToken::Argc => "argc",
+            EToken::Argv => "argv",
+            EToken::Envp => "envp",
+            EToken::Exit => "exit",
+            EToken::I8 => "i8",
+            EToken::U8 => "u8",
+            EToken::I16 => "i16",
+            EToken::U16 => "u16",
+            EToken::I32 => "i32",
+            EToken::U32 => "u32",
+            EToken::I64 => "i64",
+            EToken::U64 => "u64",
+            EToken::F32 => "f32",
+            EToken::F64 => "f64",
+            EToken::Ptr => "ptr",
+            EToken::Str => "str",
+            EToken::Bool => "bool",
+            EToken::Void => "void",
+            EToken::RetType => "ret",
+        };
+        l_sKeyword.to_string()
+    }
+}
+
 struct CLexer {
     l_sInput: String,
     l_iPos: usize,
+    l_iTokenStart: usize,  // where the token being read starts, for errors
 }
 
 impl CLexer {
     fn F_cNew(l_sInput: String) -> Self {
-        CLexer { l_sInput, l_iPos: 0 }
+        CLexer { l_sInput, l_iPos: 0, l_iTokenStart: 0 }
+    }
+
+    fn F_cTokenSpan(&self) -> CSpan {
+        CSpan::F_cNew(self.l_iTokenStart, self.l_iPos.saturating_sub(self.l_iTokenStart).max(1))
     }
 
     fn F_cPeek(&self) -> Option<char> {
         self.l_sInput.chars().nth(self.l_iPos)
     }
 
+    fn F_cPeekNext(&self) -> Option<char> {
+        self.l_sInput.chars().nth(self.l_iPos + 1)
+    }
+
+    // Consumes the current char, plus the next one when it equals l_cNext
+    fn F_bAdvanceIf(&mut self, l_cNext: char) -> bool {
+        self.F_cAdvance();
+        if self.F_cPeek() == Some(l_cNext) {
+            self.F_cAdvance();
+            true
+        } else {
+            false
+        }
+    }
+
     fn F_cAdvance(&mut self) -> Option<char> {
         let l_cChar = self.F_cPeek();
         self.l_iPos += 1;
@@ -105,7 +256,7 @@ impl CLexer {
                         l_sResult.push('\\');
                         l_sResult.push(l_cC);
                     }
-                    None => return Err("Fin inattendue dans escape".to_string()),
+                    None => return Err("unexpected end of input in escape sequence".to_string()),
                 }
             } else {
                 l_sResult.push(l_cChar);
@@ -113,14 +264,15 @@ impl CLexer {
             }
         }
         
-        Err("String non terminee".to_string())
+        Err("unterminated string literal".to_string())
     }
 
     fn F_sReadIdent(&mut self) -> String {
         let mut l_sResult = String::new();
         
+        // Parentheses are allowed inside names, e.g. `sizeof(u64)`
         while let Some(l_cChar) = self.F_cPeek() {
-            if l_cChar.is_alphanumeric() || l_cChar == '_' {
+            if l_cChar.is_alphanumeric() || l_cChar == '_' || l_cChar == '(' || l_cChar == ')' {
                 l_sResult.push(l_cChar);
                 self.F_cAdvance();
             } else {
@@ -150,109 +302,240 @@ impl CLexer {
         }
 
         if l_sNum.is_empty() {
-            return Err("Nombre invalide".to_string());
+            return Err("invalid number".to_string());
         }
 
-        let l_iVal = l_sNum.parse::<i64>().map_err(|_| "Parse error".to_string())?;
+        let l_iVal = l_sNum.parse::<i64>().map_err(|_| format!("number literal {} does not fit in 64 bits", l_sNum))?;
         Ok(if l_bNeg { -l_iVal } else { l_iVal })
     }
 
-    fn F_lTokenize(&mut self) -> Result<Vec<EToken>, String> {
+    // Returns the tokens and, in parallel, their spans. Lexical errors are
+    // collected into l_lDiagnostics and the lexer resumes after them.
+    fn F_lTokenize(&mut self, l_lDiagnostics: &mut Vec<CDiagnostic>) -> (Vec<EToken>, Vec<CSpan>) {
         let mut l_lTokens = Vec::new();
+        let mut l_lSpans = Vec::new();
 
         loop {
             self.F_vSkipWhitespace();
+            self.l_iTokenStart = self.l_iPos;
 
-            match self.F_cPeek() {
-                None => break,
-                Some('"') => {
-                    let l_sStr = self.F_sReadString()?;
-                    l_lTokens.push(EToken::String(l_sStr));
-                }
-
-                // Operators
-                Some('+') => {
-                    self.F_cAdvance();
-                    l_lTokens.push(EToken::Plus);
-                }
-                Some('-') => {
-                    self.F_cAdvance();
-                    l_lTokens.push(EToken::Minus);
-                }
-                Some('*') => {
-                    self.F_cAdvance();
-                    l_lTokens.push(EToken::Mul);
-                }
-                Some('/') => {
-                    self.F_cAdvance();
-                    l_lTokens.push(EToken::Div);
-                }
-                Some('%') => {
-                    self.F_cAdvance();
-                    l_lTokens.push(EToken::Mod);
-                }
-
-
-                // Numbers
-                Some(l_cChar) if l_cChar.is_numeric() || (l_cChar == '-' && self.l_sInput.chars().nth(self.l_iPos + 1).map_or(false, |c| c.is_numeric())) => {
-                    let l_iNum = self.F_iReadNumber()?;
-                    l_lTokens.push(EToken::Number(l_iNum));
-                }
-                Some(l_cChar) if l_cChar.is_alphabetic() || l_cChar == '_' => {
-                    let l_sIdent = self.F_sReadIdent();
-                    let l_eToken = match l_sIdent.as_str() {
-                        "ret" => EToken::RetType,
-                        "proc" => EToken::Proc,
-                        "const" => EToken::Const,
-                        "in" => EToken::In,
-                        "end" => EToken::End,
-                        "dup" => EToken::Dup,
-                        "swap" => EToken::Swap,
-                        "puts" => EToken::Puts,
-                        "dump" => EToken::Dump,
-                        "syscall" => EToken::Syscall0,
-                        "syscall1" => EToken::Syscall1,
-                        "syscall2" => EToken::Syscall2,
-                        "syscall3" => EToken::Syscall3,
-                        "syscall4" => EToken::Syscall4,
-                        "syscall5" => EToken::Syscall5,
-                        "syscall6" => EToken::Syscall6,
-                        "i8" => EToken::I8,
-                        "u8" => EToken::U8,
-                        "i16" => EToken::I16,
-                        "u16" => EToken::U16,
-                        "i32" => EToken::I32,
-                        "u32" => EToken::U32,
-                        "i64" => EToken::I64,
-                        "u64" => EToken::U64,
-                        "f32" => EToken::F32,
-                        "f64" => EToken::F64,
-                        "ptr" => EToken::Ptr,
-                        "str" => EToken::Str,
-                        "bool" => EToken::Bool,
-                        "void" => EToken::Void,
-                        _ => EToken::Ident(l_sIdent),
-                    };
+            match self.F_oNextToken() {
+                Ok(Some(l_eToken)) => {
                     l_lTokens.push(l_eToken);
+                    l_lSpans.push(self.F_cTokenSpan());
                 }
-                Some(l_cChar) => {
-                    return Err(format!("Caractere inattendu: {}", l_cChar));
+                Ok(None) => break,
+                Err(e) => {
+                    l_lDiagnostics.push(CDiagnostic::F_cError("E0001", e).F_cAt(Some(self.F_cTokenSpan())));
+                    if self.l_iPos == self.l_iTokenStart {
+                        self.F_cAdvance();
+                    }
                 }
             }
         }
 
-        Ok(l_lTokens)
+        (l_lTokens, l_lSpans)
+    }
+
+    // Reads the token starting at the current position (None at the end of input)
+    fn F_oNextToken(&mut self) -> Result<Option<EToken>, String> {
+        match self.F_cPeek() {
+            None => Ok(None),
+            Some('"') => {
+                let l_sStr = self.F_sReadString()?;
+                Ok(Some(EToken::String(l_sStr)))
+            }
+
+            // Operators
+            Some('+') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::Plus))
+            }
+            Some('-') if !self.F_cPeekNext().is_some_and(|c| c.is_numeric()) => {
+                self.F_cAdvance();
+                Ok(Some(EToken::Minus))
+            }
+            Some('*') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::Mul))
+            }
+            Some('/') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::Div))
+            }
+            Some('%') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::Mod))
+            }
+            Some('=') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::Eq))
+            }
+            Some('!') if self.F_cPeekNext() == Some('=') => {
+                self.F_bAdvanceIf('=');
+                Ok(Some(EToken::Neq))
+            }
+            // @8 @16 @32 @64 / !8 !16 !32 !64
+            Some(l_cChar @ ('@' | '!')) => {
+                self.F_cAdvance();
+                let l_iBits = match self.F_iReadNumber() {
+                    Ok(l_iBits @ (8 | 16 | 32 | 64)) => l_iBits as u8,
+                    _ => return Err(format!("expected 8, 16, 32 or 64 after '{}'", l_cChar)),
+                };
+                Ok(Some(if l_cChar == '@' { EToken::Load(l_iBits) } else { EToken::Store(l_iBits) }))
+            }
+            Some('<') => {
+                let l_eToken = match self.F_cPeekNext() {
+                    Some('=') => { self.F_bAdvanceIf('='); EToken::Le }
+                    Some('<') => { self.F_bAdvanceIf('<'); EToken::Shl }
+                    _ => { self.F_cAdvance(); EToken::Lt }
+                };
+                Ok(Some(l_eToken))
+            }
+            Some('>') => {
+                let l_eToken = match self.F_cPeekNext() {
+                    Some('=') => { self.F_bAdvanceIf('='); EToken::Ge }
+                    Some('>') => { self.F_bAdvanceIf('>'); EToken::Shr }
+                    _ => { self.F_cAdvance(); EToken::Gt }
+                };
+                Ok(Some(l_eToken))
+            }
+            Some('&') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::BitAnd))
+            }
+            Some('|') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::BitOr))
+            }
+            Some('^') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::BitXor))
+            }
+            Some('~') => {
+                self.F_cAdvance();
+                Ok(Some(EToken::BitNot))
+            }
+
+
+            // Numbers
+            Some(l_cChar) if l_cChar.is_numeric() || l_cChar == '-' => {
+                let l_iNum = self.F_iReadNumber()?;
+                Ok(Some(EToken::Number(l_iNum)))
+            }
+            Some(l_cChar) if l_cChar.is_alphabetic() || l_cChar == '_' => {
+                let l_sIdent = self.F_sReadIdent();
+                let l_eToken = match l_sIdent.as_str() {
+                    "ret" => EToken::RetType,
+                    "proc" => EToken::Proc,
+                    "const" => EToken::Const,
+                    "memory" => EToken::Memory,
+                    "export" => EToken::Export,
+                    "extern" => EToken::Extern,
+                    "inline" => EToken::Inline,
+                    "noinline" => EToken::NoInline,
+                    "in" => EToken::In,
+                    "end" => EToken::End,
+                    "if" => EToken::If,
+                    "else" => EToken::Else,
+                    "while" => EToken::While,
+                    "do" => EToken::Do,
+                    "break" => EToken::Break,
+                    "continue" => EToken::Continue,
+                    "dup" => EToken::Dup,
+                    "swap" => EToken::Swap,
+                    "drop" => EToken::Drop,
+                    "over" => EToken::Over,
+                    "rot" => EToken::Rot,
+                    "udiv" => EToken::UDiv,
+                    "umod" => EToken::UMod,
+                    "ult" => EToken::ULt,
+                    "ugt" => EToken::UGt,
+                    "ule" => EToken::ULe,
+                    "uge" => EToken::UGe,
+                    "sar" => EToken::Sar,
+                    "puts" => EToken::Puts,
+                    "dump" => EToken::Dump,
+                    "syscall" => EToken::Syscall0,
+                    "syscall1" => EToken::Syscall1,
+                    "syscall2" => EToken::Syscall2,
+                    "syscall3" => EToken::Syscall3,
+                    "syscall4" => EToken::Syscall4,
+                    "syscall5" => EToken::Syscall5,
+                    "syscall6" => EToken::Syscall6,
+                    "argc" => EToken::Argc,
+                    "argv" => EToken::Argv,
+                    "envp" => EToken::Envp,
+                    "exit" => EToken::Exit,
+                    "i8" => EToken::I8,
+                    "u8" => EToken::U8,
+                    "i16" => EToken::I16,
+                    "u16" => EToken::U16,
+                    "i32" => EToken::I32,
+                    "u32" => EToken::U32,
+                    "i64" => EToken::I64,
+                    "u64" => EToken::U64,
+                    "f32" => EToken::F32,
+                    "f64" => EToken::F64,
+                    "ptr" => EToken::Ptr,
+                    "str" => EToken::Str,
+                    "bool" => EToken::Bool,
+                    "void" => EToken::Void,
+                    _ => EToken::Ident(l_sIdent),
+                };
+                Ok(Some(l_eToken))
+            }
+            Some(l_cChar) => {
+                self.F_cAdvance();
+                Err(format!("unexpected character '{}'", l_cChar))
+            }
+        }
     }
 }
 
-struct CParser {
+struct CParser<'m> {
     l_lTokens: Vec<EToken>,
+    l_lSpans: Vec<CSpan>,       // span of each token
     l_iPos: usize,
+    l_iLabelCount: usize,
+    l_lLoops: Vec<(usize, usize)>, // (continue, break) labels of the enclosing loops
+    l_lBodySpans: Vec<CSpan>,   // span of each instruction of the body being parsed
+    l_lDecls: Vec<(&'static str, CSpan)>, // name and name span of each declaration, in order
+    l_cSourceMap: &'m mut CSourceMap,
 }
 
-impl CParser {
-    fn F_cNew(l_lTokens: Vec<EToken>) -> Self {
-        CParser { l_lTokens, l_iPos: 0 }
+impl<'m> CParser<'m> {
+    fn F_cNew(l_lTokens: Vec<EToken>, l_lSpans: Vec<CSpan>, l_cSourceMap: &'m mut CSourceMap) -> Self {
+        CParser {
+            l_lTokens, l_lSpans, l_iPos: 0, l_iLabelCount: 0, l_lLoops: Vec::new(),
+            l_lBodySpans: Vec::new(), l_lDecls: Vec::new(), l_cSourceMap,
+        }
+    }
+
+    // Span of the last consumed token (the end of input maps to the last token)
+    fn F_oLastSpan(&self) -> Option<CSpan> {
+        let l_iLast = self.l_iPos.saturating_sub(1).min(self.l_lSpans.len().checked_sub(1)?);
+        self.l_lSpans.get(l_iLast).copied()
+    }
+
+    // Appends an instruction to a body, located at the last consumed token
+    fn F_vEmit(&mut self, l_lBody: &mut Vec<EIrInstr>, l_cInstr: EIrInstr) {
+        l_lBody.push(l_cInstr);
+        self.l_lBodySpans.push(self.F_oLastSpan().unwrap_or(CSpan::F_cNew(0, 1)));
+    }
+
+    // Records the spans of a finished declaration; l_cName is its name's span
+    fn F_vEndDecl(&mut self, l_sName: &'static str, l_cName: CSpan) {
+        let l_lBody = std::mem::take(&mut self.l_lBodySpans);
+        self.l_lDecls.push((l_sName, l_cName));
+        self.l_cSourceMap.F_vAddDecl(l_sName, l_cName, l_lBody);
+    }
+
+    // Labels are numbered program-wide so the backend can use them as-is
+    fn F_iNewLabel(&mut self) -> usize {
+        let l_iLabel = self.l_iLabelCount;
+        self.l_iLabelCount += 1;
+        l_iLabel
     }
 
     fn F_ePeek(&self) -> Option<&EToken> {
@@ -274,8 +557,8 @@ impl CParser {
     fn F_bExpect(&mut self, l_eExpected: EToken) -> Result<(), String> {
         match self.F_eAdvance() {
             Some(l_eToken) if l_eToken == &l_eExpected => Ok(()),
-            Some(l_eToken) => Err(format!("Expected {:?}, got {:?}", l_eExpected, l_eToken)),
-            None => Err("Unexpected end of input".to_string()),
+            Some(l_eToken) => Err(format!("expected `{}`, got `{}`", l_eExpected.F_sSpelling(), l_eToken.F_sSpelling())),
+            None => Err("unexpected end of input".to_string()),
         }
     }
 
@@ -294,53 +577,86 @@ impl CParser {
             Some(EToken::Ptr) => Ok(EType::Ptr),
             Some(EToken::Str) => Ok(EType::Str),
             Some(EToken::Bool) => Ok(EType::Bool),
-            Some(l_eToken) => Ok(EType::Void),//Err(format!("Expected type, got {:?}", l_eToken)),
-            None => Err("Unexpected end of input".to_string()),
+            Some(_l_eToken) => Ok(EType::Void),//Err(format!("Expected type, got {:?}", l_eToken)),
+            None => Err("unexpected end of input".to_string()),
+        }
+    }
+
+    // Parses types until a non-type token or the end of input (an extern
+    // can end the file); `void` entries are skipped
+    fn F_lParseTypeList(&mut self) -> Result<Vec<EType>, String> {
+        let mut l_lTypes = vec![];
+        loop {
+            match self.F_ePeek() {
+                Some(EToken::Void) => {
+                    self.F_eAdvance();
+                    continue;
+                }
+                None => break,
+                _ => {}
+            }
+            match self.F_lParseType()? {
+                EType::Void => {
+                    self.F_eBack();
+                    break;
+                }
+                l_eType => l_lTypes.push(l_eType),
+            }
         }
+        Ok(l_lTypes)
     }
 
+    // proc [name] [param types...] [ret [return types...]] in ... end
     fn F_lParseProc(&mut self) -> Result<EIrInstr, String> {
         self.F_bExpect(EToken::Proc)?;
 
         let l_sName = match self.F_eAdvance() {
             Some(EToken::Ident(l_sName)) => l_sName.clone(),
-            _ => return Err("Expected proc name".to_string()),
+            _ => return Err("expected proc name".to_string()),
         };
+        let l_cNameSpan = self.F_oLastSpan().unwrap();
 
-        /*/ parameters parsing can be added
-        let mut l_lParams = vec![];
-        while let Ok(l_eType) = self.F_lParseType() {
-            if let EType::Void = l_eType {
-                self.F_eBack();
-                break;
-            }
-            l_lParams.push(l_eType);
-        }
+        let l_lParams = self.F_lParseTypeList()?;
 
-        let mut l_eRetType = EType::Void;
-        // same for parameters but for return types
+        let mut l_lRets = Vec::new();
         if let Some(EToken::RetType) = self.F_ePeek() {
             self.F_eAdvance();
-            // we can parse return types here if needed
-            // get type
-            l_eRetType = self.F_lParseType()?;
-        }*/
+            l_lRets = self.F_lParseTypeList()?;
+        }
 
         self.F_bExpect(EToken::In)?;
 
         let mut l_lBody = Vec::new();
-        while let Some(l_eToken) = self.F_ePeek() {
-            if l_eToken == &EToken::End {
-                self.F_eAdvance();
-                break;
-            }
-            l_lBody.push(self.F_eParseInstr()?);
+        self.F_vParseBlockUntil(&mut l_lBody, &EToken::End)?;
+        self.F_vEmit(&mut l_lBody, EIrInstr::Ret);
+
+        let l_sNameStatic = Box::leak(l_sName.into_boxed_str());
+        self.F_vEndDecl(l_sNameStatic, l_cNameSpan);
+        Ok(EIrInstr::Proc(l_sNameStatic, l_lBody, l_lParams, l_lRets))
+    }
+
+    // extern [name] [param types...] [ret [return types...]]: a C function,
+    // declared without a body
+    fn F_lParseExtern(&mut self) -> Result<EIrInstr, String> {
+        self.F_bExpect(EToken::Extern)?;
+
+        let l_sName = match self.F_eAdvance() {
+            Some(EToken::Ident(l_sName)) => l_sName.clone(),
+            _ => return Err("expected extern name".to_string()),
+        };
+        let l_cNameSpan = self.F_oLastSpan().unwrap();
+
+        let l_lParams = self.F_lParseTypeList()?;
+
+        let mut l_lRets = Vec::new();
+        if let Some(EToken::RetType) = self.F_ePeek() {
+            self.F_eAdvance();
+            l_lRets = self.F_lParseTypeList()?;
         }
-        l_lBody.push(EIrInstr::Ret);
 
         let l_sNameStatic = Box::leak(l_sName.into_boxed_str());
-        //l_lParams, l_eRetType))
-        Ok(EIrInstr::Proc(l_sNameStatic, l_lBody, Vec::new(), EType::Void))
+        self.F_vEndDecl(l_sNameStatic, l_cNameSpan);
+        Ok(EIrInstr::Extern(l_sNameStatic, l_lParams, l_lRets))
     }
 
     fn F_lParseConst(&mut self) -> Result<EIrInstr, String> {
@@ -348,23 +664,118 @@ impl CParser {
 
         let l_sName = match self.F_eAdvance() {
             Some(EToken::Ident(l_sName)) => l_sName.clone(),
-            _ => return Err("Expected const name".to_string()),
+            _ => return Err("expected const name".to_string()),
         };
+        let l_cNameSpan = self.F_oLastSpan().unwrap();
 
-        self.F_bExpect(EToken::In)?;
+        // `in` is optional for consts: `const sizeof(u64) 8 end`
+        if let Some(EToken::In) = self.F_ePeek() {
+            self.F_eAdvance();
+        }
 
         let mut l_lBody = Vec::new();
-        while let Some(l_eToken) = self.F_ePeek() {
-            if l_eToken == &EToken::End {
-                self.F_eAdvance();
-                break;
+        self.F_vParseBlockUntil(&mut l_lBody, &EToken::End)?;
+        self.F_vEmit(&mut l_lBody, EIrInstr::Ret);
+
+        let l_sNameStatic = Box::leak(l_sName.into_boxed_str());
+        self.F_vEndDecl(l_sNameStatic, l_cNameSpan);
+        Ok(EIrInstr::Const(l_sNameStatic, l_lBody))
+    }
+
+    // memory NAME size-expression end
+    fn F_lParseMemory(&mut self) -> Result<EIrInstr, String> {
+        self.F_bExpect(EToken::Memory)?;
+
+        let l_sName = match self.F_eAdvance() {
+            Some(EToken::Ident(l_sName)) => l_sName.clone(),
+            _ => return Err("expected memory name".to_string()),
+        };
+        let l_cNameSpan = self.F_oLastSpan().unwrap();
+
+        let mut l_lSize = Vec::new();
+        self.F_vParseBlockUntil(&mut l_lSize, &EToken::End)?;
+
+        let l_sNameStatic = Box::leak(l_sName.into_boxed_str());
+        self.F_vEndDecl(l_sNameStatic, l_cNameSpan);
+        Ok(EIrInstr::Memory(l_sNameStatic, l_lSize))
+    }
+
+    // Parses instructions into l_lBody until one of the block terminators
+    // (`end` / `else` / `do`) is reached; the terminator is consumed and returned.
+    fn F_eParseBlock(&mut self, l_lBody: &mut Vec<EIrInstr>) -> Result<EToken, String> {
+        loop {
+            match self.F_ePeek() {
+                Some(EToken::End) | Some(EToken::Else) | Some(EToken::Do) => {
+                    return Ok(self.F_eAdvance().unwrap().clone());
+                }
+                Some(EToken::If) => {
+                    self.F_eAdvance();
+                    self.F_vParseIf(l_lBody)?;
+                }
+                Some(EToken::While) => {
+                    self.F_eAdvance();
+                    self.F_vParseWhile(l_lBody)?;
+                }
+                Some(EToken::Break) | Some(EToken::Continue) => {
+                    let l_bBreak = self.F_eAdvance() == Some(&EToken::Break);
+                    let (l_iContinue, l_iBreak) = *self.l_lLoops.last()
+                        .ok_or_else(|| format!("{} outside of a loop", if l_bBreak { "break" } else { "continue" }))?;
+                    self.F_vEmit(l_lBody, EIrInstr::Jump(if l_bBreak { l_iBreak } else { l_iContinue }));
+                }
+                Some(_) => {
+                    let l_cInstr = self.F_eParseInstr()?;
+                    self.F_vEmit(l_lBody, l_cInstr);
+                }
+                None => return Err("unexpected end of input, expected `end`".to_string()),
             }
-            l_lBody.push(self.F_eParseInstr()?);
         }
-        l_lBody.push(EIrInstr::Ret);
+    }
 
-        let l_sNameStatic = Box::leak(l_sName.into_boxed_str());
-        Ok(EIrInstr::Proc(l_sNameStatic, l_lBody, Vec::new(), EType::Void))
+    fn F_vParseBlockUntil(&mut self, l_lBody: &mut Vec<EIrInstr>, l_eExpected: &EToken) -> Result<(), String> {
+        let l_eToken = self.F_eParseBlock(l_lBody)?;
+        if &l_eToken != l_eExpected {
+            return Err(format!("expected `{}`, got `{}`", l_eExpected.F_sSpelling(), l_eToken.F_sSpelling()));
+        }
+        Ok(())
+    }
+
+    // cond if ... [else ...] end
+    //   JumpIfZero(else) ... Jump(end) Label(else) ... Label(end)
+    fn F_vParseIf(&mut self, l_lBody: &mut Vec<EIrInstr>) -> Result<(), String> {
+        let l_iElse = self.F_iNewLabel();
+        self.F_vEmit(l_lBody, EIrInstr::JumpIfZero(l_iElse));
+
+        match self.F_eParseBlock(l_lBody)? {
+            EToken::Else => {
+                let l_iEnd = self.F_iNewLabel();
+                self.F_vEmit(l_lBody, EIrInstr::Jump(l_iEnd));
+                self.F_vEmit(l_lBody, EIrInstr::Label(l_iElse));
+                self.F_vParseBlockUntil(l_lBody, &EToken::End)?;
+                self.F_vEmit(l_lBody, EIrInstr::Label(l_iEnd));
+            }
+            _ => self.F_vEmit(l_lBody, EIrInstr::Label(l_iElse)),
+        }
+        Ok(())
+    }
+
+    // while cond do ... end
+    //   Label(start) cond JumpIfZero(end) ... Jump(start) Label(end)
+    fn F_vParseWhile(&mut self, l_lBody: &mut Vec<EIrInstr>) -> Result<(), String> {
+        let l_iStart = self.F_iNewLabel();
+        let l_iEnd = self.F_iNewLabel();
+
+        self.F_vEmit(l_lBody, EIrInstr::Label(l_iStart));
+        self.F_vParseBlockUntil(l_lBody, &EToken::Do)?;
+        self.F_vEmit(l_lBody, EIrInstr::JumpIfZero(l_iEnd));
+
+        self.l_lLoops.push((l_iStart, l_iEnd));
+        let l_rBody = self.F_vParseBlockUntil(l_lBody, &EToken::End);
+        self.l_lLoops.pop();
+        l_rBody?;
+
+        self.F_vEmit(l_lBody, EIrInstr::Jump(l_iStart));
+        self.F_vEmit(l_lBody, EIrInstr::Label(l_iEnd));
+        Ok(())
     }
 
     fn F_eParseInstr(&mut self) -> Result<EIrInstr, String> {
@@ -380,11 +791,43 @@ impl CParser {
             Some(EToken::Mul) => Ok(EIrInstr::MulI64),
             Some(EToken::Div) => Ok(EIrInstr::DivI64),
             Some(EToken::Mod) => Ok(EIrInstr::ModI64),
+            Some(EToken::UDiv) => Ok(EIrInstr::DivU64),
+            Some(EToken::UMod) => Ok(EIrInstr::ModU64),
+
+            Some(EToken::Eq) => Ok(EIrInstr::EqI64),
+            Some(EToken::Neq) => Ok(EIrInstr::NeqI64),
+            Some(EToken::Lt) => Ok(EIrInstr::LtI64),
+            Some(EToken::Gt) => Ok(EIrInstr::GtI64),
+            Some(EToken::Le) => Ok(EIrInstr::LeI64),
+            Some(EToken::Ge) => Ok(EIrInstr::GeI64),
+            Some(EToken::ULt) => Ok(EIrInstr::LtU64),
+            Some(EToken::UGt) => Ok(EIrInstr::GtU64),
+            Some(EToken::ULe) => Ok(EIrInstr::LeU64),
+            Some(EToken::UGe) => Ok(EIrInstr::GeU64),
+
+            Some(EToken::BitAnd) => Ok(EIrInstr::AndI64),
+            Some(EToken::BitOr) => Ok(EIrInstr::OrI64),
+            Some(EToken::BitXor) => Ok(EIrInstr::XorI64),
+            Some(EToken::BitNot) => Ok(EIrInstr::NotI64),
+            Some(EToken::Shl) => Ok(EIrInstr::ShlI64),
+            Some(EToken::Shr) => Ok(EIrInstr::ShrU64),
+            Some(EToken::Sar) => Ok(EIrInstr::SarI64),
 
             Some(EToken::Dup) => Ok(EIrInstr::Dup),
             Some(EToken::Swap) => Ok(EIrInstr::Swap),
+            Some(EToken::Drop) => Ok(EIrInstr::Drop),
+            Some(EToken::Over) => Ok(EIrInstr::Over),
+            Some(EToken::Rot) => Ok(EIrInstr::Rot),
             Some(EToken::Puts) => Ok(EIrInstr::Puts),
             Some(EToken::Dump) => Ok(EIrInstr::Dump),
+            Some(EToken::Load(8)) => Ok(EIrInstr::Load8),
+            Some(EToken::Load(16)) => Ok(EIrInstr::Load16),
+            Some(EToken::Load(32)) => Ok(EIrInstr::Load32),
+            Some(EToken::Load(_)) => Ok(EIrInstr::Load64),
+            Some(EToken::Store(8)) => Ok(EIrInstr::Store8),
+            Some(EToken::Store(16)) => Ok(EIrInstr::Store16),
+            Some(EToken::Store(32)) => Ok(EIrInstr::Store32),
+            Some(EToken::Store(_)) => Ok(EIrInstr::Store64),
             Some(EToken::Syscall0) => Ok(EIrInstr::Syscall0),
             Some(EToken::Syscall1) => Ok(EIrInstr::Syscall1),
             Some(EToken::Syscall2) => Ok(EIrInstr::Syscall2),
@@ -392,37 +835,235 @@ impl CParser {
             Some(EToken::Syscall4) => Ok(EIrInstr::Syscall4),
             Some(EToken::Syscall5) => Ok(EIrInstr::Syscall5),
             Some(EToken::Syscall6) => Ok(EIrInstr::Syscall6),
+            Some(EToken::Argc) => Ok(EIrInstr::Argc),
+            Some(EToken::Argv) => Ok(EIrInstr::Argv),
+            Some(EToken::Envp) => Ok(EIrInstr::Envp),
+            Some(EToken::Exit) => Ok(EIrInstr::Exit),
             Some(EToken::Ident(l_sName)) => {
                 let l_sStatic = Box::leak(l_sName.clone().into_boxed_str());
                 Ok(EIrInstr::Call(l_sStatic))
             }
             Some(EToken::End) => Ok(EIrInstr::Ret),
-            Some(l_eToken) => Err(format!("Unexpected token: {:?}", l_eToken)),
-            None => Err("Unexpected end of input".to_string()),
+            Some(l_eToken) => Err(format!("unexpected token `{}`", l_eToken.F_sSpelling())),
+            None => Err("unexpected end of input".to_string()),
         }
     }
 
-    fn F_lParse(&mut self) -> Result<Vec<EIrInstr>, String> {
+    fn F_vParseDecl(&mut self, l_lProgram: &mut Vec<EIrInstr>) -> Result<(), String> {
+        let l_cDecl = match self.F_ePeek() {
+            Some(EToken::Proc) => self.F_lParseProc()?,
+            Some(EToken::Const) => self.F_lParseConst()?,
+            Some(EToken::Memory) => self.F_lParseMemory()?,
+            Some(EToken::Extern) => self.F_lParseExtern()?,
+            // export / inline / noinline proc ...: the proc, then a marker
+            // with its name for each modifier
+            Some(EToken::Export | EToken::Inline | EToken::NoInline) => {
+                let mut l_lModifiers = Vec::new();
+                while let Some(l_eToken @ (EToken::Export | EToken::Inline | EToken::NoInline)) = self.F_ePeek() {
+                    let l_eToken = l_eToken.clone();
+                    self.F_eAdvance();
+                    if l_lModifiers.contains(&l_eToken) {
+                        return Err(format!("`{}` given twice", F_sModifierKeyword(&l_eToken)));
+                    }
+                    l_lModifiers.push(l_eToken);
+                }
+                if l_lModifiers.contains(&EToken::Inline) && l_lModifiers.contains(&EToken::NoInline) {
+                    return Err("a proc cannot be both inline and noinline".to_string());
+                }
+                if self.F_ePeek() != Some(&EToken::Proc) {
+                    self.F_eAdvance();
+                    return Err(match l_lModifiers[0] {
+                        EToken::Export => "only procs can be exported".to_string(),
+                        _ => "only procs can be inline or noinline".to_string(),
+                    });
+                }
+                let l_cProc = self.F_lParseProc()?;
+                let EIrInstr::Proc(l_sName, ..) = l_cProc else { unreachable!() };
+                l_lProgram.push(l_cProc);
+                for l_eModifier in &l_lModifiers[..l_lModifiers.len() - 1] {
+                    l_lProgram.push(F_cModifier(l_eModifier, l_sName));
+                }
+                F_cModifier(l_lModifiers.last().unwrap(), l_sName)
+            }
+            _ => {
+                let l_eToken = self.F_eAdvance().cloned();
+                return Err(format!("expected proc, const, memory or extern declaration, got `{}`", l_eToken.unwrap().F_sSpelling()));
+            }
+        };
+        l_lProgram.push(l_cDecl);
+        Ok(())
+    }
+
+    // A declaration with a syntax error is reported and skipped: parsing
+    // resumes at the next declaration keyword
+    fn F_lParse(&mut self, l_lDiagnostics: &mut Vec<CDiagnostic>) -> Vec<EIrInstr> {
         let mut l_lProgram = Vec::new();
 
         while self.l_iPos < self.l_lTokens.len() {
-            if let Some(EToken::Proc) = self.F_ePeek() {
-                l_lProgram.push(self.F_lParseProc()?);
-            } else if let Some(EToken::Const) = self.F_ePeek() {
-                l_lProgram.push(self.F_lParseConst()?);
-            } else {
-                return Err("Expected proc declaration".to_string());
+            match self.F_vParseDecl(&mut l_lProgram) {
+                Ok(()) => {}
+                Err(e) => {
+                    l_lDiagnostics.push(CDiagnostic::F_cError("E0002", e).F_cAt(self.F_oLastSpan()));
+                    self.l_lLoops.clear();
+                    self.l_lBodySpans.clear();
+                    while let Some(l_eToken) = self.F_ePeek() {
+                        if matches!(l_eToken, EToken::Proc | EToken::Const | EToken::Memory | EToken::Export | EToken::Extern |
+                            EToken::Inline | EToken::NoInline) {
+                            break;
+                        }
+                        self.F_eAdvance();
+                    }
+                }
             }
         }
 
-        Ok(l_lProgram)
+        self.F_vCheckDuplicates(l_lDiagnostics);
+        l_lProgram
+    }
+
+    // Procs, consts, memories and externs share one namespace: a name
+    // declared again is reported at the later declaration
+    fn F_vCheckDuplicates(&self, l_lDiagnostics: &mut Vec<CDiagnostic>) {
+        for (l_iDecl, (l_sName, l_cSpan)) in self.l_lDecls.iter().enumerate() {
+            let Some((_, l_cFirst)) = self.l_lDecls[..l_iDecl].iter().find(|(l_sOther, _)| l_sOther == l_sName) else {
+                continue;
+            };
+            let (l_iLine, l_iCol) = self.l_cSourceMap.F_tLineCol(*l_cFirst);
+            l_lDiagnostics.push(
+                CDiagnostic::F_cError("E0003", format!("`{}` is declared more than once", l_sName))
+                    .F_cAt(Some(*l_cSpan))
+                    .F_cNote(format!("first declared at {}:{}:{}", self.l_cSourceMap.l_sFile, l_iLine, l_iCol))
+            );
+        }
+    }
+}
+
+fn F_sModifierKeyword(l_eModifier: &EToken) -> &'static str {
+    match l_eModifier {
+        EToken::Export => "export",
+        EToken::Inline => "inline",
+        _ => "noinline",
+    }
+}
+
+// Marker recorded after a proc declared with a modifier keyword
+fn F_cModifier(l_eModifier: &EToken, l_sName: &'static str) -> EIrInstr {
+    match l_eModifier {
+        EToken::Export => EIrInstr::Export(l_sName),
+        EToken::Inline => EIrInstr::Inline(l_sName),
+        _ => EIrInstr::NoInline(l_sName),
     }
 }
 
-pub fn F_lParseProgram(l_sInput: &str) -> Result<Vec<EIrInstr>, String> {
+// Parses a whole file, recording the location of every instruction in
+// l_cSourceMap. All lexical and syntax errors are reported, not just the first.
+pub fn F_lParseProgram(l_sInput: &str, l_cSourceMap: &mut CSourceMap) -> Result<Vec<EIrInstr>, Vec<CDiagnostic>> {
+    let mut l_lDiagnostics = Vec::new();
     let mut l_cLexer = CLexer::F_cNew(l_sInput.to_string());
-    let l_lTokens = l_cLexer.F_lTokenize()?;
-    
-    let mut l_cParser = CParser::F_cNew(l_lTokens);
-    l_cParser.F_lParse()
+    let (l_lTokens, l_lSpans) = l_cLexer.F_lTokenize(&mut l_lDiagnostics);
+
+    let mut l_cParser = CParser::F_cNew(l_lTokens, l_lSpans, l_cSourceMap);
+    let mut l_lProgram = l_cParser.F_lParse(&mut l_lDiagnostics);
+    if !l_lDiagnostics.is_empty() {
+        return Err(l_lDiagnostics);
+    }
+    F_vResolveNames(&mut l_lProgram);
+    Ok(l_lProgram)
+}
+
+// A memory name used in a body pushes the region's address and an extern
+// name calls the C function, instead of calling a proc; declarations may
+// come after their uses.
+fn F_vResolveNames(l_lProgram: &mut [EIrInstr]) {
+    let l_lMemories: Vec<&'static str> = l_lProgram.iter()
+        .filter_map(|l_cInstr| match l_cInstr {
+            EIrInstr::Memory(l_sName, _) => Some(*l_sName),
+            _ => None,
+        })
+        .collect();
+    let l_lExterns: Vec<&'static str> = l_lProgram.iter()
+        .filter_map(|l_cInstr| match l_cInstr {
+            EIrInstr::Extern(l_sName, _, _) => Some(*l_sName),
+            _ => None,
+        })
+        .collect();
+
+    for l_cInstr in l_lProgram.iter_mut() {
+        let l_lBody = match l_cInstr {
+            EIrInstr::Proc(_, l_lBody, _, _) | EIrInstr::Const(_, l_lBody) | EIrInstr::Memory(_, l_lBody) => l_lBody,
+            _ => continue,
+        };
+        for l_cBodyInstr in l_lBody.iter_mut() {
+            if let EIrInstr::Call(l_sName) = l_cBodyInstr {
+                if l_lMemories.contains(l_sName) {
+                    *l_cBodyInstr = EIrInstr::PushMem(l_sName);
+                } else if l_lExterns.contains(l_sName) {
+                    *l_cBodyInstr = EIrInstr::CallExtern(l_sName);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each diagnostic of a program that fails to parse, as
+    // (code, line, column, message, notes)
+    fn F_lErrors(l_sCode: &str) -> Vec<(&'static str, usize, usize, String, Vec<String>)> {
+        let mut l_cSourceMap = CSourceMap::F_cNew("test.eb", l_sCode);
+        let l_lDiagnostics = F_lParseProgram(l_sCode, &mut l_cSourceMap).expect_err("test program fails to parse");
+        l_lDiagnostics.into_iter().map(|l_cDiagnostic| {
+            let (l_iLine, l_iCol) = l_cSourceMap.F_tLineCol(l_cDiagnostic.l_oSpan.expect("diagnostic has a span"));
+            (l_cDiagnostic.l_sCode, l_iLine, l_iCol, l_cDiagnostic.l_sMessage, l_cDiagnostic.l_lNotes)
+        }).collect()
+    }
+
+    fn F_vAssertError(l_sCode: &str, l_sErrorCode: &str, l_tLineCol: (usize, usize), l_sMessage: &str) {
+        let l_lErrors = F_lErrors(l_sCode);
+        assert_eq!(l_lErrors.len(), 1, "{:?}", l_lErrors);
+        let (l_sGotCode, l_iLine, l_iCol, l_sGotMessage, _) = &l_lErrors[0];
+        assert_eq!((*l_sGotCode, (*l_iLine, *l_iCol), l_sGotMessage.as_str()), (l_sErrorCode, l_tLineCol, l_sMessage));
+    }
+
+    #[test]
+    fn messages_use_source_spelling() {
+        F_vAssertError("proc main in\n    while 1 end\nend\n", "E0002", (2, 13), "expected `do`, got `end`");
+        F_vAssertError("proc main @8 in end\n", "E0002", (1, 11), "expected `in`, got `@8`");
+        F_vAssertError("proc main in\n    1 if drop\n", "E0002", (2, 10), "unexpected end of input, expected `end`");
+        F_vAssertError("proc main in i64 end\n", "E0002", (1, 14), "unexpected token `i64`");
+        F_vAssertError("dup proc main in end\n", "E0002", (1, 1), "expected proc, const, memory or extern declaration, got `dup`");
+        F_vAssertError("proc main in \"a\" 1 if else \"b\" do end end\n", "E0002", (1, 32), "expected `end`, got `do`");
+    }
+
+    #[test]
+    fn lexical_errors() {
+        F_vAssertError("proc main in 1 $ end\n", "E0001", (1, 16), "unexpected character '$'");
+        F_vAssertError("proc main in @7 end\n", "E0001", (1, 14), "expected 8, 16, 32 or 64 after '@'");
+    }
+
+    #[test]
+    fn every_syntax_error_is_reported() {
+        let l_lErrors = F_lErrors("proc f in while end end\nproc g in end\nproc in end\nproc main in end\n");
+        let l_lMessages: Vec<(usize, &str)> = l_lErrors.iter().map(|l_tError| (l_tError.1, l_tError.3.as_str())).collect();
+        assert_eq!(l_lMessages, [(1, "expected `do`, got `end`"), (3, "expected proc name")]);
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let l_lErrors = F_lErrors("proc f in end\nconst f 1 end\nproc main in end\nmemory f 8 end\n");
+        assert_eq!(l_lErrors, [
+            ("E0003", 2, 7, "`f` is declared more than once".to_string(), vec!["first declared at test.eb:1:6".to_string()]),
+            ("E0003", 4, 8, "`f` is declared more than once".to_string(), vec!["first declared at test.eb:1:6".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn exported_proc_declared_twice() {
+        let l_lErrors = F_lErrors("export proc main in end\nproc main in end\n");
+        assert_eq!(l_lErrors, [
+            ("E0003", 2, 6, "`main` is declared more than once".to_string(), vec!["first declared at test.eb:1:13".to_string()]),
+        ]);
+    }
 }
@@ -0,0 +1,988 @@
+use std::collections::HashMap;
+
+// ============================================================================
+// x86-64 Encoder
+// ============================================================================
+//
+// Assembles the NASM subset written by `StackAsmBuilder` into machine code,
+// so that executables can be produced without nasm. Supported:
+//
+//   section .text/.data/.bss, global, extern, labels (`.x` is local to the
+//   previous label), db/dw/dd/dq, resb/resw/resd/resq, align/alignb
+//
+//   mov movzx movsx movsxd lea add or adc sbb and sub xor cmp test
+//   imul mul div idiv neg not inc dec shl sal shr sar rol ror setcc cmovcc
+//   jmp jcc call ret push pop cqo cdq syscall nop leave
+//
+// Operands are 8/16/32/64-bit registers, immediates (numbers or symbols) and
+// memory references `size [base + index*scale + disp]` or `[rel symbol + disp]`.
+// `rel` references are RIP-relative; other symbol addresses are encoded as
+// sign-extended 32-bit absolute values (non-PIE code only). Jumps and calls
+// always use a 32-bit displacement.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum Section {
+    Text,
+    Data,
+    Bss,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RelocKind {
+    /// 32-bit absolute address, sign-extended by the CPU (R_X86_64_32S)
+    Abs32S,
+    /// 64-bit absolute address (R_X86_64_64)
+    Abs64,
+    /// 32-bit displacement from the relocated field (R_X86_64_PC32)
+    Pc32,
+}
+
+/// A field of `section` at `offset` to be patched with the address of `symbol`
+#[derive(Clone, Debug)]
+pub struct Reloc {
+    pub section: Section,
+    pub offset: usize,
+    pub kind: RelocKind,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: usize,
+}
+
+/// Output of the assembler: section contents, labels and the relocations left
+/// for the ELF writer (every reference that is not a jump inside a section)
+#[derive(Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub bss_size: usize,
+    pub symbols: Vec<Symbol>,
+    pub globals: Vec<String>,
+    pub externs: Vec<String>,
+    pub relocs: Vec<Reloc>,
+}
+
+impl Object {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    pub fn is_global(&self, name: &str) -> bool {
+        self.globals.iter().any(|g| g == name)
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Object, String> {
+    let mut asm = Assembler::new();
+    for (i, line) in source.lines().enumerate() {
+        asm.line(line).map_err(|e| format!("line {}: {}: `{}`", i + 1, e, line.trim()))?;
+    }
+    asm.finish()
+}
+
+// ============================================================================
+// Operands
+// ============================================================================
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Reg {
+    num: u8,
+    size: u8,
+}
+
+#[derive(Clone, Debug)]
+struct Mem {
+    size: Option<u8>,
+    rel: bool,
+    base: Option<u8>,
+    index: Option<(u8, u8)>,
+    disp: i64,
+    symbol: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Reg(Reg),
+    Mem(Mem),
+    Imm(i64, Option<String>),
+}
+
+const REGS_64: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+const REGS_32: [&str; 16] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"];
+const REGS_16: [&str; 16] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"];
+const REGS_8: [&str; 16] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"];
+
+fn register(name: &str) -> Option<Reg> {
+    let name = name.to_ascii_lowercase();
+    for (table, size) in [(&REGS_64, 8), (&REGS_32, 4), (&REGS_16, 2), (&REGS_8, 1)] {
+        if let Some(num) = table.iter().position(|r| *r == name) {
+            return Some(Reg { num: num as u8, size });
+        }
+    }
+    None
+}
+
+fn condition(cc: &str) -> Option<u8> {
+    Some(match cc {
+        "o" => 0x0,
+        "no" => 0x1,
+        "b" | "c" | "nae" => 0x2,
+        "ae" | "nb" | "nc" => 0x3,
+        "e" | "z" => 0x4,
+        "ne" | "nz" => 0x5,
+        "be" | "na" => 0x6,
+        "a" | "nbe" => 0x7,
+        "s" => 0x8,
+        "ns" => 0x9,
+        "p" | "pe" => 0xa,
+        "np" | "po" => 0xb,
+        "l" | "nge" => 0xc,
+        "ge" | "nl" => 0xd,
+        "le" | "ng" => 0xe,
+        "g" | "nle" => 0xf,
+        _ => return None,
+    })
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return u64::from_str_radix(&hex.replace('_', ""), 16).ok().map(|v| v as i64);
+    }
+    if let Some(c) = text.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(ch), None) => Some(ch as i64),
+            _ => None,
+        };
+    }
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        return text.replace('_', "").parse::<u64>().ok().map(|v| v as i64);
+    }
+    None
+}
+
+fn is_symbol(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || "_.$?@".contains(c))
+}
+
+/// Sum of terms like `rdx + rdx*4 - 2 + label + 4096*8`
+#[derive(Default)]
+struct Terms {
+    regs: Vec<(Reg, u8)>,
+    constant: i64,
+    symbol: Option<String>,
+}
+
+fn parse_terms(expr: &str) -> Result<Terms, String> {
+    let mut terms = Terms::default();
+    let mut rest = expr.trim();
+    let mut negative = false;
+    if let Some(r) = rest.strip_prefix('-') {
+        negative = true;
+        rest = r;
+    }
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        let mut factor = 1i64;
+        let mut reg = None;
+        let mut symbol = None;
+        for part in term.split('*').map(str::trim) {
+            if let Some(r) = register(part) {
+                if reg.is_some() {
+                    return Err(format!("invalid expression `{}`", expr));
+                }
+                reg = Some(r);
+            } else if let Some(n) = parse_number(part) {
+                factor = factor.wrapping_mul(n);
+            } else if is_symbol(part) && symbol.is_none() {
+                symbol = Some(part.to_string());
+            } else {
+                return Err(format!("invalid expression `{}`", expr));
+            }
+        }
+        match (reg, symbol) {
+            (Some(_), Some(_)) => return Err(format!("invalid expression `{}`", expr)),
+            (Some(r), None) => {
+                if negative || !matches!(factor, 1 | 2 | 4 | 8) {
+                    return Err(format!("invalid scale in `{}`", expr));
+                }
+                terms.regs.push((r, factor as u8));
+            }
+            (None, Some(s)) => {
+                if negative || factor != 1 || terms.symbol.is_some() {
+                    return Err(format!("invalid symbol reference `{}`", expr));
+                }
+                terms.symbol = Some(s);
+            }
+            (None, None) => {
+                terms.constant = terms.constant.wrapping_add(if negative { factor.wrapping_neg() } else { factor });
+            }
+        }
+        if end == rest.len() {
+            return Ok(terms);
+        }
+        negative = rest.as_bytes()[end] == b'-';
+        rest = &rest[end + 1..];
+    }
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let text = text.trim();
+    let lower = text.to_ascii_lowercase();
+    let mut size = None;
+    let mut rest = text;
+    for (keyword, s) in [("byte", 1), ("word", 2), ("dword", 4), ("qword", 8)] {
+        if lower.starts_with(keyword) && lower[keyword.len()..].starts_with([' ', '[']) {
+            size = Some(s);
+            rest = text[keyword.len()..].trim_start();
+            break;
+        }
+    }
+
+    if let Some(inner) = rest.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+        let (rel, inner) = match inner.trim_start().strip_prefix("rel ") {
+            Some(r) => (true, r),
+            None => (false, inner),
+        };
+        let terms = parse_terms(inner)?;
+        if rel && (!terms.regs.is_empty() || terms.symbol.is_none()) {
+            return Err(format!("`rel` needs a symbol and no registers: `{}`", inner));
+        }
+        let (base, index) = match terms.regs.as_slice() {
+            [] => (None, None),
+            [(b, 1)] => (Some(*b), None),
+            [(i, s)] => (None, Some((*i, *s))),
+            // rsp cannot be an index: swap when it was written second
+            [(b, 1), (i, 1)] if i.num == 4 => (Some(*i), Some((*b, 1))),
+            [(b, 1), (i, s)] | [(i, s), (b, 1)] => (Some(*b), Some((*i, *s))),
+            _ => return Err(format!("invalid address `{}`", inner)),
+        };
+        if base.is_some_and(|b| b.size != 8) || index.is_some_and(|(i, _)| i.size != 8) {
+            return Err(format!("only 64-bit registers can address memory: `{}`", inner));
+        }
+        if index.is_some_and(|(i, _)| i.num == 4) {
+            return Err(format!("rsp cannot be an index register: `{}`", inner));
+        }
+        return Ok(Operand::Mem(Mem {
+            size,
+            rel,
+            base: base.map(|b| b.num),
+            index: index.map(|(i, s)| (i.num, s)),
+            disp: terms.constant,
+            symbol: terms.symbol,
+        }));
+    }
+    if size.is_some() {
+        return Err("size keyword without a memory operand".to_string());
+    }
+    // `call sym wrt ..plt`: calls to extern symbols go through the PLT anyway
+    let rest = rest.strip_suffix("wrt ..plt").map_or(rest, str::trim_end);
+    if let Some(r) = register(rest) {
+        return Ok(Operand::Reg(r));
+    }
+    let terms = parse_terms(rest)?;
+    if !terms.regs.is_empty() {
+        return Err(format!("invalid operand `{}`", rest));
+    }
+    Ok(Operand::Imm(terms.constant, terms.symbol))
+}
+
+/// Splits on commas outside quotes and brackets
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'' | '`') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = text[start..].trim();
+    if !last.is_empty() || !parts.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'' | '`') => quote = Some(c),
+            (None, ';') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn fits_i8(v: i64) -> bool {
+    (-128..=127).contains(&v)
+}
+
+fn fits_i32(v: i64) -> bool {
+    (i32::MIN as i64..=i32::MAX as i64).contains(&v)
+}
+
+// ============================================================================
+// Assembler
+// ============================================================================
+
+enum RegField {
+    Reg(Reg),
+    Digit(u8),
+}
+
+struct Assembler {
+    obj: Object,
+    section: Section,
+    scope: String,
+    labels: HashMap<String, (Section, usize)>,
+    // RIP-relative relocation of the instruction being encoded: its addend
+    // depends on the instruction's length, known once it is complete
+    pending_rel: Option<usize>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self { obj: Object::default(), section: Section::Text, scope: String::new(), labels: HashMap::new(), pending_rel: None }
+    }
+
+    fn offset(&self) -> usize {
+        match self.section {
+            Section::Text => self.obj.text.len(),
+            Section::Data => self.obj.data.len(),
+            Section::Bss => self.obj.bss_size,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        match self.section {
+            Section::Text => self.obj.text.extend_from_slice(bytes),
+            Section::Data => self.obj.data.extend_from_slice(bytes),
+            Section::Bss => return Err("initialised data in .bss".to_string()),
+        }
+        Ok(())
+    }
+
+    /// `.x` names a label local to the last non-local label
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') { format!("{}{}", self.scope, name) } else { name.to_string() }
+    }
+
+    fn reloc(&mut self, kind: RelocKind, symbol: &str, addend: i64) {
+        let symbol = self.qualify(symbol);
+        let offset = self.offset();
+        self.obj.relocs.push(Reloc { section: self.section, offset, kind, symbol, addend });
+    }
+
+    /// A 32-bit immediate or displacement, with a relocation when it names a symbol
+    fn imm32(&mut self, value: i64, symbol: &Option<String>) -> Result<(), String> {
+        match symbol {
+            Some(s) => {
+                self.reloc(RelocKind::Abs32S, s, value);
+                self.bytes(&[0; 4])
+            }
+            None if fits_i32(value) => self.bytes(&(value as i32).to_le_bytes()),
+            None => Err(format!("immediate {} does not fit in 32 bits", value)),
+        }
+    }
+
+    fn imm(&mut self, size: u8, value: i64, symbol: &Option<String>) -> Result<(), String> {
+        match (size, symbol) {
+            (1, None) if (-128..=255).contains(&value) => self.bytes(&[value as u8]),
+            (2, None) if (-32768..=65535).contains(&value) => self.bytes(&(value as u16).to_le_bytes()),
+            (1 | 2, _) => Err(format!("immediate {} does not fit in {} bits", value, size * 8)),
+            (4, None) if value >= 0 && value <= u32::MAX as i64 => self.bytes(&(value as u32).to_le_bytes()),
+            _ => self.imm32(value, symbol),
+        }
+    }
+
+    // ========================================================================
+    // Lines
+    // ========================================================================
+
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        let mut line = strip_comment(line).trim();
+
+        // `name:` possibly followed by an instruction or a directive
+        if let Some(colon) = line.find(':') {
+            let name = line[..colon].trim();
+            if is_symbol(name) {
+                self.label(name)?;
+                line = line[colon + 1..].trim();
+            }
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let mnemonic = mnemonic.to_ascii_lowercase();
+        match mnemonic.as_str() {
+            // The ELF writer always marks the stack non-executable
+            "section" | "segment" if rest.starts_with(".note.GNU-stack") => Ok(()),
+            "section" | "segment" => {
+                self.section = match rest {
+                    ".text" => Section::Text,
+                    ".data" => Section::Data,
+                    ".bss" => Section::Bss,
+                    _ => return Err(format!("unknown section `{}`", rest)),
+                };
+                Ok(())
+            }
+            "global" | "extern" => {
+                for name in rest.split(',').map(str::trim) {
+                    if !is_symbol(name) {
+                        return Err(format!("invalid symbol `{}`", name));
+                    }
+                    match mnemonic.as_str() {
+                        "global" => self.obj.globals.push(name.to_string()),
+                        _ => self.obj.externs.push(name.to_string()),
+                    }
+                }
+                Ok(())
+            }
+            "db" | "dw" | "dd" | "dq" => self.data(&mnemonic, rest),
+            "resb" | "resw" | "resd" | "resq" => {
+                if self.section != Section::Bss {
+                    return Err(format!("{} outside .bss", mnemonic));
+                }
+                let count = self.constant(rest)?;
+                let count = usize::try_from(count).map_err(|_| format!("negative count {}", count))?;
+                let unit = match mnemonic.as_str() { "resb" => 1, "resw" => 2, "resd" => 4, _ => 8 };
+                self.obj.bss_size = count.checked_mul(unit)
+                    .and_then(|size| self.obj.bss_size.checked_add(size))
+                    .ok_or_else(|| format!("{} {} overflows the size of .bss", mnemonic, count))?;
+                Ok(())
+            }
+            "align" | "alignb" => {
+                let align = self.constant(rest)? as usize;
+                if align == 0 || !align.is_power_of_two() {
+                    return Err(format!("invalid alignment {}", align));
+                }
+                let pad = self.offset().checked_next_multiple_of(align)
+                    .ok_or_else(|| format!("{} {} overflows the section", mnemonic, align))? - self.offset();
+                match self.section {
+                    Section::Bss => self.obj.bss_size += pad,
+                    Section::Text => self.bytes(&vec![0x90; pad])?,
+                    Section::Data => self.bytes(&vec![0; pad])?,
+                }
+                Ok(())
+            }
+            _ => {
+                if self.section != Section::Text {
+                    return Err("instruction outside .text".to_string());
+                }
+                let ops = split_operands(rest).into_iter().map(parse_operand).collect::<Result<Vec<_>, _>>()?;
+                self.instruction(&mnemonic, &ops)?;
+                // rip points after the instruction, not after the displacement
+                if let Some(index) = self.pending_rel.take() {
+                    let end = self.obj.text.len();
+                    let reloc = &mut self.obj.relocs[index];
+                    reloc.addend -= (end - reloc.offset) as i64;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn label(&mut self, name: &str) -> Result<(), String> {
+        if !name.starts_with('.') {
+            self.scope = name.to_string();
+        }
+        let name = self.qualify(name);
+        let place = (self.section, self.offset());
+        if self.labels.insert(name.clone(), place).is_some() {
+            return Err(format!("label `{}` defined twice", name));
+        }
+        self.obj.symbols.push(Symbol { name, section: place.0, offset: place.1 });
+        Ok(())
+    }
+
+    fn constant(&self, expr: &str) -> Result<i64, String> {
+        let terms = parse_terms(expr)?;
+        if !terms.regs.is_empty() || terms.symbol.is_some() {
+            return Err(format!("`{}` is not a constant", expr));
+        }
+        Ok(terms.constant)
+    }
+
+    fn data(&mut self, directive: &str, rest: &str) -> Result<(), String> {
+        let size = match directive { "db" => 1, "dw" => 2, "dd" => 4, _ => 8 };
+        for item in split_operands(rest) {
+            if let Some(text) = item.strip_prefix(['"', '`']).and_then(|t| t.strip_suffix(['"', '`'])) {
+                if size != 1 {
+                    return Err("strings are only supported by db".to_string());
+                }
+                self.bytes(text.as_bytes())?;
+                continue;
+            }
+            let terms = parse_terms(item)?;
+            if !terms.regs.is_empty() {
+                return Err(format!("invalid data `{}`", item));
+            }
+            match (&terms.symbol, size) {
+                (Some(s), 8) => {
+                    self.reloc(RelocKind::Abs64, &s.clone(), terms.constant);
+                    self.bytes(&[0; 8])?;
+                }
+                (Some(_), _) => return Err("symbol addresses need dq".to_string()),
+                (None, 8) => self.bytes(&terms.constant.to_le_bytes())?,
+                (None, _) => self.imm(size, terms.constant, &None)?,
+            }
+        }
+        Ok(())
+    }
+
+    // ========================================================================
+    // Encoding
+    // ========================================================================
+
+    /// Prefixes, opcode, ModRM/SIB and displacement of an instruction whose
+    /// r/m operand is `rm`. The operand size selects 0x66 or REX.W.
+    fn encode_rm(&mut self, size: u8, opcode: &[u8], reg: RegField, rm: &Operand) -> Result<(), String> {
+        let (reg_num, reg_byte) = match reg {
+            RegField::Reg(r) => (r.num, r.size == 1 && (4..8).contains(&r.num)),
+            RegField::Digit(d) => (d, false),
+        };
+        let mut rex = 0u8;
+        if size == 8 {
+            rex |= 0x08;
+        }
+        if reg_num >= 8 {
+            rex |= 0x04;
+        }
+        let mut force_rex = reg_byte;
+        match rm {
+            Operand::Reg(r) => {
+                if r.num >= 8 {
+                    rex |= 0x01;
+                }
+                force_rex |= r.size == 1 && (4..8).contains(&r.num);
+            }
+            Operand::Mem(m) => {
+                if m.base.is_some_and(|b| b >= 8) {
+                    rex |= 0x01;
+                }
+                if m.index.is_some_and(|(i, _)| i >= 8) {
+                    rex |= 0x02;
+                }
+            }
+            Operand::Imm(..) => return Err("immediate used as a register or memory operand".to_string()),
+        }
+
+        let mut out = Vec::new();
+        if size == 2 {
+            out.push(0x66);
+        }
+        if rex != 0 || force_rex {
+            out.push(0x40 | rex);
+        }
+        out.extend_from_slice(opcode);
+        let reg_bits = (reg_num & 7) << 3;
+
+        let m = match rm {
+            Operand::Reg(r) => {
+                out.push(0xc0 | reg_bits | (r.num & 7));
+                return self.bytes(&out);
+            }
+            Operand::Mem(m) => m,
+            Operand::Imm(..) => unreachable!(),
+        };
+
+        if m.rel {
+            out.push(reg_bits | 0x05);
+            self.bytes(&out)?;
+            self.pending_rel = Some(self.obj.relocs.len());
+            self.reloc(RelocKind::Pc32, &m.symbol.clone().unwrap_or_default(), m.disp);
+            return self.bytes(&[0; 4]);
+        }
+
+        let disp32 = m.symbol.is_some() || !fits_i8(m.disp);
+        let scale_bits = |s: u8| match s { 1 => 0, 2 => 1, 4 => 2, _ => 3 } << 6;
+        match (m.base, m.index) {
+            // [disp32]: SIB with no base and no index (not RIP-relative)
+            (None, None) => {
+                out.push(reg_bits | 0x04);
+                out.push(0x25);
+            }
+            (None, Some((i, s))) => {
+                out.push(reg_bits | 0x04);
+                out.push(scale_bits(s) | ((i & 7) << 3) | 0x05);
+            }
+            (Some(b), index) => {
+                // rbp/r13 have no mod 00 form: they take a zero disp8
+                let mode = if disp32 { 0x80 } else if m.disp != 0 || b & 7 == 5 { 0x40 } else { 0x00 };
+                match index {
+                    None if b & 7 != 4 => out.push(mode | reg_bits | (b & 7)),
+                    None => {
+                        out.push(mode | reg_bits | 0x04);
+                        out.push(0x24);
+                    }
+                    Some((i, s)) => {
+                        out.push(mode | reg_bits | 0x04);
+                        out.push(scale_bits(s) | ((i & 7) << 3) | (b & 7));
+                    }
+                }
+                self.bytes(&out)?;
+                return match mode {
+                    0x00 => Ok(()),
+                    0x40 => self.bytes(&[m.disp as i8 as u8]),
+                    _ => self.imm32(m.disp, &m.symbol),
+                };
+            }
+        }
+        self.bytes(&out)?;
+        self.imm32(m.disp, &m.symbol)
+    }
+
+    /// Operand size of a two-operand instruction
+    fn size_of(dst: &Operand, src: &Operand) -> Result<u8, String> {
+        let size = |op: &Operand| match op {
+            Operand::Reg(r) => Some(r.size),
+            Operand::Mem(m) => m.size,
+            Operand::Imm(..) => None,
+        };
+        match (size(dst), size(src)) {
+            (Some(a), Some(b)) if a != b => Err("operand sizes do not match".to_string()),
+            (Some(a), _) | (None, Some(a)) => Ok(a),
+            (None, None) => Err("operation size not specified".to_string()),
+        }
+    }
+
+    /// Rel32 jump or call to a label; resolved in `finish` when it is in .text
+    fn branch(&mut self, opcode: &[u8], target: &Operand) -> Result<(), String> {
+        match target {
+            Operand::Imm(0, Some(label)) => {
+                self.bytes(opcode)?;
+                self.reloc(RelocKind::Pc32, &label.clone(), -4);
+                self.bytes(&[0; 4])
+            }
+            _ => Err("expected a label".to_string()),
+        }
+    }
+
+    fn instruction(&mut self, mnemonic: &str, ops: &[Operand]) -> Result<(), String> {
+        use Operand::{Imm, Mem as M, Reg as R};
+
+        const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+        const SHIFT: [(&str, u8); 6] = [("rol", 0), ("ror", 1), ("shl", 4), ("sal", 4), ("shr", 5), ("sar", 7)];
+        const UNARY: [(&str, u8); 6] = [("not", 2), ("neg", 3), ("mul", 4), ("imul", 5), ("div", 6), ("idiv", 7)];
+
+        if let Some(digit) = ALU.iter().position(|m| *m == mnemonic) {
+            let digit = digit as u8;
+            let [dst, src] = ops else { return Err("expected two operands".to_string()) };
+            let size = Self::size_of(dst, src)?;
+            let byte = (size == 1) as u8;
+            return match (dst, src) {
+                (R(_) | M(_), R(r)) => self.encode_rm(size, &[digit * 8 + 1 - byte], RegField::Reg(*r), dst),
+                (R(r), M(_)) => self.encode_rm(size, &[digit * 8 + 3 - byte], RegField::Reg(*r), src),
+                (R(_) | M(_), Imm(v, s)) => {
+                    if size == 1 {
+                        self.encode_rm(size, &[0x80], RegField::Digit(digit), dst)?;
+                        self.imm(1, *v, s)
+                    } else if s.is_none() && fits_i8(*v) {
+                        self.encode_rm(size, &[0x83], RegField::Digit(digit), dst)?;
+                        self.bytes(&[*v as i8 as u8])
+                    } else {
+                        self.encode_rm(size, &[0x81], RegField::Digit(digit), dst)?;
+                        self.imm(size, *v, s)
+                    }
+                }
+                _ => Err("invalid operands".to_string()),
+            };
+        }
+        if let Some((_, digit)) = SHIFT.iter().find(|(m, _)| *m == mnemonic) {
+            let [dst, count] = ops else { return Err("expected two operands".to_string()) };
+            let size = Self::size_of(dst, &Imm(0, None))?;
+            let byte = (size == 1) as u8;
+            return match count {
+                R(Reg { num: 1, size: 1 }) => self.encode_rm(size, &[0xd3 - byte], RegField::Digit(*digit), dst),
+                Imm(1, None) => self.encode_rm(size, &[0xd1 - byte], RegField::Digit(*digit), dst),
+                Imm(v, None) if (0..=255).contains(v) => {
+                    self.encode_rm(size, &[0xc1 - byte], RegField::Digit(*digit), dst)?;
+                    self.bytes(&[*v as u8])
+                }
+                _ => Err("shift count must be cl or an 8-bit immediate".to_string()),
+            };
+        }
+        if let Some((_, digit)) = UNARY.iter().find(|(m, _)| *m == mnemonic).filter(|_| ops.len() == 1) {
+            let size = Self::size_of(&ops[0], &Imm(0, None))?;
+            let byte = (size == 1) as u8;
+            return self.encode_rm(size, &[0xf7 - byte], RegField::Digit(*digit), &ops[0]);
+        }
+        if let Some(cc) = mnemonic.strip_prefix("set").and_then(condition) {
+            return match ops {
+                [op @ (R(Reg { size: 1, .. }) | M(_))] => self.encode_rm(1, &[0x0f, 0x90 + cc], RegField::Digit(0), op),
+                _ => Err("expected an 8-bit operand".to_string()),
+            };
+        }
+        if let Some(cc) = mnemonic.strip_prefix("cmov").and_then(condition) {
+            return match ops {
+                [R(r), src @ (R(_) | M(_))] if r.size > 1 => {
+                    let size = Self::size_of(&ops[0], src)?;
+                    self.encode_rm(size, &[0x0f, 0x40 + cc], RegField::Reg(*r), src)
+                }
+                _ => Err("invalid operands".to_string()),
+            };
+        }
+        if let Some(cc) = mnemonic.strip_prefix('j').and_then(condition) {
+            let [target] = ops else { return Err("expected a label".to_string()) };
+            return self.branch(&[0x0f, 0x80 + cc], target);
+        }
+
+        match (mnemonic, ops) {
+            ("mov", [dst, src]) => self.mov(dst, src),
+            ("lea", [R(r), src @ M(_)]) if r.size >= 4 => self.encode_rm(r.size, &[0x8d], RegField::Reg(*r), src),
+            ("movzx" | "movsx", [R(r), src @ (R(_) | M(_))]) => {
+                let src_size = match src {
+                    R(s) => s.size,
+                    M(m) => m.size.ok_or("operation size not specified")?,
+                    Imm(..) => unreachable!(),
+                };
+                let base = if mnemonic == "movzx" { 0xb6 } else { 0xbe };
+                match src_size {
+                    1 | 2 if r.size > src_size => {
+                        self.encode_rm(r.size, &[0x0f, base + (src_size - 1)], RegField::Reg(*r), src)
+                    }
+                    _ => Err("invalid operand sizes".to_string()),
+                }
+            }
+            ("movsxd", [R(r), src @ (R(_) | M(_))]) if r.size == 8 => self.encode_rm(8, &[0x63], RegField::Reg(*r), src),
+            ("test", [dst, src]) => {
+                let size = Self::size_of(dst, src)?;
+                let byte = (size == 1) as u8;
+                match (dst, src) {
+                    (R(_) | M(_), R(r)) => self.encode_rm(size, &[0x85 - byte], RegField::Reg(*r), dst),
+                    (R(_) | M(_), Imm(v, s)) => {
+                        self.encode_rm(size, &[0xf7 - byte], RegField::Digit(0), dst)?;
+                        self.imm(size, *v, s)
+                    }
+                    _ => Err("invalid operands".to_string()),
+                }
+            }
+            ("imul", [R(r), src @ (R(_) | M(_))]) if r.size > 1 => {
+                let size = Self::size_of(&ops[0], src)?;
+                self.encode_rm(size, &[0x0f, 0xaf], RegField::Reg(*r), src)
+            }
+            ("imul", [R(r), src @ (R(_) | M(_)), Imm(v, None)]) if r.size > 1 => {
+                let size = Self::size_of(&ops[0], src)?;
+                if fits_i8(*v) {
+                    self.encode_rm(size, &[0x6b], RegField::Reg(*r), src)?;
+                    self.bytes(&[*v as i8 as u8])
+                } else {
+                    self.encode_rm(size, &[0x69], RegField::Reg(*r), src)?;
+                    self.imm(size, *v, &None)
+                }
+            }
+            ("inc" | "dec", [op]) => {
+                let size = Self::size_of(op, &Imm(0, None))?;
+                let digit = (mnemonic == "dec") as u8;
+                self.encode_rm(size, &[0xff - (size == 1) as u8], RegField::Digit(digit), op)
+            }
+            ("jmp", [target @ Imm(..)]) => self.branch(&[0xe9], target),
+            ("call", [target @ Imm(..)]) => self.branch(&[0xe8], target),
+            ("jmp", [target @ (R(Reg { size: 8, .. }) | M(_))]) => self.encode_rm(4, &[0xff], RegField::Digit(4), target),
+            ("call", [target @ (R(Reg { size: 8, .. }) | M(_))]) => self.encode_rm(4, &[0xff], RegField::Digit(2), target),
+            ("push" | "pop", [R(r)]) if r.size == 8 => {
+                if r.num >= 8 {
+                    self.bytes(&[0x41])?;
+                }
+                let base = if mnemonic == "push" { 0x50 } else { 0x58 };
+                self.bytes(&[base + (r.num & 7)])
+            }
+            ("push", [Imm(v, s)]) => {
+                self.bytes(&[0x68])?;
+                self.imm32(*v, s)
+            }
+            ("push", [op @ M(_)]) => self.encode_rm(4, &[0xff], RegField::Digit(6), op),
+            ("pop", [op @ M(_)]) => self.encode_rm(4, &[0x8f], RegField::Digit(0), op),
+            ("cqo", []) => self.bytes(&[0x48, 0x99]),
+            ("cdq", []) => self.bytes(&[0x99]),
+            ("syscall", []) => self.bytes(&[0x0f, 0x05]),
+            ("ret", []) => self.bytes(&[0xc3]),
+            ("leave", []) => self.bytes(&[0xc9]),
+            ("nop", []) => self.bytes(&[0x90]),
+            _ => Err(format!("unsupported instruction `{}` with {} operand(s)", mnemonic, ops.len())),
+        }
+    }
+
+    fn mov(&mut self, dst: &Operand, src: &Operand) -> Result<(), String> {
+        use Operand::{Imm, Mem as M, Reg as R};
+        let size = Self::size_of(dst, src)?;
+        let byte = (size == 1) as u8;
+        match (dst, src) {
+            (R(_) | M(_), R(r)) => self.encode_rm(size, &[0x89 - byte], RegField::Reg(*r), dst),
+            (R(r), M(_)) => self.encode_rm(size, &[0x8b - byte], RegField::Reg(*r), src),
+            // mov r64, imm: the shortest of imm32 sign-extended, imm32
+            // zero-extended (mov r32) and imm64
+            (R(r), Imm(v, None)) if size == 8 && !fits_i32(*v) => {
+                if *v >= 0 && *v <= u32::MAX as i64 {
+                    self.mov_imm_short(*r, 4, *v)
+                } else {
+                    self.mov_imm_short(*r, 8, *v)
+                }
+            }
+            (R(r), Imm(v, None)) if size != 8 => self.mov_imm_short(*r, size, *v),
+            (R(_) | M(_), Imm(v, s)) => {
+                self.encode_rm(size, &[0xc7 - byte], RegField::Digit(0), dst)?;
+                self.imm(size, *v, s)
+            }
+            _ => Err("invalid operands".to_string()),
+        }
+    }
+
+    /// mov r, imm in its B0+r / B8+r form
+    fn mov_imm_short(&mut self, r: Reg, size: u8, value: i64) -> Result<(), String> {
+        let mut out = Vec::new();
+        if size == 2 {
+            out.push(0x66);
+        }
+        let rex = if size == 8 { 0x48 } else { 0x40 } | (r.num >= 8) as u8;
+        if rex != 0x40 || (size == 1 && (4..8).contains(&r.num)) {
+            out.push(rex);
+        }
+        out.push(if size == 1 { 0xb0 } else { 0xb8 } + (r.num & 7));
+        self.bytes(&out)?;
+        match size {
+            8 => self.bytes(&value.to_le_bytes()),
+            _ => self.imm(size, value, &None),
+        }
+    }
+
+    // ========================================================================
+    // Finish
+    // ========================================================================
+
+    /// Resolves branches to labels of their own section and checks that every
+    /// remaining reference names a label or an `extern`
+    fn finish(mut self) -> Result<Object, String> {
+        let mut relocs = Vec::new();
+        for reloc in std::mem::take(&mut self.obj.relocs) {
+            let target = self.labels.get(&reloc.symbol).copied();
+            match target {
+                Some((section, offset)) if reloc.kind == RelocKind::Pc32 && section == reloc.section => {
+                    let value = offset as i64 + reloc.addend - reloc.offset as i64;
+                    let field = match section {
+                        Section::Text => &mut self.obj.text,
+                        _ => &mut self.obj.data,
+                    };
+                    field[reloc.offset..reloc.offset + 4].copy_from_slice(&(value as i32).to_le_bytes());
+                }
+                None if !self.obj.externs.contains(&reloc.symbol) => {
+                    return Err(format!("undefined symbol `{}`", reloc.symbol));
+                }
+                _ => relocs.push(reloc),
+            }
+        }
+        self.obj.relocs = relocs;
+        for global in &self.obj.globals {
+            if !self.labels.contains_key(global) {
+                return Err(format!("global symbol `{}` is not defined", global));
+            }
+        }
+        Ok(self.obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(source: &str) -> Vec<u8> {
+        assemble(&format!("section .text\n{}", source)).unwrap().text
+    }
+
+    fn error(source: &str) -> String {
+        assemble(source).err().expect("assembled")
+    }
+
+    #[test]
+    fn modrm_sib_bases() {
+        // rsp/r12 need a SIB byte, rbp/r13 a zero disp8
+        assert_eq!(text("mov rax, [rsp]"), [0x48, 0x8b, 0x04, 0x24]);
+        assert_eq!(text("mov rax, [r12]"), [0x49, 0x8b, 0x04, 0x24]);
+        assert_eq!(text("mov rax, [rbp]"), [0x48, 0x8b, 0x45, 0x00]);
+        assert_eq!(text("mov rax, [r13]"), [0x49, 0x8b, 0x45, 0x00]);
+        assert_eq!(text("mov rax, [rsp + 8]"), [0x48, 0x8b, 0x44, 0x24, 0x08]);
+        assert_eq!(text("mov rax, [r12 + r13*8]"), [0x4b, 0x8b, 0x04, 0xec]);
+        assert_eq!(text("mov rax, [rbx + rcx*8 - 16]"), [0x48, 0x8b, 0x44, 0xcb, 0xf0]);
+        assert_eq!(text("mov rax, [r15 + 4096]"), [0x49, 0x8b, 0x87, 0x00, 0x10, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn rex_prefixes() {
+        assert_eq!(text("mov qword [r15], rax"), [0x49, 0x89, 0x07]);
+        assert_eq!(text("mov r8, r9"), [0x4d, 0x89, 0xc8]);
+        assert_eq!(text("push r14"), [0x41, 0x56]);
+        // spl/bpl/sil/dil need an empty REX, which also makes ah..bh unreachable
+        assert_eq!(text("mov byte [r15], sil"), [0x41, 0x88, 0x37]);
+        assert_eq!(text("mov al, sil"), [0x40, 0x88, 0xf0]);
+        assert_eq!(text("movzx eax, dil"), [0x40, 0x0f, 0xb6, 0xc7]);
+        assert_eq!(text("mov al, bl"), [0x88, 0xd8]);
+        assert_eq!(text("mov r8b, 1"), [0x41, 0xb0, 0x01]);
+    }
+
+    #[test]
+    fn immediate_sizes() {
+        assert_eq!(text("add rax, 1"), [0x48, 0x83, 0xc0, 0x01]);
+        assert_eq!(text("add r15, -8"), [0x49, 0x83, 0xc7, 0xf8]);
+        assert_eq!(text("add rax, 128"), [0x48, 0x81, 0xc0, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(text("sub rsp, 0x1000"), [0x48, 0x81, 0xec, 0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(text("mov rax, -1"), [0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(text("mov rax, 0xffffffff"), [0xb8, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(text("mov rax, 0x100000000"), [0x48, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        assert!(assemble("section .text\nadd rax, 0x100000000").is_err());
+    }
+
+    #[test]
+    fn rip_relative_addend() {
+        // The CPU adds the displacement to the address of the next
+        // instruction, so the addend counts the bytes after the field
+        let obj = assemble("extern sym\nsection .text\nlea rax, [rel sym]\nmov qword [rel sym + 8], 1\ncmp byte [rel sym], 1").unwrap();
+        let relocs: Vec<_> = obj.relocs.iter().map(|r| (r.offset, r.kind, r.addend)).collect();
+        assert_eq!(relocs, [(3, RelocKind::Pc32, -4), (10, RelocKind::Pc32, 0), (20, RelocKind::Pc32, -5)]);
+    }
+
+    #[test]
+    fn local_branches() {
+        let obj = assemble("section .text\nf:\n.loop:\n    dec rcx\n    jnz .loop\n    call f\n    ret").unwrap();
+        assert!(obj.relocs.is_empty());
+        assert_eq!(obj.text, [0x48, 0xff, 0xc9, 0x0f, 0x85, 0xf7, 0xff, 0xff, 0xff, 0xe8, 0xf2, 0xff, 0xff, 0xff, 0xc3]);
+    }
+
+    #[test]
+    fn bss_reservations() {
+        let obj = assemble("section .bss\na: resb 3\nalignb 8\nb: resq 2").unwrap();
+        assert_eq!(obj.bss_size, 24);
+        assert_eq!(obj.symbol("b").map(|s| s.offset), Some(8));
+        assert!(error("section .bss\nresb -1").contains("negative count"));
+        assert!(error("section .bss\nresq 0x2000000000000000").contains("overflows"));
+        assert!(error("section .bss\nresb 0x7fffffffffffffff\nresb 0x7fffffffffffffff\nresb 2").contains("overflows"));
+    }
+}
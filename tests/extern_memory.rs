@@ -0,0 +1,78 @@
+// The interpreter and the compiled code must agree on programs that read and
+// write memory C handed out. The compiled side is an object linked into a C
+// program by `cc`; the test is skipped where there is no C compiler.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SOURCE: &str = r#"
+extern malloc i64 ret ptr
+extern free ptr
+extern getenv ptr ret ptr
+extern strlen ptr ret i64
+
+export proc roundtrip in
+    16 malloc
+    dup 72 !8
+    dup 1 + 105 !8
+    dup 2 + 10 !8
+    dup 8 + 1234567 !64
+    dup 3 puts
+    dup 8 + @64 dump "\n" puts
+    free
+    "EBOTH_TEST_VALUE" drop getenv dup strlen puts "\n" puts
+end
+
+proc main in
+    roundtrip
+end
+"#;
+
+const DRIVER: &str = "
+void proc_roundtrip(void);
+
+int main(void) {
+    proc_roundtrip();
+    return 0;
+}
+";
+
+const EXPECTED: &str = "Hi\n1234567\nfrom the environment\n";
+
+fn scratch_dir() -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("extern_memory");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn stdout_of(command: &mut Command) -> String {
+    let output = command.env("EBOTH_TEST_VALUE", "from the environment").output().unwrap();
+    assert!(output.status.success(), "{:?} failed: {}", command, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn malloc_round_trip_matches_compiled_code() {
+    let dir = scratch_dir();
+    let source = dir.join("roundtrip.eb");
+    std::fs::write(&source, SOURCE).unwrap();
+
+    let interpreted = stdout_of(Command::new(env!("CARGO_BIN_EXE_eboth")).arg("run").arg(&source));
+    assert_eq!(interpreted, EXPECTED);
+
+    let object = dir.join("roundtrip.o");
+    let driver = dir.join("driver.c");
+    let program = dir.join("roundtrip");
+    std::fs::write(&driver, DRIVER).unwrap();
+    stdout_of(Command::new(env!("CARGO_BIN_EXE_eboth"))
+        .args(["emit-obj", "--backend=builtin", "-q", "-o"]).arg(&object).arg(&source));
+    match Command::new("cc").arg(&driver).arg(&object).arg("-o").arg(&program).status() {
+        Ok(status) => assert!(status.success(), "cc failed"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("skipped: no cc to link the compiled program");
+            return;
+        }
+        Err(e) => panic!("cannot run cc: {}", e),
+    }
+    assert_eq!(stdout_of(&mut Command::new(&program)), interpreted);
+}
@@ -0,0 +1,39 @@
+// The interpreter bounds the call depth like the compiled proc stack: 8192
+// procs may be running at once, main included, and one more call stops the
+// program as a failed --check-stack check does.
+
+use std::process::{Command, Output};
+
+fn run_down(depth: u32) -> Output {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("stack_depth");
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join(format!("down{}.eb", depth));
+    std::fs::write(&source, format!("\
+proc down i64 ret i64 in
+    dup 0 > if 1 - down 1 + end
+end
+
+proc main in
+    {} down dump
+end
+", depth)).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_eboth")).arg("run").arg(&source).output().unwrap()
+}
+
+#[test]
+fn deepest_recursion_runs() {
+    // main and 8191 calls of down
+    let output = run_down(8190);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, b"8190");
+}
+
+#[test]
+fn deeper_recursion_overflows_the_proc_stack() {
+    for depth in [8191, 1_000_000] {
+        let output = run_down(depth);
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "error: proc stack overflow in proc down\n");
+        assert!(output.stdout.is_empty());
+    }
+}